@@ -0,0 +1,436 @@
+use crate::audio_input::{self, AudioInput, AudioStream};
+use crate::beat;
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::f32::consts::PI;
+use std::sync::mpsc::{channel, sync_channel, Receiver};
+
+// Samples in each spectrum, about 23ms at 44.1kHz
+pub const ANALYSIS_WINDOW: usize = 1024;
+// Live input is analysed every this many samples, so windows overlap by half
+const HOP_SIZE: usize = ANALYSIS_WINDOW / 2;
+
+// Upper edges of the bass and mid bands in Hz, high is everything above
+const BASS_CUTOFF: f32 = 250.0;
+const MID_CUTOFF: f32 = 4_000.0;
+
+// How quickly the average spectral flux follows the music, onsets are
+// measured against it
+const FLUX_SMOOTHING: f32 = 0.1;
+
+// What the audio sounds like over the last analysis window. Everything goes
+// from 0 to 1.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AudioFeatures {
+    // Loudness, from -60dB to full scale
+    pub rms: f32,
+    // Loudness of each band on the same scale
+    pub bass: f32,
+    pub mid: f32,
+    pub high: f32,
+    // The center of mass of the spectrum, as a fraction of the Nyquist
+    // frequency. Brighter sounds are higher.
+    pub spectral_centroid: f32,
+    // How suddenly the spectrum grew, 1 when it's twice the recent average
+    pub onset_strength: f32,
+}
+
+impl AudioFeatures {
+    // Several analysis windows can arrive between frames, onsets are kept so
+    // short ones aren't missed and everything else is the latest
+    fn merge(self, later: AudioFeatures) -> AudioFeatures {
+        AudioFeatures {
+            onset_strength: self.onset_strength.max(later.onset_strength),
+            ..later
+        }
+    }
+}
+
+// Turns mono samples into AudioFeatures, keeping the state needed to measure
+// onsets between windows
+pub struct Analyzer {
+    sample_rate: u32,
+    samples: Vec<f32>,
+    hann_window: Vec<f32>,
+    previous_magnitudes: Vec<f32>,
+    average_flux: f32,
+}
+
+impl Analyzer {
+    pub fn new(sample_rate: u32) -> Self {
+        let hann_window = (0..ANALYSIS_WINDOW)
+            .map(|i| 0.5 - 0.5 * (2.0 * PI * i as f32 / ANALYSIS_WINDOW as f32).cos())
+            .collect();
+        Analyzer {
+            sample_rate,
+            samples: vec![0.0; ANALYSIS_WINDOW],
+            hann_window,
+            previous_magnitudes: vec![0.0; ANALYSIS_WINDOW / 2],
+            average_flux: 0.0,
+        }
+    }
+
+    // Adds samples from -1 to 1, only the last ANALYSIS_WINDOW are analysed
+    pub fn push(&mut self, samples: &[f32]) {
+        let samples = &samples[samples.len().saturating_sub(ANALYSIS_WINDOW)..];
+        self.samples.drain(..samples.len());
+        self.samples.extend_from_slice(samples);
+    }
+
+    pub fn analyze(&mut self) -> AudioFeatures {
+        let mut spectrum: Vec<(f32, f32)> = self
+            .samples
+            .iter()
+            .zip(&self.hann_window)
+            .map(|(sample, window)| (sample * window, 0.0))
+            .collect();
+        fft(&mut spectrum);
+
+        // Scaled so a full scale sine wave has a magnitude of 1, the Hann
+        // window halves the amplitude
+        let scale = 4.0 / ANALYSIS_WINDOW as f32;
+        let magnitudes: Vec<f32> = spectrum[..ANALYSIS_WINDOW / 2]
+            .iter()
+            .map(|(re, im)| (re * re + im * im).sqrt() * scale)
+            .collect();
+
+        let bin_width = self.sample_rate as f32 / ANALYSIS_WINDOW as f32;
+        let mut band_power = [0.0; 3];
+        let mut weighted_bins = 0.0;
+        let mut total_magnitude = 0.0;
+        for (bin, magnitude) in magnitudes.iter().enumerate().skip(1) {
+            let frequency = bin as f32 * bin_width;
+            let band = if frequency < BASS_CUTOFF {
+                0
+            } else if frequency < MID_CUTOFF {
+                1
+            } else {
+                2
+            };
+            band_power[band] += magnitude * magnitude / 2.0;
+            weighted_bins += bin as f32 * magnitude;
+            total_magnitude += magnitude;
+        }
+
+        let flux: f32 = magnitudes
+            .iter()
+            .zip(&self.previous_magnitudes)
+            .map(|(magnitude, previous)| (magnitude - previous).max(0.0))
+            .sum();
+        let onset_strength = if self.average_flux > 0.0 {
+            (flux / self.average_flux - 1.0).clamp(0.0, 1.0)
+        } else {
+            0.0
+        };
+        self.average_flux += (flux - self.average_flux) * FLUX_SMOOTHING;
+        self.previous_magnitudes = magnitudes;
+
+        let mean_square = self
+            .samples
+            .iter()
+            .map(|sample| sample * sample)
+            .sum::<f32>()
+            / ANALYSIS_WINDOW as f32;
+        AudioFeatures {
+            rms: decibel_level(mean_square.sqrt()),
+            bass: decibel_level(band_power[0].sqrt()),
+            mid: decibel_level(band_power[1].sqrt()),
+            high: decibel_level(band_power[2].sqrt()),
+            spectral_centroid: if total_magnitude > 0.0 {
+                weighted_bins / total_magnitude / (ANALYSIS_WINDOW / 2) as f32
+            } else {
+                0.0
+            },
+            onset_strength,
+        }
+    }
+}
+
+// From 0 at -60dB to 1 at full scale
+fn decibel_level(amplitude: f32) -> f32 {
+    (1.0 + 20.0 * amplitude.max(1e-6).log10() / 60.0).clamp(0.0, 1.0)
+}
+
+// In place radix-2 FFT of (real, imaginary) pairs, the length has to be a
+// power of two
+fn fft(data: &mut [(f32, f32)]) {
+    let n = data.len();
+    let mut j = 0;
+    for i in 1..n {
+        let mut bit = n >> 1;
+        while j & bit != 0 {
+            j ^= bit;
+            bit >>= 1;
+        }
+        j |= bit;
+        if i < j {
+            data.swap(i, j);
+        }
+    }
+
+    let mut length = 2;
+    while length <= n {
+        let angle = -2.0 * PI / length as f32;
+        for start in (0..n).step_by(length) {
+            for k in 0..length / 2 {
+                let (sin, cos) = (angle * k as f32).sin_cos();
+                let (re, im) = data[start + k + length / 2];
+                let twiddled = (re * cos - im * sin, re * sin + im * cos);
+                let even = data[start + k];
+                data[start + k] = (even.0 + twiddled.0, even.1 + twiddled.1);
+                data[start + k + length / 2] = (even.0 - twiddled.0, even.1 - twiddled.1);
+            }
+        }
+        length <<= 1;
+    }
+}
+
+// What the audio thread found since the last frame
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct AudioFrame {
+    pub features: AudioFeatures,
+    // The tempo at each detected beat
+    pub beats: Vec<f64>,
+}
+
+// Detects beats and analyses an audio input on the audio thread, and hands the
+// results to the render loop
+pub struct AudioAnalysis {
+    // Capture stops when this is dropped
+    _stream: AudioStream,
+    // Records live devices for their beats
+    _beat_detector: beat::BeatDetector,
+    features: Receiver<AudioFeatures>,
+    // Unbounded, a beat is never dropped however far behind the render loop is
+    beats: Receiver<f64>,
+    latest: AudioFeatures,
+}
+
+impl AudioAnalysis {
+    pub fn start(input: &AudioInput, sensitivity: u32) -> Result<Self, Box<dyn Error>> {
+        let (feature_sender, features) = sync_channel(64);
+        let (beat_sender, beats) = channel();
+        let mut beat_detector = beat::BeatDetector::new();
+
+        let live_device = input.live_device()?;
+        if let Some(device) = &live_device {
+            let beat_sender = beat_sender.clone();
+            beat_detector.start_listening(device.clone(), sensitivity, move |(_, bpm)| {
+                let _ = beat_sender.send(bpm);
+            });
+        }
+        let track_live_beats = live_device.is_none();
+
+        let make_consumer = move |sample_rate| {
+            let mut analyzer = Analyzer::new(sample_rate);
+            let mut track_beats =
+                track_live_beats.then(|| beat::beat_tracker(sample_rate, sensitivity));
+            let mut pending = Vec::with_capacity(HOP_SIZE);
+            move |samples: &[f32]| {
+                if let Some(track_beats) = &mut track_beats {
+                    let whole: Vec<i16> = samples
+                        .iter()
+                        .map(|sample| (sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16)
+                        .collect();
+                    for beat in track_beats(&whole) {
+                        let _ = beat_sender.send(beat.bpm);
+                    }
+                }
+
+                for &sample in samples {
+                    pending.push(sample);
+                    if pending.len() == HOP_SIZE {
+                        analyzer.push(&pending);
+                        pending.clear();
+                        // The render loop only wants the latest, drop it if it's behind
+                        let _ = feature_sender.try_send(analyzer.analyze());
+                    }
+                }
+            }
+        };
+        let stream = match &live_device {
+            Some(device) => audio_input::start_device(device, make_consumer)?,
+            None => input.start(make_consumer)?,
+        };
+
+        Ok(AudioAnalysis {
+            _stream: stream,
+            _beat_detector: beat_detector,
+            features,
+            beats,
+            latest: AudioFeatures::default(),
+        })
+    }
+
+    // Everything found since the last frame. When no analysis has arrived the
+    // features are the same as last frame, without the onset.
+    pub fn next_frame(&mut self) -> AudioFrame {
+        let mut features: Option<AudioFeatures> = None;
+        for later in self.features.try_iter() {
+            features = Some(match features {
+                Some(features) => features.merge(later),
+                None => later,
+            });
+        }
+        let beats = self.beats.try_iter().collect();
+        let features = match features {
+            Some(features) => {
+                self.latest = features;
+                features
+            }
+            None => AudioFeatures {
+                onset_strength: 0.0,
+                ..self.latest
+            },
+        };
+        AudioFrame { features, beats }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: u32 = 44_100;
+
+    // A window of a sine that fits it exactly, so its energy lands in one bin
+    // and the two either side of it
+    fn sine(bin: usize, amplitude: f32) -> Vec<f32> {
+        (0..ANALYSIS_WINDOW)
+            .map(|i| amplitude * (2.0 * PI * (bin * i) as f32 / ANALYSIS_WINDOW as f32).sin())
+            .collect()
+    }
+
+    fn bin_frequency(bin: usize) -> f32 {
+        bin as f32 * SAMPLE_RATE as f32 / ANALYSIS_WINDOW as f32
+    }
+
+    fn analyze(samples: &[f32]) -> AudioFeatures {
+        let mut analyzer = Analyzer::new(SAMPLE_RATE);
+        analyzer.push(samples);
+        analyzer.analyze()
+    }
+
+    fn assert_close(actual: f32, expected: f32, tolerance: f32) {
+        assert!(
+            (actual - expected).abs() < tolerance,
+            "expected {expected}, got {actual}"
+        );
+    }
+
+    #[test]
+    fn fft_matches_the_dft() {
+        let input: Vec<(f32, f32)> = (0..16)
+            .map(|i| ((i as f32 * 0.7).sin() + 0.3, (i as f32 * 1.3).cos() * 0.5))
+            .collect();
+        let mut output = input.clone();
+        fft(&mut output);
+
+        for (k, &(re, im)) in output.iter().enumerate() {
+            let (mut dft_re, mut dft_im) = (0.0, 0.0);
+            for (n, &(x_re, x_im)) in input.iter().enumerate() {
+                let (sin, cos) = (-2.0 * PI * (k * n) as f32 / 16.0).sin_cos();
+                dft_re += x_re * cos - x_im * sin;
+                dft_im += x_re * sin + x_im * cos;
+            }
+            assert_close(re, dft_re, 1e-4);
+            assert_close(im, dft_im, 1e-4);
+        }
+    }
+
+    #[test]
+    fn fft_of_an_impulse_is_flat() {
+        let mut data = vec![(0.0, 0.0); 8];
+        data[0] = (1.0, 0.0);
+        fft(&mut data);
+        assert!(data.iter().all(|&value| value == (1.0, 0.0)));
+    }
+
+    #[test]
+    fn full_scale_sines_land_in_their_band() {
+        // 129Hz, 990Hz and 9.99kHz
+        let (bass, mid, high) = (3, 23, 232);
+        assert!(bin_frequency(bass) < BASS_CUTOFF);
+        assert!(bin_frequency(mid) > BASS_CUTOFF && bin_frequency(mid) < MID_CUTOFF);
+        assert!(bin_frequency(high) > MID_CUTOFF);
+
+        let features = analyze(&sine(bass, 1.0));
+        assert_close(features.bass, 1.0, 0.05);
+        assert_close(features.mid, 0.0, 0.05);
+        assert_close(features.high, 0.0, 0.05);
+
+        let features = analyze(&sine(mid, 1.0));
+        assert_close(features.bass, 0.0, 0.05);
+        assert_close(features.mid, 1.0, 0.05);
+        assert_close(features.high, 0.0, 0.05);
+
+        let features = analyze(&sine(high, 1.0));
+        assert_close(features.bass, 0.0, 0.05);
+        assert_close(features.mid, 0.0, 0.05);
+        assert_close(features.high, 1.0, 0.05);
+    }
+
+    #[test]
+    fn levels_are_in_decibels() {
+        // A sine's RMS is 3dB below its peak
+        assert_close(analyze(&sine(23, 1.0)).rms, 1.0 - 3.01 / 60.0, 0.01);
+        assert_close(analyze(&sine(23, 0.1)).rms, 1.0 - 23.01 / 60.0, 0.01);
+        assert_close(analyze(&sine(23, 0.1)).mid, 1.0 - 20.0 / 60.0, 0.05);
+        assert_close(decibel_level(1e-4), 0.0, 1e-6);
+    }
+
+    #[test]
+    fn the_centroid_follows_the_frequency() {
+        assert_close(
+            analyze(&sine(232, 1.0)).spectral_centroid,
+            232.0 / 512.0,
+            0.01,
+        );
+        assert!(
+            analyze(&sine(23, 1.0)).spectral_centroid < analyze(&sine(232, 1.0)).spectral_centroid
+        );
+    }
+
+    #[test]
+    fn silence_is_all_zero() {
+        let mut analyzer = Analyzer::new(SAMPLE_RATE);
+        for _ in 0..3 {
+            analyzer.push(&[0.0; ANALYSIS_WINDOW]);
+            assert_eq!(analyzer.analyze(), AudioFeatures::default());
+        }
+    }
+
+    #[test]
+    fn a_jump_in_level_is_an_onset() {
+        let mut analyzer = Analyzer::new(SAMPLE_RATE);
+        for _ in 0..5 {
+            analyzer.push(&sine(23, 0.01));
+            analyzer.analyze();
+        }
+        analyzer.push(&sine(23, 0.01));
+        assert_eq!(analyzer.analyze().onset_strength, 0.0);
+
+        analyzer.push(&sine(23, 1.0));
+        assert!(analyzer.analyze().onset_strength > 0.0);
+
+        // and staying there isn't
+        analyzer.push(&sine(23, 1.0));
+        assert_eq!(analyzer.analyze().onset_strength, 0.0);
+    }
+
+    #[test]
+    fn only_the_last_window_is_analysed() {
+        let mut analyzer = Analyzer::new(SAMPLE_RATE);
+        analyzer.push(&sine(3, 1.0));
+        // Half a window of silence leaves half the sine
+        analyzer.push(&[0.0; ANALYSIS_WINDOW / 2]);
+        let half = analyzer.analyze();
+        assert!(half.bass > 0.5 && half.bass < 1.0);
+
+        let mut long = sine(232, 1.0);
+        long.extend(sine(3, 1.0));
+        let features = analyze(&long);
+        assert_close(features.bass, 1.0, 0.05);
+        assert_close(features.high, 0.0, 0.05);
+    }
+}
@@ -0,0 +1,99 @@
+use std::error::Error;
+use std::fs::File;
+use std::path::Path;
+use symphonia::core::audio::SampleBuffer;
+use symphonia::core::codecs::DecoderOptions;
+use symphonia::core::errors::Error as SymphoniaError;
+use symphonia::core::formats::FormatOptions;
+use symphonia::core::io::MediaSourceStream;
+use symphonia::core::meta::MetadataOptions;
+use symphonia::core::probe::Hint;
+
+// Decoded audio downmixed to a single channel, which is what the beat
+// detector expects
+pub struct MonoAudio {
+    pub samples: Vec<i16>,
+    pub sample_rate: u32,
+}
+
+impl MonoAudio {
+    pub fn duration(&self) -> f64 {
+        self.samples.len() as f64 / self.sample_rate as f64
+    }
+
+    // The samples between two times in seconds, from -1 to 1
+    pub fn window(&self, start: f64, end: f64) -> Vec<f32> {
+        let index = |time: f64| ((time * self.sample_rate as f64) as usize).min(self.samples.len());
+        self.samples[index(start)..index(end).max(index(start))]
+            .iter()
+            .map(|&sample| sample as f32 / i16::MAX as f32)
+            .collect()
+    }
+}
+
+// Decodes a WAV or FLAC file
+pub fn decode_mono(path: impl AsRef<Path>) -> Result<MonoAudio, Box<dyn Error>> {
+    let path = path.as_ref();
+    let source = MediaSourceStream::new(Box::new(File::open(path)?), Default::default());
+
+    let mut hint = Hint::new();
+    if let Some(extension) = path.extension().and_then(|extension| extension.to_str()) {
+        hint.with_extension(extension);
+    }
+
+    let mut format = symphonia::default::get_probe()
+        .format(
+            &hint,
+            source,
+            &FormatOptions::default(),
+            &MetadataOptions::default(),
+        )?
+        .format;
+
+    let track = format
+        .default_track()
+        .ok_or(format!("No audio track in {}", path.display()))?;
+    let track_id = track.id;
+    let sample_rate = track
+        .codec_params
+        .sample_rate
+        .ok_or(format!("Unknown sample rate in {}", path.display()))?;
+    let mut decoder =
+        symphonia::default::get_codecs().make(&track.codec_params, &DecoderOptions::default())?;
+
+    let mut samples = Vec::new();
+    loop {
+        let packet = match format.next_packet() {
+            Ok(packet) => packet,
+            Err(SymphoniaError::IoError(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+                break
+            }
+            Err(e) => return Err(e.into()),
+        };
+        if packet.track_id() != track_id {
+            continue;
+        }
+
+        let decoded = decoder.decode(&packet)?;
+        let spec = *decoded.spec();
+        let channels = spec.channels.count();
+        let mut buffer = SampleBuffer::<i16>::new(decoded.capacity() as u64, spec);
+        buffer.copy_interleaved_ref(decoded);
+
+        for frame in buffer.samples().chunks_exact(channels) {
+            let sum: i32 = frame.iter().map(|sample| *sample as i32).sum();
+            samples.push((sum / channels as i32) as i16);
+        }
+    }
+
+    println!(
+        "Decoded {} ({:.2}s at {}Hz)",
+        path.display(),
+        samples.len() as f64 / sample_rate as f64,
+        sample_rate
+    );
+    Ok(MonoAudio {
+        samples,
+        sample_rate,
+    })
+}
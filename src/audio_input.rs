@@ -0,0 +1,411 @@
+use crate::audio_file;
+use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+use std::error::Error;
+use std::f32::consts::PI;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Samples handed over at a time by the inputs that aren't devices
+const CHUNK_SIZE: usize = 512;
+// The rate the click track and test tone are generated at
+const SYNTHETIC_SAMPLE_RATE: u32 = 44_100;
+
+// Where live audio comes from
+#[derive(Debug, Clone, PartialEq)]
+pub enum AudioInput {
+    // The device at an index of a host's list, e.g. "ASIO:3"
+    DeviceIndex(String, usize),
+    // The first input device on any host with this in its name
+    DeviceName(String),
+    // What's playing on a PulseAudio or PipeWire sink, the default sink when
+    // none is given
+    Monitor(Option<String>),
+    // A WAV or FLAC file fed in at its own pace, over and over
+    File(PathBuf),
+    // Clicks at this BPM, every fourth one higher and louder
+    ClickTrack(f32),
+    // A sine wave at this frequency
+    TestTone(f32),
+}
+
+impl AudioInput {
+    // Parses "device:<name>", "monitor", "monitor:<sink>", "file:<path>",
+    // "click:<bpm>", "tone:<hz>" or "<host>:<index>"
+    pub fn from_name(name: &str) -> AudioInput {
+        match name.split_once(':') {
+            None if name == "monitor" => AudioInput::Monitor(None),
+            Some(("device", device)) => AudioInput::DeviceName(device.to_string()),
+            Some(("monitor", sink)) => AudioInput::Monitor(Some(sink.to_string())),
+            Some(("file", path)) => AudioInput::File(PathBuf::from(path)),
+            Some(("click", bpm)) => match bpm.parse() {
+                Ok(bpm) if bpm > 0.0 => AudioInput::ClickTrack(bpm),
+                _ => panic!("Click track needs a BPM above zero: {name}"),
+            },
+            Some(("tone", frequency)) => match frequency.parse() {
+                Ok(frequency) if frequency > 0.0 => AudioInput::TestTone(frequency),
+                _ => panic!("Test tone needs a frequency above zero: {name}"),
+            },
+            Some((host, index)) => match index.parse() {
+                Ok(index) => AudioInput::DeviceIndex(host.to_string(), index),
+                Err(_) => panic!("Unknown audio input: {name}"),
+            },
+            None => panic!("Unknown audio input: {name}"),
+        }
+    }
+
+    // The device recorded for inputs picked by index or name. Beats for these
+    // come from beat_detector recording the device itself, the other inputs
+    // have theirs tracked from the samples.
+    pub fn live_device(&self) -> Result<Option<cpal::Device>, Box<dyn Error>> {
+        match self {
+            AudioInput::DeviceIndex(host_name, index) => {
+                find_device_by_index(host_name, *index).map(Some)
+            }
+            AudioInput::DeviceName(name) => find_device_by_name(name).map(Some),
+            _ => Ok(None),
+        }
+    }
+
+    // Starts calling the consumer with mono samples from -1 to 1 on another
+    // thread. The consumer is made once the sample rate is known.
+    pub fn start<C>(
+        &self,
+        make_consumer: impl FnOnce(u32) -> C,
+    ) -> Result<AudioStream, Box<dyn Error>>
+    where
+        C: FnMut(&[f32]) + Send + 'static,
+    {
+        if let Some(device) = self.live_device()? {
+            return start_device(&device, make_consumer);
+        }
+        match self {
+            // Started above
+            AudioInput::DeviceIndex(..) | AudioInput::DeviceName(..) => unreachable!(),
+            AudioInput::Monitor(sink) => {
+                let (device, source) = monitor_device(sink.as_deref())?;
+                // The pulse device only reads PULSE_SOURCE while the stream is
+                // opened, put it back so nothing else opened later records the
+                // monitor too
+                let previous_source = std::env::var_os("PULSE_SOURCE");
+                std::env::set_var("PULSE_SOURCE", source);
+                let stream = start_device(&device, make_consumer);
+                match previous_source {
+                    Some(previous_source) => std::env::set_var("PULSE_SOURCE", previous_source),
+                    None => std::env::remove_var("PULSE_SOURCE"),
+                }
+                stream
+            }
+            AudioInput::File(path) => {
+                let audio = audio_file::decode_mono(path)?;
+                let samples: Vec<f32> = audio
+                    .samples
+                    .iter()
+                    .map(|&sample| sample as f32 / i16::MAX as f32)
+                    .collect();
+                if samples.is_empty() {
+                    return Err(format!("No audio in {}", path.display()).into());
+                }
+                let mut position = 0;
+                Ok(start_generator(
+                    audio.sample_rate,
+                    make_consumer(audio.sample_rate),
+                    move |chunk| {
+                        for sample in chunk {
+                            *sample = samples[position];
+                            position = (position + 1) % samples.len();
+                        }
+                    },
+                ))
+            }
+            AudioInput::ClickTrack(bpm) => {
+                // At least a sample apart, however fast the tempo
+                let samples_per_beat = ((SYNTHETIC_SAMPLE_RATE as f32 * 60.0 / bpm) as u64).max(1);
+                let mut position = 0u64;
+                Ok(start_generator(
+                    SYNTHETIC_SAMPLE_RATE,
+                    make_consumer(SYNTHETIC_SAMPLE_RATE),
+                    move |chunk| {
+                        for sample in chunk {
+                            let beat = position / samples_per_beat;
+                            let time =
+                                (position % samples_per_beat) as f32 / SYNTHETIC_SAMPLE_RATE as f32;
+                            let (frequency, amplitude) = if beat % 4 == 0 {
+                                (1_500.0, 0.9)
+                            } else {
+                                (1_000.0, 0.5)
+                            };
+                            // A short sine burst that dies away in about 30ms
+                            *sample = amplitude
+                                * (2.0 * PI * frequency * time).sin()
+                                * (-time * 150.0).exp();
+                            position += 1;
+                        }
+                    },
+                ))
+            }
+            AudioInput::TestTone(frequency) => {
+                let step = frequency / SYNTHETIC_SAMPLE_RATE as f32;
+                let mut phase = 0.0f32;
+                Ok(start_generator(
+                    SYNTHETIC_SAMPLE_RATE,
+                    make_consumer(SYNTHETIC_SAMPLE_RATE),
+                    move |chunk| {
+                        for sample in chunk {
+                            *sample = 0.5 * (2.0 * PI * phase).sin();
+                            phase = (phase + step).fract();
+                        }
+                    },
+                ))
+            }
+        }
+    }
+}
+
+// Audio keeps coming until this is dropped
+pub struct AudioStream {
+    _stream: Option<cpal::Stream>,
+    running: Arc<AtomicBool>,
+}
+
+impl Drop for AudioStream {
+    fn drop(&mut self) {
+        self.running.store(false, Ordering::SeqCst);
+    }
+}
+
+fn find_device_by_index(host_name: &str, index: usize) -> Result<cpal::Device, Box<dyn Error>> {
+    let host_id = cpal::available_hosts()
+        .into_iter()
+        .find(|host_id| host_id.name() == host_name)
+        .ok_or(format!("Unknown audio host: {host_name}"))?;
+    let device = cpal::host_from_id(host_id)?
+        .input_devices()?
+        .nth(index)
+        .ok_or(format!(
+            "No input device {index} on {host_name}, see --list-audio-devices"
+        ))?;
+    // The list can shift when devices come and go, so say what was picked
+    println!(
+        "Using audio device [{index}] {}",
+        device.name().unwrap_or_default()
+    );
+    Ok(device)
+}
+
+fn find_device_by_name(name: &str) -> Result<cpal::Device, Box<dyn Error>> {
+    let name = name.to_lowercase();
+    for host_id in cpal::available_hosts() {
+        let host = cpal::host_from_id(host_id)?;
+        for device in host.input_devices()? {
+            let device_name = device.name().unwrap_or_default();
+            if device_name.to_lowercase().contains(&name) {
+                println!("Using audio device {device_name} on {}", host_id.name());
+                return Ok(device);
+            }
+        }
+    }
+    Err(format!("No input device matching {name}, see --list-audio-devices").into())
+}
+
+// PulseAudio, and PipeWire through pipewire-pulse, expose every sink's output
+// as a "<sink>.monitor" source. ALSA's pulse device records from whichever
+// source PULSE_SOURCE names when it's opened.
+#[cfg(target_os = "linux")]
+fn monitor_device(sink: Option<&str>) -> Result<(cpal::Device, String), Box<dyn Error>> {
+    let sink = match sink {
+        Some(sink) => sink.to_string(),
+        None => {
+            let output = std::process::Command::new("pactl")
+                .arg("get-default-sink")
+                .output()?;
+            if !output.status.success() {
+                return Err("pactl couldn't find the default sink".into());
+            }
+            String::from_utf8(output.stdout)?.trim().to_string()
+        }
+    };
+    let device = cpal::default_host()
+        .input_devices()?
+        .find(|device| device.name().map(|name| name == "pulse").unwrap_or(false))
+        .ok_or("No ALSA pulse device, is the PulseAudio ALSA plugin installed?")?;
+    println!("Recording the output of {sink}");
+    Ok((device, format!("{sink}.monitor")))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn monitor_device(_sink: Option<&str>) -> Result<(cpal::Device, String), Box<dyn Error>> {
+    Err("Monitor capture needs PulseAudio or PipeWire on Linux".into())
+}
+
+pub fn start_device<C>(
+    device: &cpal::Device,
+    make_consumer: impl FnOnce(u32) -> C,
+) -> Result<AudioStream, Box<dyn Error>>
+where
+    C: FnMut(&[f32]) + Send + 'static,
+{
+    let config = device.default_input_config()?;
+    let channels = config.channels() as usize;
+    let mut consumer = make_consumer(config.sample_rate().0);
+    let mut mono = Vec::new();
+    let mut on_frames = move |frames: &mut dyn Iterator<Item = f32>| {
+        mono.clear();
+        mono.extend(frames);
+        consumer(&mono);
+    };
+    let on_error = |e| eprintln!("Audio input stream error: {e}");
+
+    let stream = match config.sample_format() {
+        cpal::SampleFormat::F32 => device.build_input_stream(
+            &config.into(),
+            move |data: &[f32], _: &_| {
+                on_frames(&mut data.chunks(channels).map(|frame| mix_down(frame, |s| s)))
+            },
+            on_error,
+            None,
+        )?,
+        cpal::SampleFormat::I16 => device.build_input_stream(
+            &config.into(),
+            move |data: &[i16], _: &_| {
+                on_frames(
+                    &mut data
+                        .chunks(channels)
+                        .map(|frame| mix_down(frame, |s| s as f32 / i16::MAX as f32)),
+                )
+            },
+            on_error,
+            None,
+        )?,
+        cpal::SampleFormat::U16 => device.build_input_stream(
+            &config.into(),
+            move |data: &[u16], _: &_| {
+                on_frames(
+                    &mut data
+                        .chunks(channels)
+                        .map(|frame| mix_down(frame, |s| s as f32 / u16::MAX as f32 * 2.0 - 1.0)),
+                )
+            },
+            on_error,
+            None,
+        )?,
+        sample_format => return Err(format!("Unsupported sample format {sample_format:?}").into()),
+    };
+    stream.play()?;
+
+    Ok(AudioStream {
+        _stream: Some(stream),
+        running: Arc::new(AtomicBool::new(true)),
+    })
+}
+
+fn mix_down<T: Copy>(frame: &[T], to_f32: impl Fn(T) -> f32) -> f32 {
+    frame.iter().map(|&sample| to_f32(sample)).sum::<f32>() / frame.len() as f32
+}
+
+// Fills chunks on a thread of its own, keeping pace with the wall clock like
+// a device would
+fn start_generator(
+    sample_rate: u32,
+    mut consumer: impl FnMut(&[f32]) + Send + 'static,
+    mut generate: impl FnMut(&mut [f32]) + Send + 'static,
+) -> AudioStream {
+    let running = Arc::new(AtomicBool::new(true));
+    let still_running = running.clone();
+    thread::spawn(move || {
+        let start = Instant::now();
+        let mut samples_sent = 0u64;
+        let mut chunk = [0.0; CHUNK_SIZE];
+        while still_running.load(Ordering::SeqCst) {
+            generate(&mut chunk);
+            consumer(&chunk);
+            samples_sent += CHUNK_SIZE as u64;
+
+            let due = Duration::from_secs_f64(samples_sent as f64 / sample_rate as f64);
+            if let Some(wait) = due.checked_sub(start.elapsed()) {
+                thread::sleep(wait);
+            }
+        }
+    });
+    AudioStream {
+        _stream: None,
+        running,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc::channel;
+
+    #[test]
+    fn inputs_parse_from_their_names() {
+        assert_eq!(
+            AudioInput::from_name("device:Scarlett 2i2"),
+            AudioInput::DeviceName("Scarlett 2i2".to_string())
+        );
+        assert_eq!(AudioInput::from_name("monitor"), AudioInput::Monitor(None));
+        assert_eq!(
+            AudioInput::from_name("monitor:speakers"),
+            AudioInput::Monitor(Some("speakers".to_string()))
+        );
+        assert_eq!(
+            AudioInput::from_name("file:music/track.flac"),
+            AudioInput::File(PathBuf::from("music/track.flac"))
+        );
+        assert_eq!(
+            AudioInput::from_name("click:128"),
+            AudioInput::ClickTrack(128.0)
+        );
+        assert_eq!(
+            AudioInput::from_name("tone:440"),
+            AudioInput::TestTone(440.0)
+        );
+        assert_eq!(
+            AudioInput::from_name("ASIO:3"),
+            AudioInput::DeviceIndex("ASIO".to_string(), 3)
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Unknown audio input")]
+    fn unknown_inputs_panic() {
+        AudioInput::from_name("speakers");
+    }
+
+    #[test]
+    #[should_panic(expected = "BPM above zero")]
+    fn click_tracks_need_a_tempo() {
+        AudioInput::from_name("click:0");
+    }
+
+    #[test]
+    fn only_devices_are_recorded_live() {
+        for name in ["monitor", "file:track.wav", "click:120", "tone:440"] {
+            assert!(AudioInput::from_name(name).live_device().unwrap().is_none());
+        }
+    }
+
+    #[test]
+    fn test_tones_are_generated_at_their_own_rate() {
+        let (sender, receiver) = channel();
+        let (rate_sender, rate_receiver) = channel();
+        let _stream = AudioInput::TestTone(441.0)
+            .start(|sample_rate| {
+                rate_sender.send(sample_rate).unwrap();
+                move |samples: &[f32]| {
+                    let _ = sender.send(samples.to_vec());
+                }
+            })
+            .unwrap();
+
+        assert_eq!(rate_receiver.recv().unwrap(), SYNTHETIC_SAMPLE_RATE);
+        let chunk = receiver.recv().unwrap();
+        assert_eq!(chunk.len(), CHUNK_SIZE);
+        // A quarter of the way through the first cycle is the peak
+        assert!((chunk[25] - 0.5).abs() < 1e-3);
+        assert!(chunk.iter().all(|sample| sample.abs() <= 0.5));
+    }
+}
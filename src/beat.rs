@@ -1,5 +1,6 @@
 use beat_detector::{BeatInfo, StrategyKind};
 use cpal::traits::{DeviceTrait, HostTrait};
+use serde::{Deserialize, Serialize};
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use std::time::Instant;
@@ -41,6 +42,60 @@ pub fn list_audio_devices() {
     }
 }
 
+// Number of samples handed to the detector at a time
+const DETECTOR_WINDOW: usize = 1024;
+
+#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
+pub struct Beat {
+    // Seconds from the start of the audio
+    pub time: f64,
+    pub bpm: f64,
+}
+
+// Finds beats in samples handed over in chunks of any size, from a file or
+// an input that isn't a device. Beat times come from the sample position rather than the wall clock,
+// so they stay aligned with the audio.
+pub fn beat_tracker(sample_rate: u32, sensitivity: u32) -> impl FnMut(&[i16]) -> Vec<Beat> + Send {
+    let detector = StrategyKind::Spectrum.detector(sample_rate, sensitivity as f32);
+
+    let mut ema = EMA::new(32, &500.0).unwrap();
+    let mut last_beat_ms = 0.0;
+    let mut window = Vec::with_capacity(DETECTOR_WINDOW);
+    let mut samples_seen = 0;
+
+    move |samples: &[i16]| {
+        let mut beats = Vec::new();
+        for &sample in samples {
+            window.push(sample);
+            if window.len() < DETECTOR_WINDOW {
+                continue;
+            }
+            if detector.is_beat(&window).is_some() {
+                let beat_ms = samples_seen as f64 * 1000.0 / sample_rate as f64;
+                let ema_result = ema.next(&(beat_ms - last_beat_ms));
+                last_beat_ms = beat_ms;
+
+                beats.push(Beat {
+                    time: beat_ms / 1000.0,
+                    bpm: 60_000.0 / ema_result,
+                });
+            }
+            samples_seen += DETECTOR_WINDOW;
+            window.clear();
+        }
+        beats
+    }
+}
+
+// Runs the same detection used for live input over already decoded audio
+pub fn detect_beats(samples: &[i16], sample_rate: u32, sensitivity: u32) -> Vec<Beat> {
+    let beats = beat_tracker(sample_rate, sensitivity)(samples);
+    println!("Detected {} beats", beats.len());
+    beats
+}
+
+// Records a live input device itself and calls back with each beat and the
+// tempo so far
 pub struct BeatDetector {
     // This should be called on drop since we can't
     // call it from the event_loop
@@ -63,8 +118,7 @@ impl BeatDetector {
 
     pub fn start_listening(
         &mut self,
-        host_name: String,
-        device_id: usize,
+        device: cpal::Device,
         sensitivity: u32,
         mut callback: impl FnMut((BeatInfo, f64)) + Sync + Send + 'static,
     ) {
@@ -76,44 +130,30 @@ impl BeatDetector {
         };
         self.exit_callback = Box::new(exit_callback);
 
-        let mut audio_device = None;
-        for host_id in cpal::available_hosts() {
-            if host_id.name() == host_name {
-                let host = cpal::host_from_id(host_id).unwrap();
-                for (i, device) in host.input_devices().unwrap().enumerate() {
-                    if i == device_id {
-                        audio_device = Some(device);
-                    }
-                }
-            }
-        }
-
-        if let Some(device) = audio_device {
-            let strategy = StrategyKind::Spectrum;
+        let strategy = StrategyKind::Spectrum;
 
-            let mut ema = EMA::new(32, &500.0).unwrap();
+        let mut ema = EMA::new(32, &500.0).unwrap();
 
-            let mut last_beat = Instant::now();
-            let on_beat = move |info: BeatInfo| {
-                // beat detectors relative_ms is unreliable, since we
-                // are reading live audio data just use the current time
-                let current_beat = Instant::now();
-                let time_since_last_beat = (current_beat - last_beat).as_millis() as f64;
-                let ema_result = ema.next(&time_since_last_beat);
+        let mut last_beat = Instant::now();
+        let on_beat = move |info: BeatInfo| {
+            // beat detectors relative_ms is unreliable, since we
+            // are reading live audio data just use the current time
+            let current_beat = Instant::now();
+            let time_since_last_beat = (current_beat - last_beat).as_millis() as f64;
+            let ema_result = ema.next(&time_since_last_beat);
 
-                last_beat = current_beat;
-                //        println!("EMA: {ema_result} BPM: {}", 60_000.0 / ema_result);
-                //        println!("Beat detected: {:?}", info,);
-                callback((info, 60_000.0 / ema_result));
-            };
-            let _ = beat_detector::record::start_listening(
-                on_beat,
-                Some(device),
-                strategy,
-                sensitivity as f32,
-                recording,
-            )
-            .unwrap();
-        }
+            last_beat = current_beat;
+            //        println!("EMA: {ema_result} BPM: {}", 60_000.0 / ema_result);
+            //        println!("Beat detected: {:?}", info,);
+            callback((info, 60_000.0 / ema_result));
+        };
+        let _ = beat_detector::record::start_listening(
+            on_beat,
+            Some(device),
+            strategy,
+            sensitivity as f32,
+            recording,
+        )
+        .unwrap();
     }
 }
@@ -0,0 +1,488 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+// Detected beats pull the clock this much of the way onto the beat grid, so
+// one early or late detection doesn't throw it off
+const PHASE_CORRECTION: f64 = 0.5;
+
+// Taps further apart than this start a new tap tempo
+const TAP_TIMEOUT: f64 = 2.0;
+const MAX_TAPS: usize = 8;
+
+const MIN_BPM: f32 = 40.0;
+const MAX_BPM: f32 = 240.0;
+
+// How quickly the accents of older bars are forgotten when looking for the
+// downbeat, and how much stronger another beat has to be to take over
+const ACCENT_DECAY: f32 = 0.9;
+const DOWNBEAT_MARGIN: f32 = 1.2;
+
+// MIDI clock ticks per beat
+const MIDI_TICKS_PER_BEAT: u64 = 24;
+// Ticks in a sixteenth note, the unit of the song position
+const MIDI_TICKS_PER_SIXTEENTH: u64 = MIDI_TICKS_PER_BEAT / 4;
+// The tempo is averaged over this many tick intervals
+const MIDI_TEMPO_TICKS: usize = 24;
+// A MIDI clock that hasn't ticked for this long has gone away
+const MIDI_CLOCK_TIMEOUT: Duration = Duration::from_secs(1);
+
+// What changes wait for on the beat grid
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Quantize {
+    Off,
+    Beat,
+    Bar,
+}
+
+impl Quantize {
+    pub fn from_name(name: Option<&str>) -> Quantize {
+        match name {
+            None | Some("off") => Quantize::Off,
+            Some("beat") => Quantize::Beat,
+            Some("bar") => Quantize::Bar,
+            Some(name) => panic!("Unknown quantize setting: {name}"),
+        }
+    }
+}
+
+// Where the clock is on the beat grid this frame
+#[derive(Debug, Default, Copy, Clone, PartialEq)]
+pub struct ClockPosition {
+    pub beat_phase: f32,
+    pub bar_phase: f32,
+    // Whether a beat or a downbeat went by since the last frame
+    pub crossed_beat: bool,
+    pub crossed_bar: bool,
+}
+
+// A beat and bar clock that keeps running between detected beats. Detected
+// beats lock its phase and tempo, unless the tempo has been tapped in.
+pub struct BeatClock {
+    bpm: f32,
+    beats_per_bar: u32,
+    // Beats since the clock started, the fraction is the phase
+    position: f64,
+    previous_position: f64,
+    // The beat position of a downbeat, bars start every beats_per_bar from it
+    bar_offset: f64,
+    // How strong the beats landing on each beat of the bar have been
+    accents: Vec<f32>,
+    // Seconds since the clock started
+    time: f64,
+    taps: Vec<f64>,
+    tapped: bool,
+}
+
+impl BeatClock {
+    pub fn new(beats_per_bar: u32) -> Self {
+        let beats_per_bar = beats_per_bar.max(1);
+        BeatClock {
+            bpm: 120.0,
+            beats_per_bar,
+            position: 0.0,
+            previous_position: 0.0,
+            bar_offset: 0.0,
+            accents: vec![0.0; beats_per_bar as usize],
+            time: 0.0,
+            taps: Vec::new(),
+            tapped: false,
+        }
+    }
+
+    pub fn advance(&mut self, seconds: f32) {
+        self.time += seconds as f64;
+        self.previous_position = self.position;
+        self.position += seconds as f64 * self.bpm as f64 / 60.0;
+    }
+
+    // Follows an external clock exactly, at its tempo and, when it's playing,
+    // at its position in beats from the top of the song
+    pub fn sync(&mut self, bpm: f32, beats: Option<f64>) {
+        self.bpm = bpm;
+        if let Some(beats) = beats {
+            self.position = beats;
+            self.bar_offset = 0.0;
+        }
+    }
+
+    // A detected beat with the detected tempo and how hard it hit, e.g. the
+    // bass level
+    pub fn on_beat(&mut self, bpm: f32, strength: f32) {
+        if !self.tapped && bpm.is_finite() {
+            self.bpm = bpm.clamp(MIN_BPM, MAX_BPM);
+        }
+        let nearest_beat = self.position.round();
+        self.position += (nearest_beat - self.position) * PHASE_CORRECTION;
+        self.track_downbeat(nearest_beat, strength);
+    }
+
+    // The downbeat is whichever beat of the bar has been hit hardest lately
+    fn track_downbeat(&mut self, beat: f64, strength: f32) {
+        for accent in &mut self.accents {
+            *accent *= ACCENT_DECAY;
+        }
+        let beat_in_bar = self.beat_in_bar_at(beat);
+        self.accents[beat_in_bar] += strength;
+
+        let (strongest, accent) = self
+            .accents
+            .iter()
+            .copied()
+            .enumerate()
+            .max_by(|(_, a), (_, b)| a.total_cmp(b))
+            .unwrap();
+        if strongest != 0 && accent > self.accents[0] * DOWNBEAT_MARGIN {
+            self.bar_offset += strongest as f64;
+            self.accents.rotate_left(strongest);
+        }
+    }
+
+    // Taps on the beat set the tempo and phase until the tap tempo is released
+    pub fn tap(&mut self) {
+        if let Some(&last_tap) = self.taps.last() {
+            if self.time - last_tap > TAP_TIMEOUT {
+                self.taps.clear();
+            }
+        }
+        self.taps.push(self.time);
+        if self.taps.len() > MAX_TAPS {
+            self.taps.remove(0);
+        }
+
+        self.position = self.position.round();
+        if self.taps.len() >= 2 {
+            let seconds = (self.taps[self.taps.len() - 1] - self.taps[0]) as f32;
+            let bpm = 60.0 * (self.taps.len() - 1) as f32 / seconds;
+            self.bpm = bpm.clamp(MIN_BPM, MAX_BPM);
+            self.tapped = true;
+        }
+    }
+
+    // Goes back to following the detected tempo
+    pub fn release_tap_tempo(&mut self) {
+        self.taps.clear();
+        self.tapped = false;
+    }
+
+    // Shifts the phase by a fraction of a beat, positive is later
+    pub fn nudge(&mut self, beats: f32) {
+        self.position += beats as f64;
+    }
+
+    // Makes the nearest beat the first of its bar
+    pub fn set_downbeat(&mut self) {
+        self.bar_offset = self.position.round();
+        self.accents.iter_mut().for_each(|accent| *accent = 0.0);
+    }
+
+    pub fn bpm(&self) -> f32 {
+        self.bpm
+    }
+
+    pub fn is_tapped(&self) -> bool {
+        self.tapped
+    }
+
+    pub fn beats_per_bar(&self) -> u32 {
+        self.beats_per_bar
+    }
+
+    // How far through the current beat, from 0 to 1
+    pub fn beat_phase(&self) -> f32 {
+        self.position.rem_euclid(1.0) as f32
+    }
+
+    // How far through the current bar, from 0 to 1
+    pub fn bar_phase(&self) -> f32 {
+        ((self.position - self.bar_offset).rem_euclid(self.beats_per_bar as f64)
+            / self.beats_per_bar as f64) as f32
+    }
+
+    // Counting from 0 at the downbeat
+    pub fn beat_in_bar(&self) -> u32 {
+        self.beat_in_bar_at(self.position.floor()) as u32
+    }
+
+    fn beat_in_bar_at(&self, beat: f64) -> usize {
+        (beat - self.bar_offset).rem_euclid(self.beats_per_bar as f64) as usize
+    }
+
+    pub fn position(&self) -> ClockPosition {
+        ClockPosition {
+            beat_phase: self.beat_phase(),
+            bar_phase: self.bar_phase(),
+            crossed_beat: self.crossed_beat(),
+            crossed_bar: self.crossed_bar(),
+        }
+    }
+
+    // Whether the last advance crossed a beat
+    fn crossed_beat(&self) -> bool {
+        self.position.floor() > self.previous_position.floor()
+    }
+
+    // Whether the last advance crossed a downbeat
+    fn crossed_bar(&self) -> bool {
+        let bar =
+            |position: f64| ((position - self.bar_offset) / self.beats_per_bar as f64).floor();
+        bar(self.position) > bar(self.previous_position)
+    }
+
+    // Whether something waiting for the grid can happen now
+    pub fn on_grid(&self, quantize: Quantize) -> bool {
+        match quantize {
+            Quantize::Off => true,
+            Quantize::Beat => self.crossed_beat(),
+            Quantize::Bar => self.crossed_bar(),
+        }
+    }
+}
+
+// Follows the clock ticks and transport messages of a DAW or drum machine
+#[derive(Debug, Default)]
+pub struct MidiClock {
+    playing: bool,
+    // Ticks from the top of the song to the latest one
+    position: u64,
+    // Where the next tick lands. After Start it's the downbeat.
+    next_tick: u64,
+    // When the latest ticks were sent, in microseconds
+    tick_times: VecDeque<u64>,
+    last_received: Option<Instant>,
+}
+
+impl MidiClock {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn tick(&mut self, time: u64) {
+        if self.playing {
+            self.position = self.next_tick;
+            self.next_tick += 1;
+        }
+        self.tick_times.push_back(time);
+        if self.tick_times.len() > MIDI_TEMPO_TICKS + 1 {
+            self.tick_times.pop_front();
+        }
+        self.last_received = Some(Instant::now());
+    }
+
+    pub fn start(&mut self) {
+        self.playing = true;
+        self.position = 0;
+        self.next_tick = 0;
+    }
+
+    pub fn resume(&mut self) {
+        self.playing = true;
+    }
+
+    pub fn stop(&mut self) {
+        self.playing = false;
+    }
+
+    pub fn song_position(&mut self, sixteenths: u16) {
+        self.next_tick = sixteenths as u64 * MIDI_TICKS_PER_SIXTEENTH;
+        self.position = self.next_tick;
+    }
+
+    // Ticks keep coming while some devices are stopped, so the tempo is known
+    // whenever the clock is there
+    pub fn bpm(&self) -> Option<f32> {
+        let received = self.last_received?;
+        if received.elapsed() > MIDI_CLOCK_TIMEOUT || self.tick_times.len() < 2 {
+            return None;
+        }
+        let intervals = (self.tick_times.len() - 1) as f64;
+        let span =
+            self.tick_times[self.tick_times.len() - 1].saturating_sub(self.tick_times[0]) as f64;
+        if span <= 0.0 {
+            return None;
+        }
+        let seconds_per_tick = span / intervals / 1_000_000.0;
+        Some((60.0 / (seconds_per_tick * MIDI_TICKS_PER_BEAT as f64)) as f32)
+    }
+
+    // Beats from the top of the song, while it's playing
+    pub fn beats(&self) -> Option<f64> {
+        (self.playing && self.bpm().is_some())
+            .then(|| self.position as f64 / MIDI_TICKS_PER_BEAT as f64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(actual: f64, expected: f64) {
+        assert!(
+            (actual - expected).abs() < 1e-3,
+            "expected {expected}, got {actual}"
+        );
+    }
+
+    #[test]
+    fn taps_set_the_tempo_until_released() {
+        let mut clock = BeatClock::new(4);
+        for _ in 0..4 {
+            clock.tap();
+            clock.advance(0.4);
+        }
+        assert!(clock.is_tapped());
+        assert_close(clock.bpm() as f64, 150.0);
+
+        // Detected beats don't change a tapped tempo
+        clock.on_beat(100.0, 1.0);
+        assert_close(clock.bpm() as f64, 150.0);
+
+        clock.release_tap_tempo();
+        clock.on_beat(100.0, 1.0);
+        assert_close(clock.bpm() as f64, 100.0);
+    }
+
+    #[test]
+    fn taps_after_a_pause_start_a_new_tempo() {
+        let mut clock = BeatClock::new(4);
+        clock.tap();
+        clock.advance(0.5);
+        clock.tap();
+        clock.advance(TAP_TIMEOUT as f32 + 1.0);
+        clock.tap();
+        clock.advance(0.25);
+        clock.tap();
+        assert_close(clock.bpm() as f64, 240.0);
+    }
+
+    #[test]
+    fn detected_beats_pull_the_phase_onto_the_grid() {
+        let mut clock = BeatClock::new(4);
+        // A fifth of a beat late at 120 BPM
+        clock.advance(0.1);
+        assert_close(clock.beat_phase() as f64, 0.2);
+        clock.on_beat(120.0, 1.0);
+        assert_close(clock.beat_phase() as f64, 0.1);
+        clock.on_beat(120.0, 1.0);
+        assert_close(clock.beat_phase() as f64, 0.05);
+
+        // and just before the next beat
+        let mut clock = BeatClock::new(4);
+        clock.advance(0.4);
+        clock.on_beat(120.0, 1.0);
+        assert_close(clock.beat_phase() as f64, 0.9);
+    }
+
+    #[test]
+    fn detected_tempos_are_kept_in_range() {
+        let mut clock = BeatClock::new(4);
+        clock.on_beat(1_000.0, 1.0);
+        assert_eq!(clock.bpm(), MAX_BPM);
+        clock.on_beat(f32::NAN, 1.0);
+        assert_eq!(clock.bpm(), MAX_BPM);
+        clock.on_beat(10.0, 1.0);
+        assert_eq!(clock.bpm(), MIN_BPM);
+    }
+
+    #[test]
+    fn the_strongest_beat_becomes_the_downbeat() {
+        let mut clock = BeatClock::new(4);
+        assert_eq!(clock.beat_in_bar(), 0);
+        // Bars where the third beat hits hardest
+        for beat in 0..8 {
+            let strength = if beat % 4 == 2 { 1.0 } else { 0.2 };
+            clock.on_beat(120.0, strength);
+            clock.advance(0.5);
+        }
+        assert_eq!(clock.beat_in_bar(), 2);
+        assert_close(clock.bar_phase() as f64, 0.5);
+    }
+
+    #[test]
+    fn set_downbeat_starts_the_bar_on_the_nearest_beat() {
+        let mut clock = BeatClock::new(4);
+        // A fifth of the way into the sixth beat
+        clock.advance(2.6);
+        clock.set_downbeat();
+        assert_eq!(clock.beat_in_bar(), 0);
+        assert_close(clock.bar_phase() as f64, 0.05);
+        clock.advance(0.5);
+        assert_eq!(clock.beat_in_bar(), 1);
+        clock.advance(1.5);
+        assert_eq!(clock.beat_in_bar(), 0);
+    }
+
+    #[test]
+    fn crossing_the_grid() {
+        let mut clock = BeatClock::new(4);
+        clock.advance(0.25);
+        assert!(clock.on_grid(Quantize::Off));
+        assert!(!clock.on_grid(Quantize::Beat));
+        clock.advance(0.3);
+        assert!(clock.on_grid(Quantize::Beat));
+        assert!(!clock.on_grid(Quantize::Bar));
+        clock.advance(1.5);
+        assert!(clock.on_grid(Quantize::Bar));
+        let position = clock.position();
+        assert!(position.crossed_beat && position.crossed_bar);
+    }
+
+    // Ticks a MIDI clock at a tempo, starting from a time in microseconds
+    fn tick(clock: &mut MidiClock, bpm: f64, start: u64, ticks: u64) -> u64 {
+        let interval = 60_000_000.0 / (bpm * MIDI_TICKS_PER_BEAT as f64);
+        for tick in 0..ticks {
+            clock.tick(start + (tick as f64 * interval) as u64);
+        }
+        start + (ticks as f64 * interval) as u64
+    }
+
+    #[test]
+    fn midi_clock_tempo() {
+        let mut clock = MidiClock::new();
+        assert_eq!(clock.bpm(), None);
+        clock.tick(0);
+        assert_eq!(clock.bpm(), None);
+
+        let time = tick(&mut clock, 125.0, 0, 48);
+        assert_close(clock.bpm().unwrap() as f64, 125.0);
+
+        // Only the latest ticks count once the tempo changes
+        tick(&mut clock, 90.0, time, MIDI_TEMPO_TICKS as u64 + 1);
+        assert_close(clock.bpm().unwrap() as f64, 90.0);
+    }
+
+    #[test]
+    fn midi_clock_with_the_same_tick_time_has_no_tempo() {
+        let mut clock = MidiClock::new();
+        clock.tick(500);
+        clock.tick(500);
+        assert_eq!(clock.bpm(), None);
+    }
+
+    #[test]
+    fn midi_transport() {
+        let mut clock = MidiClock::new();
+        let time = tick(&mut clock, 120.0, 0, 24);
+        // Stopped, so only the tempo is known
+        assert_eq!(clock.beats(), None);
+
+        // The first tick after Start is the downbeat
+        clock.start();
+        let time = tick(&mut clock, 120.0, time, 1);
+        assert_close(clock.beats().unwrap(), 0.0);
+        let time = tick(&mut clock, 120.0, time, 48);
+        assert_close(clock.beats().unwrap(), 2.0);
+
+        clock.stop();
+        let time = tick(&mut clock, 120.0, time, 24);
+        assert_eq!(clock.beats(), None);
+        clock.resume();
+        assert_close(clock.beats().unwrap(), 2.0);
+
+        // Eight sixteenths is two beats, and the next tick lands on them
+        clock.song_position(8);
+        let time = tick(&mut clock, 120.0, time, 1);
+        assert_close(clock.beats().unwrap(), 2.0);
+        tick(&mut clock, 120.0, time, 12);
+        assert_close(clock.beats().unwrap(), 2.5);
+    }
+}
@@ -8,8 +8,35 @@ use config::Config;
 pub struct AppConfig {
     pub audio_host_name: Option<String>,
     pub audio_device_id: Option<usize>,
+    pub audio_input: Option<String>,
     pub midi_device_id: Option<usize>,
+    pub midi_mapping: Option<String>,
+    pub midi_output_device_id: Option<usize>,
+    pub osc_port: Option<u16>,
+    pub osc_address: Option<String>,
     pub beat_sensitivity: Option<u32>,
+    pub preset_format: Option<String>,
+    pub initial_preset: Option<String>,
+    pub preset_directory: Option<String>,
+    pub restore_session: Option<String>,
+    pub seed: Option<u64>,
+    pub simulation_backend: Option<String>,
+    pub simulation_resolution: Option<String>,
+    pub resample_on_resize: Option<bool>,
+    pub species_file: Option<String>,
+    pub shader_directory: Option<String>,
+    pub post_process: Option<String>,
+    pub post_process_lut: Option<String>,
+    pub modulation_file: Option<String>,
+    pub beat_preset_swap: Option<bool>,
+    pub beats_per_bar: Option<u32>,
+    pub quantize: Option<String>,
+    pub render_frames: Option<u32>,
+    pub render_width: Option<u32>,
+    pub render_height: Option<u32>,
+    pub render_output: Option<String>,
+    pub render_frame_rate: Option<u32>,
+    pub render_audio: Option<String>,
 }
 
 pub fn get_config() -> AppConfig {
@@ -17,7 +44,7 @@ pub fn get_config() -> AppConfig {
         .add_source(config::File::with_name("Config"))
         .add_source(config::Environment::with_prefix("MOLD"))
         .build()
-        .unwrap_or(Config::default());
+        .unwrap_or_default();
 
     let mut app_config: AppConfig = config.try_deserialize().unwrap();
 
@@ -37,6 +64,12 @@ pub fn get_config() -> AppConfig {
                 app_config.audio_host_name = Some(host.to_string());
                 app_config.audio_device_id = Some(device.parse().unwrap());
             }
+            "--audio-input" => {
+                // "device:<name>", "monitor", "file:<path>", "click:<bpm>", "tone:<hz>"
+                // or "<host>:<index>"
+                let audio_input = args.next().unwrap();
+                app_config.audio_input = Some(audio_input);
+            }
             "--list-midi-devices" => {
                 midi::list_midi_devices();
             }
@@ -44,6 +77,104 @@ pub fn get_config() -> AppConfig {
                 let device_identifier = args.next().unwrap();
                 app_config.midi_device_id = Some(device_identifier.parse().unwrap());
             }
+            "--set-midi-output-device" => {
+                let device_identifier = args.next().unwrap();
+                app_config.midi_output_device_id = Some(device_identifier.parse().unwrap());
+            }
+            "--osc-port" => {
+                let port = args.next().unwrap();
+                app_config.osc_port = Some(port.parse().unwrap());
+            }
+            "--osc-address" => {
+                let address = args.next().unwrap();
+                app_config.osc_address = Some(address);
+            }
+            "--midi-mapping" => {
+                let mapping_file = args.next().unwrap();
+                app_config.midi_mapping = Some(mapping_file);
+            }
+            "--load-preset" => {
+                let preset_path = args.next().unwrap();
+                app_config.initial_preset = Some(preset_path);
+            }
+            "--set-preset-directory" => {
+                let preset_directory = args.next().unwrap();
+                app_config.preset_directory = Some(preset_directory);
+            }
+            "--restore-session" => {
+                let session_dir = args.next().unwrap();
+                app_config.restore_session = Some(session_dir);
+            }
+            "--seed" => {
+                let seed = args.next().unwrap();
+                app_config.seed = Some(seed.parse().unwrap());
+            }
+            "--backend" => {
+                let backend = args.next().unwrap();
+                app_config.simulation_backend = Some(backend);
+            }
+            "--simulation-resolution" => {
+                // Either "full", "half", "quarter" or a fixed size like "3840x2160"
+                let resolution = args.next().unwrap();
+                app_config.simulation_resolution = Some(resolution);
+            }
+            "--species" => {
+                let species_file = args.next().unwrap();
+                app_config.species_file = Some(species_file);
+            }
+            "--shader-dir" => {
+                let shader_directory = args.next().unwrap();
+                app_config.shader_directory = Some(shader_directory);
+            }
+            "--post-process" => {
+                // Effects in the order they're applied, like "kaleidoscope,bloom"
+                let effects = args.next().unwrap();
+                app_config.post_process = Some(effects);
+            }
+            "--lut" => {
+                let lut_path = args.next().unwrap();
+                app_config.post_process_lut = Some(lut_path);
+            }
+            "--modulation" => {
+                let modulation_file = args.next().unwrap();
+                app_config.modulation_file = Some(modulation_file);
+            }
+            "--no-beat-preset" => {
+                // Beats only drive the modulation routes
+                app_config.beat_preset_swap = Some(false);
+            }
+            "--beats-per-bar" => {
+                let beats_per_bar = args.next().unwrap();
+                app_config.beats_per_bar = Some(beats_per_bar.parse().unwrap());
+            }
+            "--quantize" => {
+                // Either "off", "beat" or "bar"
+                let quantize = args.next().unwrap();
+                app_config.quantize = Some(quantize);
+            }
+            "--render-frames" => {
+                let frames = args.next().unwrap();
+                app_config.render_frames = Some(frames.parse().unwrap());
+            }
+            "--render-size" => {
+                let size = args.next().unwrap();
+                // We allow passing on command line like "1920x1080"
+                let (width, height) = size.split_once('x').unwrap();
+                app_config.render_width = Some(width.parse().unwrap());
+                app_config.render_height = Some(height.parse().unwrap());
+            }
+            "--render-output" => {
+                let output = args.next().unwrap();
+                app_config.render_output = Some(output);
+            }
+            "--render-frame-rate" => {
+                let frame_rate = args.next().unwrap();
+                app_config.render_frame_rate = Some(frame_rate.parse().unwrap());
+            }
+            "--render-audio" => {
+                let audio_path = args.next().unwrap();
+                app_config.render_audio = Some(audio_path);
+            }
             "--list-audio-devices" => {
                 beat::list_audio_devices();
             }
@@ -0,0 +1,803 @@
+use crate::preset::{ColorStrategy, InitialParameters, Preset, TurnStrategy, WallStrategy};
+use crate::shader_pipeline::{self, Position};
+use crate::simulation::{self, PipelineState, SimulationBackend, Species, BLUR_RADIUS};
+
+type Texel = [f32; 4];
+
+// Same as the constants in shader_2
+const BLUR_DIRECTIONS: f32 = 8.0;
+const BLUR_QUALITY: f32 = 1.0;
+
+// The shaders use this rounded value rather than the real pi
+#[allow(clippy::approx_constant)]
+const SHADER_PI: f32 = 3.14159;
+
+// A pure Rust version of ShaderPipeline. Each step follows shader_1 and
+// shader_2 line by line, including their quirks, so it can be used to check
+// the GPU output and to render on machines without OpenGL. It is a lot slower.
+pub struct CpuPipeline {
+    width: u32,
+    height: u32,
+    // One buffer per species
+    particles: Vec<Vec<Position>>,
+    // The output of the agent step (u_texture0 in ShaderPipeline)
+    texture0: Vec<Texel>,
+    // The output of the diffuse step (u_texture1 in ShaderPipeline)
+    texture1: Vec<Texel>,
+}
+
+impl CpuPipeline {
+    pub fn new(width: u32, height: u32, preset: Preset) -> Self {
+        Self {
+            width,
+            height,
+            particles: vec![shader_pipeline::initial_positions(
+                preset.initial_parameters,
+            )],
+            texture0: vec![[0.0; 4]; (width * height) as usize],
+            texture1: vec![[0.0; 4]; (width * height) as usize],
+        }
+    }
+
+    // shader_1: move every agent and draw it as a point into texture0
+    fn step_agents(&mut self, species: &[Species], u_time: f32) {
+        let mut texture0 = vec![[0.0, 0.0, 0.0, 1.0]; (self.width * self.height) as usize];
+
+        self.particles.resize_with(species.len(), Default::default);
+        for (index, species_particles) in self.particles.iter_mut().enumerate() {
+            if species_particles.is_empty() {
+                *species_particles =
+                    shader_pipeline::initial_positions(species[index].preset.initial_parameters);
+            }
+        }
+
+        // With several species each one only writes to its own channel
+        let channel = |index: usize| simulation::is_multi_species(species).then_some(index);
+
+        let mut all_particles = Vec::with_capacity(species.len());
+        for (index, (current, species_particles)) in species.iter().zip(&self.particles).enumerate()
+        {
+            let mut particles = Vec::with_capacity(species_particles.len());
+            for particle in species_particles {
+                let (position, color) = self.step_agent(particle.a_position, current, u_time);
+                self.draw_point(
+                    &mut texture0,
+                    position,
+                    color,
+                    simulation::point_size(&current.preset, self.height),
+                    channel(index),
+                );
+                particles.push(Position {
+                    a_position: position,
+                });
+            }
+            all_particles.push(particles);
+        }
+
+        self.particles = all_particles;
+        self.texture0 = texture0;
+    }
+
+    fn step_agent(
+        &self,
+        a_position: [f32; 4],
+        species: &Species,
+        u_time: f32,
+    ) -> ([f32; 4], Texel) {
+        let preset = species.preset;
+        let [x, y, z, w] = a_position;
+
+        // Coord in texture space
+        let texcoord = [(x + 1.0) / 2.0, (y + 1.0) / 2.0];
+        let tex_val = self.sample(&self.texture1, texcoord);
+        let noise = glsl_rand([texcoord[0] + tex_val[0], texcoord[1] + tex_val[1]]);
+
+        // Get speed and direction
+        let mut direction = (w - 1.0) * 1000.0;
+        let speed_var = z * 1000.0;
+
+        direction += preset.random_steer_factor * 3.0 * (noise - 0.5);
+
+        let mut speed = preset.speed_multiplier * speed_var;
+
+        // Read the underlying texture with sensors spread evenly from
+        // search_angle on the right to search_angle on the left
+        let sense = |direction: f32| {
+            let sample = self.sample(
+                &self.texture1,
+                [
+                    texcoord[0] + direction.cos() * preset.search_radius,
+                    texcoord[1] + direction.sin() * preset.search_radius,
+                ],
+            );
+            (0..3)
+                .map(|channel| sample[channel] * species.sense_weights[channel])
+                .sum::<f32>()
+        };
+        let sensor_count = preset.sensor_count.get();
+        let centre = (sensor_count - 1) as f32 / 2.0;
+        let mut sense_forward = f32::MIN;
+        let mut sense_left = f32::MIN;
+        let mut sense_right = f32::MIN;
+        let mut strongest = (f32::MIN, 0.0);
+        let mut total = 0.0;
+        let mut weighted_angle = 0.0;
+        for sensor in 0..sensor_count {
+            let offset = sensor as f32 - centre;
+            let angle = preset.search_angle * offset / centre;
+            let sensed = sense(direction + angle);
+
+            // The one or two sensors in the middle look forward
+            if offset.abs() < 1.0 {
+                sense_forward = sense_forward.max(sensed);
+            } else if offset > 0.0 {
+                sense_left = sense_left.max(sensed);
+            } else {
+                sense_right = sense_right.max(sensed);
+            }
+
+            if sensed > strongest.0 {
+                strongest = (sensed, angle);
+            }
+            total += sensed.max(0.0);
+            weighted_angle += sensed.max(0.0) * angle;
+        }
+
+        let steer_amount = preset.constant_steer_factor + preset.random_steer_factor * noise;
+
+        match preset.turn_strategy {
+            TurnStrategy::Steer => {
+                if sense_forward > sense_left && sense_forward > sense_right {
+                    // Straight ahead
+                } else if sense_forward < sense_left && sense_forward < sense_right {
+                    direction += preset.random_steer_factor * (noise - 0.5);
+                } else if sense_right > sense_left {
+                    direction -= steer_amount;
+                } else if sense_right < sense_left {
+                    direction += steer_amount;
+                }
+            }
+            TurnStrategy::Strongest => {
+                if strongest.0 > sense_forward {
+                    direction += strongest.1;
+                }
+            }
+            TurnStrategy::Weighted => {
+                if total > 0.0 && preset.search_angle > 0.0 {
+                    direction += steer_amount * weighted_angle / (total * preset.search_angle);
+                }
+            }
+        }
+
+        let mut y_new = y;
+        let mut x_new = x;
+
+        match preset.wall_strategy {
+            WallStrategy::None => (),
+            WallStrategy::Wrap => {
+                if y_new > 0.99 {
+                    y_new = -0.99;
+                }
+                if y_new < -0.99 {
+                    y_new = 0.99;
+                }
+                if x_new > 0.99 {
+                    x_new = -0.99;
+                }
+                if x_new < -0.99 {
+                    x_new = 0.99;
+                }
+            }
+            WallStrategy::Bounce | WallStrategy::BounceRandom => {
+                let random_angle = if preset.wall_strategy == WallStrategy::BounceRandom {
+                    noise * preset.random_steer_factor
+                } else {
+                    0.0
+                };
+                if y_new + speed * direction.sin() > 0.90 {
+                    let d = direction.sin().atan2(direction.cos());
+                    direction -= 2.0 * d + random_angle;
+                }
+                if y_new + speed * direction.sin() < -0.90 {
+                    let d = direction.sin().atan2(direction.cos());
+                    direction -= 2.0 * d + random_angle;
+                }
+                if x_new + speed * direction.cos() > 0.90 {
+                    let d = direction.cos().atan2(direction.sin());
+                    direction += 2.0 * d + random_angle;
+                }
+                if x_new + speed * direction.cos() < -0.90 {
+                    let d = direction.cos().atan2(direction.sin());
+                    direction += 2.0 * d + random_angle;
+                }
+            }
+            WallStrategy::SlowAndReverse => {
+                let boundary = 0.75;
+                let slowdown_factor = 0.75;
+
+                if (y_new + speed * direction.sin()).abs() > boundary {
+                    speed *= slowdown_factor;
+                    direction = SHADER_PI - direction;
+                }
+                if (x_new + speed * direction.cos()).abs() > boundary {
+                    speed *= slowdown_factor;
+                    direction = -direction;
+                }
+            }
+        }
+
+        // The shader applies the speed multiplier twice
+        y_new += speed * preset.speed_multiplier * direction.sin();
+        x_new += speed * preset.speed_multiplier * direction.cos();
+
+        let trail_strength = preset.trail_strength;
+        let distance_from_center = (x_new * x_new + y_new * y_new).sqrt();
+        let normalized_distance = distance_from_center / 1.3;
+        // The color of each strategy, mixing in the one being blended towards
+        let strategy_color = |strategy| match strategy {
+            ColorStrategy::Direction => (direction.sin(), direction.cos(), trail_strength),
+            ColorStrategy::Speed => (speed_var * 50.0, speed_var * 50.0, trail_strength),
+            ColorStrategy::Position => (
+                y_new.abs() / 2.0 + 0.5,
+                x_new.abs() / 2.0 + 0.5,
+                trail_strength,
+            ),
+            ColorStrategy::Grey => (trail_strength, trail_strength, trail_strength),
+            ColorStrategy::Hue => {
+                let hue = y_new.atan2(x_new) / (2.0 * SHADER_PI) + 0.5;
+                let rgb = hsv2rgb([hue, 1.0 - normalized_distance, 1.0]);
+                (rgb[0], rgb[1], trail_strength)
+            }
+            ColorStrategy::Distance => (
+                mix(0.2, 1.0, normalized_distance),
+                mix(0.5, 1.0, normalized_distance),
+                trail_strength,
+            ),
+            ColorStrategy::Time => {
+                let time_factor = (u_time * 0.5).sin();
+                (
+                    0.5 + 0.5 * (2.0 * SHADER_PI * (x_new + y_new) + time_factor).sin(),
+                    0.5 + 0.5 * (2.0 * SHADER_PI * (x_new - y_new) + time_factor).sin(),
+                    trail_strength,
+                )
+            }
+            // The palette index times the trail strength, see shader_1
+            ColorStrategy::PaletteTrail => (0.0, 0.0, trail_strength),
+            ColorStrategy::PaletteDirection => (
+                fract(direction / (2.0 * SHADER_PI)) * trail_strength,
+                0.0,
+                trail_strength,
+            ),
+            ColorStrategy::PaletteSpeed => (
+                (speed_var * 50.0).clamp(0.0, 1.0) * trail_strength,
+                0.0,
+                trail_strength,
+            ),
+        };
+        let (r0, g0, b0) = strategy_color(preset.color_strategy);
+        let (r1, g1, b1) = strategy_color(preset.color_blend.target);
+        let amount = preset.color_blend.amount;
+        let (r, g, b) = (
+            mix(r0, r1, amount),
+            mix(g0, g1, amount),
+            mix(b0, b1, amount),
+        );
+
+        (
+            [x_new, y_new, speed_var / 1000.0, 1.0 + direction / 1000.0],
+            [r, g, b, 1.0],
+        )
+    }
+
+    // The packed position is also gl_Position, so the point goes through
+    // clipping and the perspective divide before being rasterized
+    fn draw_point(
+        &self,
+        texture: &mut [Texel],
+        position: [f32; 4],
+        color: Texel,
+        size: f32,
+        channel: Option<usize>,
+    ) {
+        let [x, y, z, w] = position;
+        if w <= 0.0 || x.abs() > w || y.abs() > w || z.abs() > w {
+            return;
+        }
+
+        let center_x = (x / w + 1.0) / 2.0 * self.width as f32;
+        let center_y = (y / w + 1.0) / 2.0 * self.height as f32;
+        let half_size = size.max(1.0) / 2.0;
+
+        // Pixels whose centers fall inside the point's square
+        let left = ((center_x - half_size - 0.5).ceil().max(0.0)) as u32;
+        let right = ((center_x + half_size - 0.5).ceil().max(0.0) as u32).min(self.width);
+        let bottom = ((center_y - half_size - 0.5).ceil().max(0.0)) as u32;
+        let top = ((center_y + half_size - 0.5).ceil().max(0.0) as u32).min(self.height);
+
+        for pixel_y in bottom..top {
+            for pixel_x in left..right {
+                let texel = &mut texture[(pixel_y * self.width + pixel_x) as usize];
+                match channel {
+                    // The trail strength, which every color strategy puts in blue
+                    Some(channel) => texel[channel] = color[2],
+                    None => *texel = color,
+                }
+            }
+        }
+    }
+
+    // shader_2: blur and fade the previous frame and add the new trails
+    fn diffuse(&mut self, species: &[Species]) {
+        let (fade_speed, blurring) = simulation::channel_diffusion(species);
+        let mut texture1 = Vec::with_capacity(self.texture1.len());
+
+        for pixel_y in 0..self.height {
+            for pixel_x in 0..self.width {
+                let texcoord = [
+                    (pixel_x as f32 + 0.5) / self.width as f32,
+                    (pixel_y as f32 + 0.5) / self.height as f32,
+                ];
+                let index = (pixel_y * self.width + pixel_x) as usize;
+
+                let mut blurred = self.texture1[index];
+                let mut pixel_count = 1.0;
+                let mut d: f32 = 0.0;
+                while d < 6.3 {
+                    let mut i = 1.0 / BLUR_QUALITY;
+                    while i <= 1.0 {
+                        let sample = self.sample(
+                            &self.texture1,
+                            [
+                                texcoord[0] + d.cos() * BLUR_RADIUS * i,
+                                texcoord[1] + d.sin() * BLUR_RADIUS * i,
+                            ],
+                        );
+                        for (blurred, sample) in blurred.iter_mut().zip(sample) {
+                            *blurred += sample;
+                        }
+                        pixel_count += 1.0;
+                        i += 1.0 / BLUR_QUALITY;
+                    }
+                    d += 6.3 / BLUR_DIRECTIONS;
+                }
+
+                let shader1_out = self.texture0[index];
+                let prev_frame = self.texture1[index];
+
+                texture1.push(std::array::from_fn(|c| {
+                    let blurred = prev_frame[c] * (1.0 - blurring[c])
+                        + blurred[c] / pixel_count * blurring[c];
+                    shader1_out[c] + blurred * (1.0 - fade_speed[c]) - 0.0001
+                }));
+            }
+        }
+
+        self.texture1 = texture1;
+    }
+
+    // Bilinear filtering with repeat wrapping, like the samplers in ShaderPipeline
+    fn sample(&self, texture: &[Texel], texcoord: [f32; 2]) -> Texel {
+        let x = texcoord[0] * self.width as f32 - 0.5;
+        let y = texcoord[1] * self.height as f32 - 0.5;
+        let (x0, y0) = (x.floor(), y.floor());
+        let (fx, fy) = (x - x0, y - y0);
+
+        let texel = |x: f32, y: f32| {
+            let x = (x as i64).rem_euclid(self.width as i64) as u32;
+            let y = (y as i64).rem_euclid(self.height as i64) as u32;
+            texture[(y * self.width + x) as usize]
+        };
+        let (a, b) = (texel(x0, y0), texel(x0 + 1.0, y0));
+        let (c, d) = (texel(x0, y0 + 1.0), texel(x0 + 1.0, y0 + 1.0));
+
+        std::array::from_fn(|i| mix(mix(a[i], b[i], fx), mix(c[i], d[i], fx), fy))
+    }
+}
+
+impl SimulationBackend for CpuPipeline {
+    fn step(&mut self, species: &[Species], u_time: f32) {
+        self.step_agents(species, u_time);
+        self.diffuse(species);
+    }
+
+    fn clear(&mut self) {
+        self.texture0 = vec![[0.0; 4]; (self.width * self.height) as usize];
+        self.texture1 = vec![[0.0; 4]; (self.width * self.height) as usize];
+    }
+
+    fn reset_points(&mut self, species: usize, initial_parameters: InitialParameters) {
+        if let Some(particles) = self.particles.get_mut(species) {
+            *particles = shader_pipeline::initial_positions(initial_parameters);
+        }
+    }
+
+    fn read_state(&self) -> PipelineState {
+        PipelineState {
+            width: self.width,
+            height: self.height,
+            particles: self.particles.clone(),
+            texture0: self.texture0.iter().flatten().copied().collect(),
+            texture1: self.texture1.iter().flatten().copied().collect(),
+        }
+    }
+
+    fn write_state(&mut self, state: PipelineState) {
+        self.width = state.width;
+        self.height = state.height;
+        self.particles = state.particles;
+        self.texture0 = to_texels(&state.texture0);
+        self.texture1 = to_texels(&state.texture1);
+    }
+
+    // The displayed frame is the output of the diffuse step
+    fn read_trails(&self) -> Vec<f32> {
+        self.texture1.iter().flatten().copied().collect()
+    }
+
+    fn size(&self) -> (u32, u32) {
+        (self.width, self.height)
+    }
+}
+
+fn to_texels(data: &[f32]) -> Vec<Texel> {
+    data.chunks_exact(4)
+        .map(|chunk| [chunk[0], chunk[1], chunk[2], chunk[3]])
+        .collect()
+}
+
+fn mix(a: f32, b: f32, t: f32) -> f32 {
+    a * (1.0 - t) + b * t
+}
+
+// GLSL's fract is always positive, unlike f32::fract
+fn fract(value: f32) -> f32 {
+    value - value.floor()
+}
+
+fn glsl_rand(co: [f32; 2]) -> f32 {
+    fract((co[0] * 12.9898 + co[1] * 78.233).sin() * 43_758.547)
+}
+
+fn hsv2rgb(c: [f32; 3]) -> [f32; 3] {
+    [1.0, 2.0 / 3.0, 1.0 / 3.0].map(|k| {
+        let p = (fract(c[0] + k) * 6.0 - 3.0).abs();
+        c[2] * mix(1.0, (p - 1.0).clamp(0.0, 1.0), c[1])
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::preset::PresetName;
+    use crate::rng;
+    use crate::wgpu_pipeline::WgpuPipeline;
+    use std::f32::consts::{FRAC_PI_2, PI};
+
+    fn pipeline(width: u32, height: u32) -> CpuPipeline {
+        CpuPipeline {
+            width,
+            height,
+            particles: vec![Vec::new()],
+            texture0: vec![[0.0; 4]; (width * height) as usize],
+            texture1: vec![[0.0; 4]; (width * height) as usize],
+        }
+    }
+
+    // Nothing random, so agents only turn because of what they sense
+    fn species(wall_strategy: WallStrategy) -> Species {
+        let mut preset = Preset::new(PresetName::GreenSlime);
+        preset.speed_multiplier = 1.0;
+        preset.random_steer_factor = 0.0;
+        preset.constant_steer_factor = 0.5;
+        preset.search_radius = 0.1;
+        preset.search_angle = 0.2;
+        preset.turn_strategy = TurnStrategy::Steer;
+        preset.wall_strategy = wall_strategy;
+        preset.trail_strength = 0.25;
+        Species::single(preset)
+    }
+
+    // Packed the same way as the shaders pack it
+    fn position(x: f32, y: f32, speed: f32, direction: f32) -> [f32; 4] {
+        [x, y, speed / 1000.0, 1.0 + direction / 1000.0]
+    }
+
+    fn direction(position: [f32; 4]) -> f32 {
+        (position[3] - 1.0) * 1000.0
+    }
+
+    fn assert_close(actual: f32, expected: f32) {
+        assert!(
+            (actual - expected).abs() < 1e-3,
+            "expected {expected}, got {actual}"
+        );
+    }
+
+    #[test]
+    fn wrap_moves_agents_to_the_other_edge() {
+        let pipeline = pipeline(16, 16);
+        let species = species(WallStrategy::Wrap);
+
+        let (moved, _) = pipeline.step_agent(position(0.995, 0.0, 0.0, 0.0), &species, 0.0);
+        assert_close(moved[0], -0.99);
+        let (moved, _) = pipeline.step_agent(position(0.0, -0.995, 0.0, 0.0), &species, 0.0);
+        assert_close(moved[1], 0.99);
+    }
+
+    #[test]
+    fn bounce_reflects_the_heading() {
+        let pipeline = pipeline(16, 16);
+        let species = species(WallStrategy::Bounce);
+
+        // Heading right into the right wall
+        let (moved, _) = pipeline.step_agent(position(0.89, 0.0, 0.05, 0.0), &species, 0.0);
+        assert_close(direction(moved).cos(), -1.0);
+        assert_close(moved[0], 0.84);
+
+        // Heading up into the top wall
+        let (moved, _) = pipeline.step_agent(position(0.0, 0.89, 0.05, FRAC_PI_2), &species, 0.0);
+        assert_close(direction(moved).sin(), -1.0);
+        assert_close(moved[1], 0.84);
+    }
+
+    #[test]
+    fn agents_keep_going_where_nothing_is_sensed() {
+        let pipeline = pipeline(16, 16);
+        let species = species(WallStrategy::None);
+
+        let (moved, _) = pipeline.step_agent(position(0.0, 0.0, 0.01, 0.0), &species, 0.0);
+        assert_close(direction(moved), 0.0);
+        assert_close(moved[0], 0.01);
+        assert_close(moved[1], 0.0);
+    }
+
+    #[test]
+    fn sensing_turns_towards_the_stronger_trail() {
+        // Trails on the top half of the map, the agent in the middle heading right
+        let mut top = pipeline(64, 64);
+        for texel in &mut top.texture1[32 * 64..] {
+            texel[2] = 1.0;
+        }
+        let mut bottom = pipeline(64, 64);
+        for texel in &mut bottom.texture1[..32 * 64] {
+            texel[2] = 1.0;
+        }
+        for turn_strategy in [
+            TurnStrategy::Steer,
+            TurnStrategy::Strongest,
+            TurnStrategy::Weighted,
+        ] {
+            let mut species = species(WallStrategy::None);
+            species.preset.turn_strategy = turn_strategy;
+
+            let (moved, _) = top.step_agent(position(0.0, 0.0, 0.0, 0.0), &species, 0.0);
+            assert!(direction(moved) > 0.0, "{turn_strategy:?} didn't turn left");
+            let (moved, _) = bottom.step_agent(position(0.0, 0.0, 0.0, 0.0), &species, 0.0);
+            assert!(
+                direction(moved) < 0.0,
+                "{turn_strategy:?} didn't turn right"
+            );
+        }
+
+        // Steer turns by the constant steer factor
+        let species = species(WallStrategy::None);
+        let (moved, _) = top.step_agent(position(0.0, 0.0, 0.0, 0.0), &species, 0.0);
+        assert_close(direction(moved), 0.5);
+    }
+
+    #[test]
+    fn color_strategies() {
+        let pipeline = pipeline(16, 16);
+        let color = |color_strategy, position| {
+            let mut species = species(WallStrategy::None);
+            species.preset.color_strategy = color_strategy;
+            let (_, color) = pipeline.step_agent(position, &species, 0.0);
+            color
+        };
+        let still = |x, y, direction| position(x, y, 0.0, direction);
+        let assert_color = |actual: Texel, expected: [f32; 3]| {
+            for (actual, expected) in actual.iter().zip(expected) {
+                assert_close(*actual, expected);
+            }
+            assert_eq!(actual[3], 1.0);
+        };
+
+        assert_color(
+            color(ColorStrategy::Direction, still(0.0, 0.0, 0.0)),
+            [0.0, 1.0, 0.25],
+        );
+        assert_color(
+            color(ColorStrategy::Direction, still(0.0, 0.0, FRAC_PI_2)),
+            [1.0, 0.0, 0.25],
+        );
+        // Moves a little, but the color only depends on the speed
+        assert_color(
+            color(ColorStrategy::Speed, position(0.0, 0.0, 0.004, 0.0)),
+            [0.2, 0.2, 0.25],
+        );
+        assert_color(
+            color(ColorStrategy::Position, still(0.5, -0.25, 0.0)),
+            [0.625, 0.75, 0.25],
+        );
+        assert_color(
+            color(ColorStrategy::Grey, still(0.3, 0.3, 0.0)),
+            [0.25, 0.25, 0.25],
+        );
+        // The center has no hue angle and full saturation, which is cyan
+        assert_color(
+            color(ColorStrategy::Hue, still(0.0, 0.0, 0.0)),
+            [0.0, 1.0, 0.25],
+        );
+        assert_color(
+            color(ColorStrategy::Distance, still(0.0, 0.0, 0.0)),
+            [0.2, 0.5, 0.25],
+        );
+        assert_color(
+            color(ColorStrategy::Distance, still(1.3, 0.0, 0.0)),
+            [1.0, 1.0, 0.25],
+        );
+        assert_color(
+            color(ColorStrategy::Time, still(0.0, 0.0, 0.0)),
+            [0.5, 0.5, 0.25],
+        );
+        assert_color(
+            color(ColorStrategy::PaletteTrail, still(0.0, 0.0, 0.0)),
+            [0.0, 0.0, 0.25],
+        );
+        assert_color(
+            color(ColorStrategy::PaletteDirection, still(0.0, 0.0, PI)),
+            [0.125, 0.0, 0.25],
+        );
+        assert_color(
+            color(ColorStrategy::PaletteSpeed, position(0.0, 0.0, 0.01, 0.0)),
+            [0.125, 0.0, 0.25],
+        );
+    }
+
+    #[test]
+    fn color_blends_mix_both_strategies() {
+        let pipeline = pipeline(16, 16);
+        let mut species = species(WallStrategy::None);
+        species.preset.color_strategy = ColorStrategy::Direction;
+        species.preset.color_blend = crate::preset::ColorBlend {
+            target: ColorStrategy::Grey,
+            amount: 0.5,
+        };
+
+        let (_, color) = pipeline.step_agent(position(0.0, 0.0, 0.0, 0.0), &species, 0.0);
+        assert_close(color[0], 0.125);
+        assert_close(color[1], 0.625);
+        assert_close(color[2], 0.25);
+    }
+
+    #[test]
+    fn diffuse_fades_without_blurring() {
+        let mut pipeline = pipeline(16, 16);
+        pipeline.texture1[8 * 16 + 8] = [1.0; 4];
+        let mut species = species(WallStrategy::None);
+        species.preset.fade_speed = 0.1;
+        species.preset.blurring = 0.0;
+
+        pipeline.diffuse(&[species]);
+
+        assert_close(pipeline.texture1[8 * 16 + 8][2], 0.9 - 0.0001);
+        assert_close(pipeline.texture1[8 * 16 + 9][2], -0.0001);
+        assert_close(pipeline.texture1[0][2], -0.0001);
+    }
+
+    #[test]
+    fn diffuse_spreads_a_lit_texel_to_its_neighbours() {
+        let mut pipeline = pipeline(16, 16);
+        pipeline.texture1[8 * 16 + 8] = [1.0; 4];
+        let mut species = species(WallStrategy::None);
+        species.preset.fade_speed = 0.1;
+        species.preset.blurring = 1.0;
+
+        pipeline.diffuse(&[species]);
+
+        let lit = pipeline.texture1[8 * 16 + 8][2];
+        assert!(lit < 0.9 - 0.0001 && lit > 0.0);
+        let neighbours = [7 * 16 + 8, 9 * 16 + 8, 8 * 16 + 7, 8 * 16 + 9];
+        for neighbour in neighbours {
+            assert!(pipeline.texture1[neighbour][2] > -0.0001);
+        }
+        // Blurring only moves the trail around, the fade is all that's lost
+        let total: f32 = pipeline
+            .texture1
+            .iter()
+            .map(|texel| texel[2] + 0.0001)
+            .sum();
+        assert_close(total, 0.9);
+    }
+
+    // A preset whose steps don't depend on the noise, which the GPU computes
+    // too differently to compare
+    fn comparable_preset() -> Preset {
+        let mut preset = Preset::new(PresetName::GreenSlime);
+        preset.random_steer_factor = 0.0;
+        preset.speed_multiplier = 1.5;
+        preset
+    }
+
+    // Float precision differs between the CPU and GPU, so the odd agent right
+    // on a wall or a pixel edge can end up elsewhere. Anything more than that
+    // means the two have drifted apart.
+    fn assert_mostly_equal(name: &str, cpu: &[f32], gpu: &[f32], stride: usize) {
+        assert_eq!(cpu.len(), gpu.len(), "{name} differ in size");
+        let differing = cpu
+            .chunks(stride)
+            .zip(gpu.chunks(stride))
+            .filter(|(cpu, gpu)| cpu.iter().zip(*gpu).any(|(a, b)| (a - b).abs() > 1e-5))
+            .count();
+        let total = cpu.len() / stride;
+        assert!(
+            differing * 100 <= total,
+            "{differing} of {total} {name} differ"
+        );
+    }
+
+    fn assert_states_match(cpu: &PipelineState, gpu: &PipelineState) {
+        let positions = |state: &PipelineState| -> Vec<f32> {
+            state
+                .particles
+                .iter()
+                .flatten()
+                .flat_map(|position| position.a_position)
+                .collect()
+        };
+        assert_mostly_equal("particles", &positions(cpu), &positions(gpu), 4);
+        assert_mostly_equal("agent texels", &cpu.texture0, &gpu.texture0, 4);
+        assert_mostly_equal("trail texels", &cpu.texture1, &gpu.texture1, 4);
+    }
+
+    // Runs one step from the same seeded state on the CPU and another backend
+    fn assert_step_matches(other: &mut dyn SimulationBackend) {
+        let (width, height) = other.size();
+        rng::seed(Some(1234));
+        let preset = comparable_preset();
+        let mut cpu = CpuPipeline::new(width, height, preset);
+        other.write_state(cpu.read_state());
+
+        let species = [Species::single(preset)];
+        cpu.step(&species, 1.0);
+        other.step(&species, 1.0);
+
+        assert_states_match(&cpu.read_state(), &other.read_state());
+    }
+
+    // OSMesa renders without a display or GPU. The comparison is skipped
+    // where it isn't installed.
+    #[cfg(all(unix, not(any(target_os = "macos", target_os = "ios"))))]
+    fn gl_renderer(width: u32, height: u32) -> Option<glium::HeadlessRenderer> {
+        use glium::glutin;
+        use glutin::platform::unix::HeadlessContextExt;
+
+        let context = glutin::ContextBuilder::new()
+            .build_osmesa(glutin::dpi::PhysicalSize::new(width, height))
+            .ok()?;
+        glium::HeadlessRenderer::new(context).ok()
+    }
+
+    #[cfg(all(unix, not(any(target_os = "macos", target_os = "ios"))))]
+    #[test]
+    fn a_step_matches_the_glium_pipeline() {
+        use crate::shader_pipeline::ShaderPipeline;
+
+        let Some(renderer) = gl_renderer(64, 64) else {
+            eprintln!("No OSMesa, skipping the comparison with the glium pipeline");
+            return;
+        };
+        let preset = comparable_preset();
+        assert_step_matches(&mut ShaderPipeline::new(&renderer, 64, 64, preset));
+    }
+
+    #[test]
+    fn a_step_matches_the_wgpu_pipeline() {
+        if !WgpuPipeline::is_available() {
+            eprintln!("No wgpu adapter, skipping the comparison with the wgpu pipeline");
+            return;
+        }
+        let preset = comparable_preset();
+        assert_step_matches(&mut WgpuPipeline::new(64, 64, preset));
+    }
+}
@@ -0,0 +1,212 @@
+use crate::analysis::Analyzer;
+use crate::audio_file;
+use crate::beat;
+use crate::clock::BeatClock;
+use crate::config::AppConfig;
+use crate::modulation::{Modulation, ModulationInputs};
+use crate::post_process::PostProcessChain;
+use crate::presenter::Presenter;
+use crate::preset_library::PresetLibrary;
+use crate::rng;
+use crate::screenshot::{self, RGBAImageData};
+use crate::session;
+use crate::shader_files::ShaderSources;
+use crate::simulation::{self, BackendKind, SimulationResolution};
+use crate::slime_mould::{self, SlimeMould};
+use crate::species::Colony;
+use glium::{glutin, Surface};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+// On unix we use OSMesa so no display server is needed at all, which also
+// lets Mesa's software rasterizer (llvmpipe) do the rendering on build servers
+#[cfg(all(unix, not(any(target_os = "macos", target_os = "ios"))))]
+fn create_renderer(width: u32, height: u32) -> glium::HeadlessRenderer {
+    use glutin::platform::unix::HeadlessContextExt;
+
+    let context = glutin::ContextBuilder::new()
+        .build_osmesa(glutin::dpi::PhysicalSize::new(width, height))
+        .unwrap();
+    glium::HeadlessRenderer::new(context).unwrap()
+}
+
+#[cfg(not(all(unix, not(any(target_os = "macos", target_os = "ios")))))]
+fn create_renderer(width: u32, height: u32) -> glium::HeadlessRenderer {
+    let event_loop = glutin::event_loop::EventLoop::new();
+    let context = glutin::ContextBuilder::new()
+        .build_headless(&event_loop, glutin::dpi::PhysicalSize::new(width, height))
+        .unwrap();
+    glium::HeadlessRenderer::new(context).unwrap()
+}
+
+// Renders frames at a fixed timestep and writes each of them as a numbered
+// PNG, e.g. for `ffmpeg -i frame-%06d.png`. When an audio file is given the
+// frames follow its timebase and its detected beats drive the beat preset
+// just like live input does.
+pub fn render(app_config: &AppConfig) {
+    let width = app_config.render_width.unwrap_or(1920);
+    let height = app_config.render_height.unwrap_or(1080);
+    let frame_rate = app_config
+        .render_frame_rate
+        .map(|frame_rate| frame_rate as f32)
+        .unwrap_or(slime_mould::LIVE_FRAME_RATE);
+    let output_dir = PathBuf::from(app_config.render_output.as_deref().unwrap_or("render"));
+    fs::create_dir_all(&output_dir).unwrap();
+
+    let mut audio_track = match &app_config.render_audio {
+        Some(audio_path) => {
+            let audio = audio_file::decode_mono(audio_path).unwrap();
+            let beats = beat::detect_beats(
+                &audio.samples,
+                audio.sample_rate,
+                app_config.beat_sensitivity.unwrap_or(6_000),
+            );
+            fs::write(
+                output_dir.join("beats.json"),
+                serde_json::to_string_pretty(&beats).unwrap(),
+            )
+            .unwrap();
+            let analyzer = Analyzer::new(audio.sample_rate);
+            Some((beats, audio, analyzer))
+        }
+        None => None,
+    };
+
+    let frames = match (app_config.render_frames, &audio_track) {
+        (Some(frames), _) => frames,
+        (None, Some((_, audio, _))) => (audio.duration() * frame_rate as f64).ceil() as u32,
+        (None, None) => panic!("Either a number of frames or an audio file is needed"),
+    };
+
+    // Frames are presented through OpenGL like in the window whichever
+    // backend runs the simulation
+    let renderer = create_renderer(width, height);
+    let (simulation_width, simulation_height) =
+        SimulationResolution::from_name(app_config.simulation_resolution.as_deref())
+            .size(width, height);
+
+    let preset_library = PresetLibrary::load(app_config.preset_directory.as_ref().map(Path::new));
+    let colony = app_config
+        .species_file
+        .as_ref()
+        .map(|path| Colony::load_from_file(path, &preset_library).unwrap());
+    let initial_preset = match (&app_config.initial_preset, &colony) {
+        (Some(preset), _) => preset_library.get_by_name_or_path(preset).unwrap(),
+        (None, Some(colony)) => colony.first_preset(),
+        (None, None) => rng::random(),
+    };
+    let backend = simulation::create_backend(
+        BackendKind::from_name(app_config.simulation_backend.as_deref()),
+        Some(&renderer),
+        simulation_width,
+        simulation_height,
+        initial_preset,
+    );
+    let mut slime_mould = SlimeMould::new(backend, initial_preset, colony);
+    // Renders use the edited shaders too, but there's nothing to watch
+    if let Some(shader_directory) = &app_config.shader_directory {
+        let sources = ShaderSources::load(shader_directory).unwrap();
+        slime_mould.reload_shaders(&sources).unwrap();
+    }
+    if let Some(modulation_file) = &app_config.modulation_file {
+        slime_mould.set_modulation(Modulation::load_from_file(modulation_file).unwrap());
+    }
+    let beat_preset_swap = app_config.beat_preset_swap.unwrap_or(true);
+    let mut beat_clock = BeatClock::new(app_config.beats_per_bar.unwrap_or(4));
+
+    let mut beat_preset = rng::random();
+    let mut non_beat_preset = slime_mould.get_preset();
+
+    let mut u_time: f32 = 0.0;
+    let mut beat_start_time = u_time;
+    let mut blend_value = 0.0;
+    let mut beat_transition_time = 0.2;
+
+    if let Some(session_dir) = &app_config.restore_session {
+        let (session_state, pipeline_state) = session::load_session(session_dir).unwrap();
+        u_time = session_state.u_time;
+        blend_value = session_state.blend_value;
+        beat_transition_time = session_state.beat_transition_time;
+        beat_start_time = session_state.beat_start_time;
+        beat_preset = session_state.beat_preset;
+        non_beat_preset = session_state.non_beat_preset;
+        slime_mould.set_state(session_state.slime_mould);
+        slime_mould
+            .write_pipeline_state(pipeline_state.resized(simulation_width, simulation_height));
+    }
+
+    // Preset transitions and automation keep their live timing at any frame
+    // rate. Agents still move one step per frame, so they only move at their
+    // live speed at LIVE_FRAME_RATE.
+    let time_step = slime_mould::TIME_STEP * slime_mould::LIVE_FRAME_RATE / frame_rate;
+
+    let presenter = Presenter::new(
+        &renderer,
+        PostProcessChain::from_config(&renderer, app_config),
+    );
+    let texture = glium::texture::Texture2d::empty(&renderer, width, height).unwrap();
+
+    println!(
+        "Rendering {frames} frames at {width}x{height} {frame_rate}fps to {}",
+        output_dir.display()
+    );
+    let mut next_beat = 0;
+    for frame in 0..frames {
+        // Beats that fall within this frame are applied before it's drawn,
+        // so the frame a beat lands on is the first to show it
+        let mut got_beat = false;
+        let mut modulation_inputs = ModulationInputs::default();
+        beat_clock.advance(1.0 / frame_rate);
+        if let Some((beats, audio, analyzer)) = &mut audio_track {
+            let frame_start = frame as f64 / frame_rate as f64;
+            let frame_end = (frame + 1) as f64 / frame_rate as f64;
+            while next_beat < beats.len() && beats[next_beat].time < frame_end {
+                got_beat = true;
+                next_beat += 1;
+            }
+            modulation_inputs.beat = got_beat;
+            // Analysed the same way as live input, just a frame at a time
+            analyzer.push(&audio.window(frame_start, frame_end));
+            modulation_inputs.audio = analyzer.analyze();
+            if got_beat {
+                let bpm = beats[next_beat - 1].bpm as f32;
+                beat_clock.on_beat(bpm, modulation_inputs.audio.bass);
+            }
+        }
+        modulation_inputs.bpm = beat_clock.bpm();
+        modulation_inputs.clock = beat_clock.position();
+        slime_mould
+            .modulation_mut()
+            .update(&modulation_inputs, 1.0 / frame_rate);
+
+        if got_beat && beat_preset_swap {
+            beat_start_time = u_time;
+            non_beat_preset = slime_mould.get_preset();
+            slime_mould.transition_preset(beat_preset, u_time, beat_transition_time);
+        } else if beat_start_time > 0.0 && (u_time - beat_start_time) > beat_transition_time {
+            slime_mould.transition_preset(non_beat_preset, u_time, beat_transition_time / 2.0);
+            beat_start_time = -1.0;
+        }
+
+        slime_mould.step(u_time, blend_value);
+        {
+            let mut framebuffer =
+                glium::framebuffer::SimpleFrameBuffer::new(&renderer, &texture).unwrap();
+            framebuffer.clear_color(0.0, 0.0, 0.0, 1.0);
+            presenter.draw(
+                &mut framebuffer,
+                &renderer,
+                slime_mould.backend(),
+                slime_mould.display_mapping(),
+                slime_mould.live_preset().post_process,
+            );
+        }
+
+        let image_data: RGBAImageData = texture.read();
+        let image_path = output_dir.join(format!("frame-{frame:06}.png"));
+        screenshot::save_screenshot(image_data, image_path.to_string_lossy().into_owned());
+
+        u_time += time_step;
+    }
+    println!("Finished rendering");
+}
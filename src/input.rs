@@ -1,23 +1,96 @@
 use crate::midi;
-use crate::preset::PresetName;
+use crate::midi_mapping::{MidiBinding, MidiMapping};
+use crate::preset::{self, Preset, PresetField, PresetParameter, TurnStrategy};
+use crate::preset_library::PRESET_BANK_SIZE;
+use crate::session;
 use glium::glutin::event::{ElementState, Event, VirtualKeyCode, WindowEvent};
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
 use std::sync::mpsc::TryIter;
 
+// How far the arrow keys nudge the beat clock
+const NUDGE_BEATS: f32 = 0.05;
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default, Serialize, Deserialize)]
 pub enum PresetSlot {
+    #[default]
     Primary,
     Secondary,
     Beat,
 }
 
+impl PresetSlot {
+    pub const ALL: [PresetSlot; 3] = [PresetSlot::Primary, PresetSlot::Secondary, PresetSlot::Beat];
+
+    pub fn index(self) -> usize {
+        self as usize
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            PresetSlot::Primary => "primary",
+            PresetSlot::Secondary => "secondary",
+            PresetSlot::Beat => "beat",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<PresetSlot> {
+        PresetSlot::ALL.into_iter().find(|slot| slot.name() == name)
+    }
+}
+
+// What MIDI controllers and OSC clients are sent back
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct ControllerState {
+    // The library index of the preset in each slot, if it came from the library
+    pub loaded_presets: [Option<usize>; 3],
+    pub preset_bank: usize,
+    pub presets: [Preset; 3],
+    pub blend_value: f32,
+    pub beat_transition_time: f32,
+    // Whether there was a beat a moment ago
+    pub beat: bool,
+    pub bpm: f32,
+}
+
 pub enum InputEvent {
     ToggleFullscreen,
+    // New framebuffer size of the window
+    Resize(u32, u32),
     ToggleAutomation,
     RandomizePreset(PresetSlot),
-    LoadPreset(PresetSlot, PresetName),
+    // Index of the preset within the current preset bank
+    LoadPreset(PresetSlot, usize),
+    LoadPresetFile(PresetSlot, PathBuf),
     UpdateBlendValue(f32),
     UpdateBeatTransitionTime(f32),
+    UpdateParameter(PresetSlot, PresetParameter, f32),
+    UpdateTurnStrategy(PresetSlot, TurnStrategy),
+    UpdatePresetField(PresetSlot, PresetField),
+    // Beat clock controls
+    TapTempo,
+    ReleaseTapTempo,
+    // Fraction of a beat to shift the beat clock by, positive is later
+    NudgeBeat(f32),
+    SetDownbeat,
+    NextPresetBank,
+    PreviousPresetBank,
+    // MIDI clock and transport, the tick has the time it was sent in microseconds
+    MidiClockTick(u64),
+    MidiStart,
+    MidiContinue,
+    MidiStop,
+    // Sixteenth notes from the top of the song
+    MidiSongPosition(u16),
+    // MIDI learn mode
+    ToggleMidiLearn,
+    // Number of targets to move the learn target by
+    StepLearnTarget(i32),
+    NextLearnSlot,
+    LearnMidiBinding(MidiBinding),
     StopEventLoop,
     DumpState,
+    RestoreSession(PathBuf),
     ClearTextures,
     ResetPoints,
     TakeScreenshot,
@@ -25,7 +98,8 @@ pub enum InputEvent {
 
 pub fn input_callback(
     events: &Vec<Event<'_, ()>>,
-    midi_events: TryIter<'_, midi::Mpd218Message>,
+    midi_events: TryIter<'_, midi::MidiMessage>,
+    midi_mapping: &mut MidiMapping,
     primary_window_id: glium::glutin::window::WindowId,
 ) -> Vec<InputEvent> {
     let mut input_events = Vec::new();
@@ -35,6 +109,9 @@ pub fn input_callback(
             if *window_id == primary_window_id {
                 match event {
                     WindowEvent::CloseRequested => input_events.push(InputEvent::StopEventLoop),
+                    WindowEvent::Resized(size) => {
+                        input_events.push(InputEvent::Resize(size.width, size.height))
+                    }
                     WindowEvent::KeyboardInput { input, .. } => {
                         if let ElementState::Pressed = input.state {
                             match input.virtual_keycode {
@@ -59,13 +136,63 @@ pub fn input_callback(
                                 Some(VirtualKeyCode::Back) => {
                                     input_events.push(InputEvent::TakeScreenshot)
                                 }
+                                Some(VirtualKeyCode::F9) => {
+                                    match session::latest_session_dir(".") {
+                                        Some(path) => {
+                                            input_events.push(InputEvent::RestoreSession(path))
+                                        }
+                                        None => eprintln!("No saved sessions found"),
+                                    }
+                                }
+                                Some(VirtualKeyCode::T) => input_events.push(InputEvent::TapTempo),
+                                Some(VirtualKeyCode::Y) => {
+                                    input_events.push(InputEvent::ReleaseTapTempo)
+                                }
+                                Some(VirtualKeyCode::Left) => {
+                                    input_events.push(InputEvent::NudgeBeat(-NUDGE_BEATS))
+                                }
+                                Some(VirtualKeyCode::Right) => {
+                                    input_events.push(InputEvent::NudgeBeat(NUDGE_BEATS))
+                                }
+                                Some(VirtualKeyCode::D) => {
+                                    input_events.push(InputEvent::SetDownbeat)
+                                }
+                                Some(VirtualKeyCode::M) => {
+                                    input_events.push(InputEvent::ToggleMidiLearn)
+                                }
+                                Some(VirtualKeyCode::Up) => {
+                                    input_events.push(InputEvent::StepLearnTarget(-1))
+                                }
+                                Some(VirtualKeyCode::Down) => {
+                                    input_events.push(InputEvent::StepLearnTarget(1))
+                                }
+                                Some(VirtualKeyCode::Tab) => {
+                                    input_events.push(InputEvent::NextLearnSlot)
+                                }
+                                Some(VirtualKeyCode::PageUp) => {
+                                    input_events.push(InputEvent::NextPresetBank)
+                                }
+                                Some(VirtualKeyCode::PageDown) => {
+                                    input_events.push(InputEvent::PreviousPresetBank)
+                                }
+                                // Load the most recently dumped preset into a slot
+                                Some(VirtualKeyCode::L) => {
+                                    push_latest_preset_file(&mut input_events, PresetSlot::Primary)
+                                }
+                                Some(VirtualKeyCode::K) => push_latest_preset_file(
+                                    &mut input_events,
+                                    PresetSlot::Secondary,
+                                ),
+                                Some(VirtualKeyCode::J) => {
+                                    push_latest_preset_file(&mut input_events, PresetSlot::Beat)
+                                }
                                 _ => (),
                             }
                             // If we received a number
                             if input.scancode >= 2 && input.scancode <= 11 {
                                 input_events.push(InputEvent::LoadPreset(
                                     PresetSlot::Primary,
-                                    PresetName::from_u32((input.scancode - 1) % 10),
+                                    bank_index((input.scancode - 1) % 10),
                                 ));
                             }
                         }
@@ -78,49 +205,32 @@ pub fn input_callback(
 
     // Midi receiver
     for m in midi_events {
-        println!("{m:?}");
         match m {
-            midi::Mpd218Message::PadPressed(pad, _velocity, _) => {
-                if pad <= 9 {
-                    input_events.push(InputEvent::LoadPreset(
-                        PresetSlot::Primary,
-                        PresetName::from_u32(pad as u32),
-                    ));
-                } else if (16..=25).contains(&pad) {
-                    input_events.push(InputEvent::LoadPreset(
-                        PresetSlot::Secondary,
-                        PresetName::from_u32((pad - 16) as u32),
-                    ));
-                } else if (32..=41).contains(&pad) {
-                    input_events.push(InputEvent::LoadPreset(
-                        PresetSlot::Beat,
-                        PresetName::from_u32((pad - 32) as u32),
-                    ));
-                } else {
-                    match pad {
-                        10 => input_events.push(InputEvent::ClearTextures),
-                        11 => input_events.push(InputEvent::ResetPoints),
-                        12 => input_events.push(InputEvent::RandomizePreset(PresetSlot::Primary)),
-                        13 => input_events.push(InputEvent::RandomizePreset(PresetSlot::Secondary)),
-                        14 => input_events.push(InputEvent::RandomizePreset(PresetSlot::Beat)),
-                        15 => input_events.push(InputEvent::ToggleAutomation),
-                        _ => (),
-                    }
-                }
+            midi::MidiMessage::Clock(time) => input_events.push(InputEvent::MidiClockTick(time)),
+            midi::MidiMessage::Start => input_events.push(InputEvent::MidiStart),
+            midi::MidiMessage::Continue => input_events.push(InputEvent::MidiContinue),
+            midi::MidiMessage::Stop => input_events.push(InputEvent::MidiStop),
+            midi::MidiMessage::SongPosition(sixteenths) => {
+                input_events.push(InputEvent::MidiSongPosition(sixteenths))
             }
-            midi::Mpd218Message::KnobChanged(knob, value, _) => {
-                if knob == 0 {
-                    input_events.push(InputEvent::UpdateBlendValue(value as f32 / 127.0));
-                }
-                if knob == 1 {
-                    input_events.push(InputEvent::UpdateBeatTransitionTime(
-                        value as f32 / 127.0 * 0.5,
-                    ));
-                }
+            _ => {
+                println!("{m:?}");
+                input_events.extend(midi_mapping.input_events(&m));
             }
-            _ => (),
         }
     }
 
     input_events
 }
+
+fn push_latest_preset_file(input_events: &mut Vec<InputEvent>, slot: PresetSlot) {
+    match preset::latest_preset_file(".") {
+        Some(path) => input_events.push(InputEvent::LoadPresetFile(slot, path)),
+        None => eprintln!("No saved preset files found"),
+    }
+}
+
+// Keys are numbered 1-9 then 0, so 0 is the last preset in the bank
+fn bank_index(value: u32) -> usize {
+    (value as usize + PRESET_BANK_SIZE - 1) % PRESET_BANK_SIZE
+}
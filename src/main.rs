@@ -1,8 +1,15 @@
 ///
 /// Heavily inspired by (and code "borrowed" from): https://observablehq.com/@johnowhitaker/dotswarm-exploring-slime-mould-inspired-shaders
 ///
-use crate::input::{InputEvent, PresetSlot};
+use crate::audio_input::AudioInput;
+use crate::clock::{BeatClock, MidiClock, Quantize};
+use crate::input::{ControllerState, InputEvent, PresetSlot};
+use crate::midi_mapping::{LearnTarget, MidiMapping};
+use crate::modulation::{Modulation, ModulationInputs};
 use crate::preset::Preset;
+use crate::preset_library::{PresetId, PresetLibrary};
+use crate::session::SessionState;
+use crate::species::Colony;
 use chrono::Local;
 use glium::glutin::event::{Event, StartCause};
 use glium::glutin::event_loop::{ControlFlow, EventLoop};
@@ -11,37 +18,90 @@ use glium::{glutin, Surface};
 use glium_glyph::glyph_brush::{ab_glyph::FontRef, Section, Text};
 use glium_glyph::GlyphBrushBuilder;
 use ringbuffer::{AllocRingBuffer, RingBufferExt, RingBufferWrite};
-use std::sync::mpsc::sync_channel;
+use std::path::PathBuf;
 use std::thread;
 use std::time::{Duration, Instant};
 
+pub mod analysis;
+pub mod audio_file;
+pub mod audio_input;
 pub mod beat;
+pub mod clock;
 pub mod config;
+pub mod cpu_pipeline;
+pub mod headless;
 pub mod input;
 pub mod midi;
+pub mod midi_mapping;
+pub mod modulation;
+pub mod osc;
+pub mod palette;
+pub mod post_process;
+pub mod presenter;
 pub mod preset;
+pub mod preset_library;
+pub mod rng;
 pub mod screenshot;
+pub mod session;
+pub mod shader_files;
 pub mod shader_pipeline;
+pub mod simulation;
 pub mod slime_mould;
+pub mod species;
+pub mod wgpu_pipeline;
+
+// How long the pads bound to tap tempo light up on a beat
+const BEAT_FLASH: Duration = Duration::from_millis(100);
 
 fn main() {
     let app_config = config::get_config();
-    let midi_channel = midi::MidiChannel::new(app_config.midi_device_id);
-
-    let mut beat_detector = beat::BeatDetector::new();
+    let seed = rng::seed(app_config.seed);
+    println!("Using seed {seed}");
 
-    let (beat_sender, beat_receiver) = sync_channel(64);
+    if app_config.render_frames.is_some() || app_config.render_audio.is_some() {
+        headless::render(&app_config);
+        return;
+    }
+    let midi_channel = midi::MidiChannel::new(app_config.midi_device_id);
+    let mut midi_output = app_config.midi_output_device_id.and_then(|port| {
+        midi::MidiOutput::open(port)
+            .map_err(|e| eprintln!("Failed to open MIDI output: {e}"))
+            .ok()
+    });
+    let mut osc_server = app_config.osc_port.and_then(|port| {
+        let address = app_config
+            .osc_address
+            .as_deref()
+            .unwrap_or(osc::DEFAULT_ADDRESS);
+        osc::OscServer::start(address, port)
+            .map_err(|e| eprintln!("Failed to start OSC server: {e}"))
+            .ok()
+    });
+    let mut midi_mapping = match &app_config.midi_mapping {
+        Some(path) => MidiMapping::load_from_file(path).unwrap_or_else(|e| {
+            eprintln!("Failed to load MIDI mapping {path}: {e}");
+            MidiMapping::builtin()
+        }),
+        None => MidiMapping::builtin(),
+    };
 
-    if app_config.audio_host_name.is_some() && app_config.audio_device_id.is_some() {
-        beat_detector.start_listening(
-            app_config.audio_host_name.unwrap(),
-            app_config.audio_device_id.unwrap(),
-            app_config.beat_sensitivity.unwrap(),
-            move |(_, bpm)| {
-                beat_sender.send(bpm).unwrap();
-            },
-        );
+    // An input given as a spec wins over the old host and device index pair
+    let audio_input = match (
+        &app_config.audio_input,
+        &app_config.audio_host_name,
+        app_config.audio_device_id,
+    ) {
+        (Some(spec), _, _) => Some(AudioInput::from_name(spec)),
+        (None, Some(host_name), Some(device_id)) => {
+            Some(AudioInput::DeviceIndex(host_name.clone(), device_id))
+        }
+        _ => None,
     };
+    let mut audio_analysis = audio_input.and_then(|input| {
+        analysis::AudioAnalysis::start(&input, app_config.beat_sensitivity.unwrap_or(6_000))
+            .map_err(|e| eprintln!("Failed to start audio input {input:?}: {e}"))
+            .ok()
+    });
 
     // 1. The **winit::EventsLoop** for handling events.
     let event_loop = glutin::event_loop::EventLoop::new();
@@ -78,9 +138,53 @@ fn main() {
     let mut glyph_brush = GlyphBrushBuilder::using_font(fira_code_font).build(&display);
 
     // Create our slime mould simulation
-    let mut slime_mould = slime_mould::SlimeMould::new(&display, width, height, rand::random());
-
-    let mut beat_preset = rand::random();
+    let preset_library = PresetLibrary::load(
+        app_config
+            .preset_directory
+            .as_ref()
+            .map(std::path::Path::new),
+    );
+    let mut preset_bank = 0;
+
+    let colony = app_config
+        .species_file
+        .as_ref()
+        .map(|path| Colony::load_from_file(path, &preset_library).unwrap());
+    let initial_preset = match (&app_config.initial_preset, &colony) {
+        (Some(preset), _) => preset_library.get_by_name_or_path(preset).unwrap(),
+        (None, Some(colony)) => colony.first_preset(),
+        (None, None) => rng::random(),
+    };
+    // The trail map is stretched over the window when the sizes differ
+    let simulation_resolution =
+        simulation::SimulationResolution::from_name(app_config.simulation_resolution.as_deref());
+    let (simulation_width, simulation_height) = simulation_resolution.size(width, height);
+    let resample_on_resize = app_config.resample_on_resize.unwrap_or(true);
+    let backend = simulation::create_backend(
+        simulation::BackendKind::from_name(app_config.simulation_backend.as_deref()),
+        Some(&display),
+        simulation_width,
+        simulation_height,
+        initial_preset,
+    );
+    let mut slime_mould = slime_mould::SlimeMould::new(backend, initial_preset, colony);
+    if let Some(modulation_file) = &app_config.modulation_file {
+        slime_mould.set_modulation(Modulation::load_from_file(modulation_file).unwrap());
+    }
+    let beat_preset_swap = app_config.beat_preset_swap.unwrap_or(true);
+    // Only what's shown on screen is post-processed, the trail map isn't
+    let post_process = post_process::PostProcessChain::from_config(&display, &app_config);
+    let presenter = presenter::Presenter::new(&display, post_process);
+    // The GLSL in this directory is recompiled whenever it's saved
+    let shader_watcher = app_config
+        .shader_directory
+        .as_ref()
+        .map(|directory| shader_files::ShaderWatcher::new(directory).unwrap());
+    let mut shader_error: Option<String> = None;
+    let preset_format =
+        preset::PresetFormat::from_name(app_config.preset_format.as_deref()).extension();
+
+    let mut beat_preset: Preset = rng::random();
     let mut non_beat_preset = slime_mould.get_preset();
 
     let mut u_time: f32 = 0.0;
@@ -88,17 +192,47 @@ fn main() {
     let mut blend_value = 0.0;
     let mut beat_transition_time = 0.2;
     let mut automate_presets = false;
+    let mut last_bpm = 0.0;
+    let mut beat_clock = BeatClock::new(app_config.beats_per_bar.unwrap_or(4));
+    let mut midi_clock = MidiClock::new();
+    let quantize = Quantize::from_name(app_config.quantize.as_deref());
+    // Waiting for the beat grid, and whether to reset the points once it's loaded
+    let mut queued_preset: Option<(Preset, bool)> = None;
+    let mut last_frame_time = Instant::now();
+
+    let mut learn_target = LearnTarget::default();
+    // Shown on the controller's pads
+    let mut loaded_presets = [None; 3];
+    let mut last_beat = None;
 
     let mut text_buffer = AllocRingBuffer::with_capacity(8);
+    let mut restore_session = app_config.restore_session.clone().map(PathBuf::from);
 
     start_loop(event_loop, move |events| {
         screenshot_taker.next_frame();
-
-        let mut got_beat = false;
-        for bpm in beat_receiver.try_iter() {
-            got_beat = true;
+        let now = Instant::now();
+        let frame_seconds = (now - last_frame_time).as_secs_f32();
+        last_frame_time = now;
+
+        let audio_frame = audio_analysis
+            .as_mut()
+            .map(analysis::AudioAnalysis::next_frame)
+            .unwrap_or_default();
+        let audio_features = audio_frame.features;
+        let got_beat = !audio_frame.beats.is_empty();
+        if got_beat {
+            last_beat = Some(now);
+        }
+        for &bpm in &audio_frame.beats {
+            last_bpm = bpm as f32;
             text_buffer.push((u_time, format!("Got beat! BPM: {bpm:.2}")));
-            //println!("Got beat! BPM: {bpm:.2}");
+        }
+        beat_clock.advance(frame_seconds);
+        // A MIDI clock takes over from the detected beats while it's ticking
+        if let Some(bpm) = midi_clock.bpm() {
+            beat_clock.sync(bpm, midi_clock.beats());
+        } else if got_beat {
+            beat_clock.on_beat(last_bpm, audio_features.bass);
         }
 
         let screen_dimensions = display.get_framebuffer_dimensions();
@@ -116,53 +250,277 @@ fn main() {
                 .with_bounds((screen_dimensions.0 as f32, screen_dimensions.1 as f32 / 2.0)),
         );
 
+        if let Some(sources) = shader_watcher
+            .as_ref()
+            .and_then(|watcher| watcher.changed_sources())
+        {
+            match sources
+                .map_err(|e| e.to_string())
+                .and_then(|sources| slime_mould.reload_shaders(&sources))
+            {
+                Ok(()) => {
+                    shader_error = None;
+                    text_buffer.push((u_time, "Reloaded shaders".to_string()));
+                }
+                Err(e) => {
+                    eprintln!("Failed to reload shaders: {e}");
+                    shader_error = Some(e);
+                }
+            }
+        }
+        // The compile log stays on screen until the shaders compile again
+        if let Some(shader_error) = &shader_error {
+            glyph_brush.queue(
+                Section::default()
+                    .add_text(
+                        Text::new(shader_error)
+                            .with_scale(16.0)
+                            .with_color([0.98, 0.05, 0.05, 0.9]),
+                    )
+                    .with_screen_position((0.0, screen_dimensions.1 as f32 / 2.0))
+                    .with_bounds((screen_dimensions.0 as f32, screen_dimensions.1 as f32 / 2.0)),
+            );
+        }
+
         let mut target = display.draw();
         target.clear_color(0.0, 0.0, 0.0, 1.0);
         //target.clear_color(1.0, 1.0, 1.0, 1.0);
 
-        slime_mould.draw(&mut target, &display, u_time, blend_value);
+        slime_mould.step(u_time, blend_value);
+        presenter.draw(
+            &mut target,
+            &display,
+            slime_mould.backend(),
+            slime_mould.display_mapping(),
+            slime_mould.live_preset().post_process,
+        );
         glyph_brush.draw_queued(&display, &mut target);
         target.finish().unwrap();
 
-        u_time += 0.02;
+        u_time += slime_mould::TIME_STEP;
 
         let mut action = Action::Continue;
 
-        for event in input::input_callback(events, midi_channel.try_iter(), primary_window_id) {
+        let mut input_events = input::input_callback(
+            events,
+            midi_channel.try_iter(),
+            &mut midi_mapping,
+            primary_window_id,
+        );
+        if let Some(osc_server) = &mut osc_server {
+            input_events.extend(osc_server.input_events());
+        }
+        if let Some(path) = restore_session.take() {
+            input_events.push(InputEvent::RestoreSession(path));
+        }
+
+        for event in input_events {
             match event {
                 InputEvent::ToggleAutomation => automate_presets = !automate_presets,
                 InputEvent::UpdateBlendValue(new_value) => blend_value = new_value,
                 InputEvent::UpdateBeatTransitionTime(new_value) => beat_transition_time = new_value,
+                InputEvent::UpdateParameter(slot, parameter, value) => match slot {
+                    PresetSlot::Primary => {
+                        slime_mould.modify_preset(|preset| parameter.set(preset, value))
+                    }
+                    PresetSlot::Secondary => {
+                        slime_mould.modify_secondary_preset(|preset| parameter.set(preset, value))
+                    }
+                    PresetSlot::Beat => parameter.set(&mut beat_preset, value),
+                },
+                InputEvent::UpdateTurnStrategy(slot, turn_strategy) => match slot {
+                    PresetSlot::Primary => {
+                        slime_mould.modify_preset(|preset| preset.turn_strategy = turn_strategy)
+                    }
+                    PresetSlot::Secondary => slime_mould
+                        .modify_secondary_preset(|preset| preset.turn_strategy = turn_strategy),
+                    PresetSlot::Beat => beat_preset.turn_strategy = turn_strategy,
+                },
+                InputEvent::UpdatePresetField(slot, field) => match slot {
+                    PresetSlot::Primary => slime_mould.modify_preset(|preset| field.set(preset)),
+                    PresetSlot::Secondary => {
+                        slime_mould.modify_secondary_preset(|preset| field.set(preset))
+                    }
+                    PresetSlot::Beat => field.set(&mut beat_preset),
+                },
                 InputEvent::RandomizePreset(slot) => {
-                    let new_preset = rand::random();
+                    let new_preset = rng::random();
+                    loaded_presets[slot.index()] = None;
                     match slot {
-                        PresetSlot::Primary => {
-                            slime_mould.transition_preset(new_preset, u_time, 1.0)
-                        }
+                        PresetSlot::Primary => queued_preset = Some((new_preset, false)),
                         PresetSlot::Secondary => slime_mould.set_secondary_preset(new_preset),
                         PresetSlot::Beat => {
                             beat_preset = new_preset;
                         }
                     }
                 }
-                InputEvent::LoadPreset(slot, preset_name) => {
-                    let new_preset = Preset::new(preset_name);
-                    match slot {
-                        PresetSlot::Primary => {
-                            slime_mould.transition_preset(new_preset, u_time, 1.0);
-                            slime_mould.reset_points();
+                InputEvent::LoadPreset(slot, index) => {
+                    let index = PresetLibrary::bank_index(preset_bank, index);
+                    if let Some(new_preset) = preset_library.get(&PresetId::Index(index)) {
+                        let name = preset_library.name(index).unwrap_or_default();
+                        text_buffer.push((u_time, format!("Loaded preset [{index}] {name}")));
+                        loaded_presets[slot.index()] = Some(index);
+                        match slot {
+                            PresetSlot::Primary => queued_preset = Some((new_preset, true)),
+                            PresetSlot::Secondary => slime_mould.set_secondary_preset(new_preset),
+                            PresetSlot::Beat => {
+                                beat_preset = new_preset;
+                            }
                         }
-                        PresetSlot::Secondary => slime_mould.set_secondary_preset(new_preset),
-                        PresetSlot::Beat => {
-                            beat_preset = new_preset;
+                    }
+                }
+                InputEvent::NextPresetBank => {
+                    preset_bank = (preset_bank + 1) % preset_library.number_of_banks();
+                    text_buffer.push((u_time, format!("Preset bank {preset_bank}")));
+                }
+                InputEvent::PreviousPresetBank => {
+                    preset_bank = (preset_bank + preset_library.number_of_banks() - 1)
+                        % preset_library.number_of_banks();
+                    text_buffer.push((u_time, format!("Preset bank {preset_bank}")));
+                }
+                InputEvent::LoadPresetFile(slot, path) => match Preset::load_from_file(&path) {
+                    Ok(new_preset) => {
+                        loaded_presets[slot.index()] = None;
+                        match slot {
+                            PresetSlot::Primary => queued_preset = Some((new_preset, true)),
+                            PresetSlot::Secondary => slime_mould.set_secondary_preset(new_preset),
+                            PresetSlot::Beat => {
+                                beat_preset = new_preset;
+                            }
+                        }
+                    }
+                    Err(e) => eprintln!("Failed to load preset {}: {e}", path.display()),
+                },
+                InputEvent::TapTempo => {
+                    beat_clock.tap();
+                    if beat_clock.is_tapped() {
+                        let bpm = beat_clock.bpm();
+                        text_buffer.push((u_time, format!("Tap tempo: {bpm:.2} BPM")));
+                    }
+                }
+                InputEvent::ReleaseTapTempo => {
+                    beat_clock.release_tap_tempo();
+                    text_buffer.push((u_time, "Following detected tempo".to_string()));
+                }
+                InputEvent::NudgeBeat(beats) => beat_clock.nudge(beats),
+                InputEvent::SetDownbeat => {
+                    beat_clock.set_downbeat();
+                    let beats_per_bar = beat_clock.beats_per_bar();
+                    text_buffer.push((
+                        u_time,
+                        format!("Downbeat set, {beats_per_bar} beats per bar"),
+                    ));
+                }
+                InputEvent::MidiClockTick(time) => midi_clock.tick(time),
+                InputEvent::MidiStart => {
+                    midi_clock.start();
+                    text_buffer.push((u_time, "MIDI start".to_string()));
+                }
+                InputEvent::MidiContinue => {
+                    midi_clock.resume();
+                    text_buffer.push((u_time, "MIDI continue".to_string()));
+                }
+                InputEvent::MidiStop => {
+                    midi_clock.stop();
+                    text_buffer.push((u_time, "MIDI stop".to_string()));
+                }
+                InputEvent::MidiSongPosition(sixteenths) => midi_clock.song_position(sixteenths),
+                InputEvent::ToggleMidiLearn => {
+                    if midi_mapping.is_learning() {
+                        midi_mapping.stop_learning();
+                        text_buffer.push((u_time, "MIDI learn off".to_string()));
+                    } else {
+                        midi_mapping.start_learning(learn_target.action());
+                        let action = learn_target.action();
+                        text_buffer.push((u_time, format!("MIDI learn: {action:?}")));
+                    }
+                }
+                InputEvent::StepLearnTarget(steps) => {
+                    learn_target.step(steps);
+                    if midi_mapping.is_learning() {
+                        midi_mapping.start_learning(learn_target.action());
+                    }
+                    let action = learn_target.action();
+                    text_buffer.push((u_time, format!("MIDI learn: {action:?}")));
+                }
+                InputEvent::NextLearnSlot => {
+                    learn_target.next_slot();
+                    if midi_mapping.is_learning() {
+                        midi_mapping.start_learning(learn_target.action());
+                    }
+                    let action = learn_target.action();
+                    text_buffer.push((u_time, format!("MIDI learn: {action:?}")));
+                }
+                InputEvent::LearnMidiBinding(binding) => {
+                    let control = binding.control_name();
+                    midi_mapping.bind(binding);
+                    text_buffer.push((u_time, format!("Bound {control} to {:?}", binding.action)));
+                    match midi_mapping.save() {
+                        Ok(()) => {
+                            println!("Saved MIDI mapping to {}", midi_mapping.path().display())
                         }
+                        Err(e) => eprintln!(
+                            "Failed to save MIDI mapping {}: {e}",
+                            midi_mapping.path().display()
+                        ),
                     }
                 }
                 InputEvent::ResetPoints => slime_mould.reset_points(),
                 InputEvent::ClearTextures => slime_mould.clear(),
-                // TODO: Make sure we dump all state that can effect the current visual
-                // hopefully in such a way that it can easily be reloaded
-                InputEvent::DumpState => slime_mould.save_preset(),
+                InputEvent::DumpState => {
+                    let timestamp = Local::now().format("%Y-%m-%d_%H%M%S%.f");
+                    let preset_path =
+                        format!("{}{timestamp}.{preset_format}", preset::PRESET_FILE_PREFIX);
+                    if let Err(e) = slime_mould.save_preset(&preset_path) {
+                        eprintln!("Failed to save preset {preset_path}: {e}");
+                    }
+
+                    let (width, height) = slime_mould.backend().size();
+                    let session_state = SessionState {
+                        u_time,
+                        blend_value,
+                        beat_transition_time,
+                        beat_start_time,
+                        automate_presets,
+                        automation_time: slime_mould.get_preset().u_time,
+                        beat_preset,
+                        non_beat_preset,
+                        slime_mould: slime_mould.get_state(),
+                        width,
+                        height,
+                    };
+                    let session_dir = format!("{}{timestamp}", session::SESSION_DIR_PREFIX);
+                    if let Err(e) = session::save_session(
+                        &session_dir,
+                        &session_state,
+                        &slime_mould.read_pipeline_state(),
+                    ) {
+                        eprintln!("Failed to save session {session_dir}: {e}");
+                    }
+                }
+                InputEvent::RestoreSession(path) => match session::load_session(&path) {
+                    Ok((session_state, pipeline_state)) => {
+                        u_time = session_state.u_time;
+                        blend_value = session_state.blend_value;
+                        beat_transition_time = session_state.beat_transition_time;
+                        beat_start_time = session_state.beat_start_time;
+                        automate_presets = session_state.automate_presets;
+                        beat_preset = session_state.beat_preset;
+                        non_beat_preset = session_state.non_beat_preset;
+                        slime_mould.set_state(session_state.slime_mould);
+                        let (width, height) = slime_mould.backend().size();
+                        slime_mould.write_pipeline_state(pipeline_state.resized(width, height));
+                    }
+                    Err(e) => eprintln!("Failed to restore session {}: {e}", path.display()),
+                },
+                // Minimizing gives a zero sized window, keep the trails as they are
+                InputEvent::Resize(0, _) | InputEvent::Resize(_, 0) => (),
+                InputEvent::Resize(width, height) => {
+                    let (width, height) = simulation_resolution.size(width, height);
+                    if (width, height) != slime_mould.backend().size() {
+                        slime_mould.resize(width, height, resample_on_resize);
+                    }
+                }
                 InputEvent::TakeScreenshot => screenshot_taker.take_screenshot(&display),
                 InputEvent::ToggleFullscreen => {
                     if fullscreen {
@@ -185,12 +543,30 @@ fn main() {
             }
         }
 
+        if beat_clock.on_grid(quantize) {
+            if let Some((new_preset, reset_points)) = queued_preset.take() {
+                slime_mould.transition_preset(new_preset, u_time, 1.0);
+                if reset_points {
+                    slime_mould.reset_points();
+                }
+            }
+        }
         if automate_presets {
             beat_preset.update(u_time);
             slime_mould.update(u_time);
         }
 
-        if got_beat {
+        slime_mould.modulation_mut().update(
+            &ModulationInputs {
+                beat: got_beat,
+                bpm: beat_clock.bpm(),
+                audio: audio_features,
+                clock: beat_clock.position(),
+            },
+            frame_seconds,
+        );
+
+        if got_beat && beat_preset_swap {
             beat_start_time = u_time;
             non_beat_preset = slime_mould.get_preset();
             slime_mould.transition_preset(beat_preset, u_time, beat_transition_time);
@@ -199,6 +575,32 @@ fn main() {
             beat_start_time = -1.0;
         }
 
+        // The primary preset is swapped out while a beat plays
+        let primary_preset = if beat_start_time > 0.0 {
+            non_beat_preset
+        } else {
+            slime_mould.get_preset()
+        };
+        let controller_state = ControllerState {
+            loaded_presets,
+            preset_bank,
+            presets: [
+                primary_preset,
+                slime_mould.get_secondary_preset(),
+                beat_preset,
+            ],
+            blend_value,
+            beat_transition_time,
+            beat: last_beat.is_some_and(|last_beat| now - last_beat < BEAT_FLASH),
+            bpm: beat_clock.bpm(),
+        };
+        if let Some(midi_output) = &mut midi_output {
+            midi_output.send_changes(&midi_mapping.feedback(&controller_state));
+        }
+        if let Some(osc_server) = &mut osc_server {
+            osc_server.send_feedback(&controller_state);
+        }
+
         for image_data in screenshot_taker.pickup_screenshots() {
             let image_name = format!(
                 "slime_mould-{}.png",
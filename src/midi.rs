@@ -1,8 +1,12 @@
-use midir::{Ignore, MidiInput};
+use midir::{Ignore, MidiInput, MidiInputConnection, MidiOutputConnection};
+use std::collections::HashMap;
+use std::error::Error;
 use std::sync::mpsc::sync_channel;
 
 pub struct MidiChannel<T> {
     receiver: std::sync::mpsc::Receiver<T>,
+    // Input stops when this is dropped
+    _connection: Option<MidiInputConnection<()>>,
 }
 
 pub fn list_midi_devices() {
@@ -13,23 +17,40 @@ pub fn list_midi_devices() {
     for (i, in_port) in in_ports.iter().enumerate() {
         println!(" {}: {}", i, midi_in.port_name(in_port).unwrap());
     }
+
+    let midi_out = midir::MidiOutput::new("midi writing output").unwrap();
+    println!("Available MIDI output ports:");
+    for (i, out_port) in midi_out.ports().iter().enumerate() {
+        println!(" {}: {}", i, midi_out.port_name(out_port).unwrap());
+    }
 }
 
 impl<T> MidiChannel<T>
 where
-    T: std::convert::From<(u64, [u8; 3])> + std::marker::Send + 'static,
+    T: std::convert::From<(u64, Vec<u8>)> + std::marker::Send + 'static,
 {
     pub fn new(channel: Option<usize>) -> Self {
-        let receiver = Self::setup_midi_input(channel);
-        Self { receiver }
+        let (receiver, connection) = Self::setup_midi_input(channel);
+        Self {
+            receiver,
+            _connection: connection,
+        }
     }
 
-    pub fn try_iter(&self) -> std::sync::mpsc::TryIter<T> {
+    pub fn try_iter(&self) -> std::sync::mpsc::TryIter<'_, T> {
         self.receiver.try_iter()
     }
 
-    fn setup_midi_input(channel: Option<usize>) -> std::sync::mpsc::Receiver<T> {
-        let (sender, receiver) = sync_channel(64);
+    fn setup_midi_input(
+        channel: Option<usize>,
+    ) -> (
+        std::sync::mpsc::Receiver<T>,
+        Option<MidiInputConnection<()>>,
+    ) {
+        // MIDI clock alone sends 48 messages a second at 120 BPM
+        let (sender, receiver) = sync_channel(256);
+        let mut running_status = RunningStatus::default();
+        let mut connection = None;
         let mut midi_in = MidiInput::new("midir reading input").unwrap();
         midi_in.ignore(Ignore::None);
 
@@ -40,108 +61,363 @@ where
 
                 let in_port_name = midi_in.port_name(in_port).unwrap();
 
-                let _conn_in = midi_in.connect(
+                match midi_in.connect(
                     in_port,
                     "midir-read-input",
                     move |time, message, _| {
-                        let len = std::cmp::min(message.len(), MAX_MIDI);
-                        let mut data = [0; MAX_MIDI];
-                        data[..len].copy_from_slice(&message[..len]);
-                        sender.send((time, data).into()).unwrap();
+                        // The render loop is gone once sending fails
+                        let message = running_status.complete(message);
+                        let _ = sender.send((time, message).into());
                     },
                     (),
-                );
-
-                println!("Connection open, reading input from '{}'.", in_port_name);
+                ) {
+                    Ok(conn_in) => {
+                        connection = Some(conn_in);
+                        println!("Connection open, reading input from '{}'.", in_port_name);
+                    }
+                    Err(e) => eprintln!("Failed to open MIDI input {in_port_name}: {e}"),
+                }
             } else {
                 eprintln!("Input channel not available: {channel}");
             }
         }
-        receiver
+        (receiver, connection)
     }
 }
-const MAX_MIDI: usize = 3;
 
-#[derive(Copy, Clone)]
-struct MidiCopy {
-    len: usize,
-    data: [u8; MAX_MIDI],
-    time: u64,
+// Controllers can leave out the status byte when it's the same as the last
+// message's, this puts it back
+#[derive(Default)]
+struct RunningStatus {
+    status: Option<u8>,
 }
 
-impl std::fmt::Debug for MidiCopy {
-    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        write!(
-            f,
-            "Midi {{ time: {}, len: {}, data: {:?} }}",
-            self.time,
-            self.len,
-            &self.data[..self.len]
-        )
+impl RunningStatus {
+    fn complete(&mut self, message: &[u8]) -> Vec<u8> {
+        match message.first() {
+            // Real-time messages can come in between and don't change it
+            Some(&status) if status >= 0xf8 => message.to_vec(),
+            Some(&status) if status >= 0xf0 => {
+                self.status = None;
+                message.to_vec()
+            }
+            Some(&status) if status >= 0x80 => {
+                self.status = Some(status);
+                message.to_vec()
+            }
+            _ => self.status.iter().chain(message).copied().collect(),
+        }
     }
 }
 
-// Pad 0-47, Knob 0-5
-// Velocity 0-127
-// Knob value 0-127
-// Time u64
-#[derive(Debug, Copy, Clone)]
-pub enum Mpd218Message {
-    PadPressed(u8, u8, u64),
-    PadHeld(u8, u64),
-    PadReleased(u8, u8, u64),
-    KnobChanged(u8, u8, u64),
-    Unknown([u8; MAX_MIDI], u64),
+// A decoded channel or system message. Channels are 0-15 here, though
+// they're numbered 1-16 on controllers and in mapping files.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MidiMessage {
+    NoteOn {
+        channel: u8,
+        note: u8,
+        velocity: u8,
+    },
+    NoteOff {
+        channel: u8,
+        note: u8,
+        velocity: u8,
+    },
+    // Aftertouch on a single note, then on the whole channel
+    PolyPressure {
+        channel: u8,
+        note: u8,
+        pressure: u8,
+    },
+    ChannelPressure {
+        channel: u8,
+        pressure: u8,
+    },
+    ControlChange {
+        channel: u8,
+        controller: u8,
+        value: u8,
+    },
+    ProgramChange {
+        channel: u8,
+        program: u8,
+    },
+    // 0-16383, centered at 8192
+    PitchBend {
+        channel: u8,
+        value: u16,
+    },
+    // Sent 24 times a quarter note, with when it arrived in microseconds
+    Clock(u64),
+    Start,
+    Continue,
+    Stop,
+    // Sixteenth notes from the start of the song
+    SongPosition(u16),
+    Unknown(Vec<u8>),
+}
+
+impl MidiMessage {
+    pub fn channel(&self) -> Option<u8> {
+        match *self {
+            MidiMessage::NoteOn { channel, .. }
+            | MidiMessage::NoteOff { channel, .. }
+            | MidiMessage::PolyPressure { channel, .. }
+            | MidiMessage::ChannelPressure { channel, .. }
+            | MidiMessage::ControlChange { channel, .. }
+            | MidiMessage::ProgramChange { channel, .. }
+            | MidiMessage::PitchBend { channel, .. } => Some(channel),
+            _ => None,
+        }
+    }
 }
 
-impl From<(u64, [u8; 3])> for Mpd218Message {
-    fn from(tuple: (u64, [u8; 3])) -> Self {
+impl MidiMessage {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        match self {
+            MidiMessage::NoteOn {
+                channel,
+                note,
+                velocity,
+            } => vec![0x90 | channel, *note, *velocity],
+            MidiMessage::NoteOff {
+                channel,
+                note,
+                velocity,
+            } => vec![0x80 | channel, *note, *velocity],
+            MidiMessage::PolyPressure {
+                channel,
+                note,
+                pressure,
+            } => vec![0xa0 | channel, *note, *pressure],
+            MidiMessage::ControlChange {
+                channel,
+                controller,
+                value,
+            } => vec![0xb0 | channel, *controller, *value],
+            MidiMessage::ProgramChange { channel, program } => vec![0xc0 | channel, *program],
+            MidiMessage::ChannelPressure { channel, pressure } => vec![0xd0 | channel, *pressure],
+            MidiMessage::PitchBend { channel, value } => {
+                vec![0xe0 | channel, (value & 0x7f) as u8, (value >> 7) as u8]
+            }
+            MidiMessage::Clock(_) => vec![0xf8],
+            MidiMessage::Start => vec![0xfa],
+            MidiMessage::Continue => vec![0xfb],
+            MidiMessage::Stop => vec![0xfc],
+            MidiMessage::SongPosition(sixteenths) => {
+                vec![0xf2, (sixteenths & 0x7f) as u8, (sixteenths >> 7) as u8]
+            }
+            MidiMessage::Unknown(data) => data.clone(),
+        }
+    }
+}
+
+impl From<(u64, Vec<u8>)> for MidiMessage {
+    fn from(tuple: (u64, Vec<u8>)) -> Self {
         let (time, data) = tuple;
-        // data[0] == 153 // pad pressed
-        // data[0] == 217 // pad held
-        // data[0] == 137 // pad released
-        // data[0] == 176 // knob turned
-        // data[1] for pads is 36-84
-        // data[1] for knobs is 0-127
-        // pad number is not passed when held so velocity is in data[1]
-        // held data is only supplied for first pad held
-        // and pad number is in last_pad
-        // Knobs are 3,9, 12-27
-        //
-        match data[0] {
-            153 => {
-                let pad = data[1] - 36;
-                let velocity = data[2];
-                //println!("Pad {} pressed with velocity {}", pad, velocity);
-                Mpd218Message::PadPressed(pad, velocity, time)
+        let status = data.first().copied().unwrap_or(0);
+        let data_1 = data.get(1).copied().unwrap_or(0);
+        let data_2 = data.get(2).copied().unwrap_or(0);
+        let channel = status & 0x0f;
+        match status {
+            0xf8 => return MidiMessage::Clock(time),
+            0xfa => return MidiMessage::Start,
+            0xfb => return MidiMessage::Continue,
+            0xfc => return MidiMessage::Stop,
+            0xf2 => return MidiMessage::SongPosition((data_2 as u16) << 7 | data_1 as u16),
+            _ => (),
+        }
+        match status & 0xf0 {
+            // A note on with no velocity is how a lot of controllers send note off
+            0x90 if data_2 > 0 => MidiMessage::NoteOn {
+                channel,
+                note: data_1,
+                velocity: data_2,
+            },
+            0x80 | 0x90 => MidiMessage::NoteOff {
+                channel,
+                note: data_1,
+                velocity: data_2,
+            },
+            0xa0 => MidiMessage::PolyPressure {
+                channel,
+                note: data_1,
+                pressure: data_2,
+            },
+            0xb0 => MidiMessage::ControlChange {
+                channel,
+                controller: data_1,
+                value: data_2,
+            },
+            0xc0 => MidiMessage::ProgramChange {
+                channel,
+                program: data_1,
+            },
+            0xd0 => MidiMessage::ChannelPressure {
+                channel,
+                pressure: data_1,
+            },
+            0xe0 => MidiMessage::PitchBend {
+                channel,
+                value: (data_2 as u16) << 7 | data_1 as u16,
+            },
+            _ => MidiMessage::Unknown(data),
+        }
+    }
+}
+
+// Sends feedback to a controller, like pad lights and encoder positions
+pub struct MidiOutput {
+    connection: MidiOutputConnection,
+    // The last message sent to each note and controller
+    sent: HashMap<(u8, u8), Vec<u8>>,
+}
+
+impl MidiOutput {
+    pub fn open(port: usize) -> Result<Self, Box<dyn Error>> {
+        let midi_out = midir::MidiOutput::new("midir writing output")?;
+        let out_ports = midi_out.ports();
+        let out_port = out_ports
+            .get(port)
+            .ok_or(format!("Output port not available: {port}"))?;
+        let out_port_name = midi_out.port_name(out_port)?;
+        let connection = midi_out
+            .connect(out_port, "midir-write-output")
+            .map_err(|e| e.to_string())?;
+        println!("Connection open, writing output to '{}'.", out_port_name);
+        Ok(MidiOutput {
+            connection,
+            sent: HashMap::new(),
+        })
+    }
+
+    // Sends the messages that change what a note or controller was last sent,
+    // so the whole state can be passed every frame
+    pub fn send_changes(&mut self, messages: &[MidiMessage]) {
+        for message in messages {
+            let bytes = message.to_bytes();
+            // Note offs and note ons light the same pad
+            let status = match bytes[0] & 0xf0 {
+                0x80 => 0x90 | (bytes[0] & 0x0f),
+                _ => bytes[0],
+            };
+            let key = (status, bytes.get(1).copied().unwrap_or(0));
+            if self.sent.get(&key) == Some(&bytes) {
+                continue;
             }
-            217 => {
-                let velocity = data[1];
-                //println!("Pad {} held with velocity {}", pad, velocity);
-                Mpd218Message::PadHeld(velocity, time)
+            if let Err(e) = self.connection.send(&bytes) {
+                eprintln!("Failed to send MIDI message {message:?}: {e}");
             }
-            137 => {
-                let pad = data[1] - 36;
-                let velocity = data[2];
-                //println!("Pad {} released with velocity {}", pad, velocity);
-                Mpd218Message::PadReleased(pad, velocity, time)
+            self.sent.insert(key, bytes);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode(data: &[u8]) -> MidiMessage {
+        (1_000, data.to_vec()).into()
+    }
+
+    #[test]
+    fn running_status_repeats_the_last_status() {
+        let mut running_status = RunningStatus::default();
+        assert_eq!(running_status.complete(&[0xb1, 7, 100]), vec![0xb1, 7, 100]);
+        assert_eq!(running_status.complete(&[7, 90]), vec![0xb1, 7, 90]);
+        assert_eq!(
+            decode(&running_status.complete(&[8, 80])),
+            MidiMessage::ControlChange {
+                channel: 1,
+                controller: 8,
+                value: 80
             }
-            176 => {
-                let mut knob = data[1] - 3;
-                if knob > 0 {
-                    knob -= 5;
-                }
-                if knob > 1 {
-                    knob -= 2;
-                }
-                let value = data[2];
-                //println!("Knob {} value {}", knob, value);
-                Mpd218Message::KnobChanged(knob, value, time)
+        );
+    }
+
+    #[test]
+    fn running_status_survives_real_time_messages() {
+        let mut running_status = RunningStatus::default();
+        running_status.complete(&[0x92, 36, 127]);
+        assert_eq!(running_status.complete(&[0xf8]), vec![0xf8]);
+        assert_eq!(running_status.complete(&[37, 127]), vec![0x92, 37, 127]);
+    }
+
+    #[test]
+    fn system_common_messages_cancel_running_status() {
+        let mut running_status = RunningStatus::default();
+        running_status.complete(&[0x90, 36, 127]);
+        running_status.complete(&[0xf2, 0, 0]);
+        assert_eq!(running_status.complete(&[36, 0]), vec![36, 0]);
+        assert_eq!(decode(&[36, 0]), MidiMessage::Unknown(vec![36, 0]));
+    }
+
+    #[test]
+    fn note_on_without_velocity_is_note_off() {
+        assert_eq!(
+            decode(&[0x93, 60, 0]),
+            MidiMessage::NoteOff {
+                channel: 3,
+                note: 60,
+                velocity: 0
+            }
+        );
+        assert_eq!(
+            decode(&[0x93, 60, 1]),
+            MidiMessage::NoteOn {
+                channel: 3,
+                note: 60,
+                velocity: 1
+            }
+        );
+    }
+
+    #[test]
+    fn fourteen_bit_values() {
+        assert_eq!(
+            decode(&[0xe0, 0x00, 0x40]),
+            MidiMessage::PitchBend {
+                channel: 0,
+                value: 8192
             }
-            _ => {
-                println!("Unknown message: {:?}", data);
-                Mpd218Message::Unknown(data, time)
+        );
+        assert_eq!(
+            decode(&[0xef, 0x7f, 0x7f]),
+            MidiMessage::PitchBend {
+                channel: 15,
+                value: 16383
             }
+        );
+        assert_eq!(
+            decode(&[0xf2, 0x05, 0x02]),
+            MidiMessage::SongPosition(2 << 7 | 5)
+        );
+    }
+
+    #[test]
+    fn system_real_time_messages() {
+        assert_eq!(decode(&[0xf8]), MidiMessage::Clock(1_000));
+        assert_eq!(decode(&[0xfa]), MidiMessage::Start);
+        assert_eq!(decode(&[0xfb]), MidiMessage::Continue);
+        assert_eq!(decode(&[0xfc]), MidiMessage::Stop);
+        // Active sensing isn't used
+        assert_eq!(decode(&[0xfe]), MidiMessage::Unknown(vec![0xfe]));
+    }
+
+    #[test]
+    fn messages_encode_to_what_they_decode_from() {
+        for data in [
+            vec![0x91, 36, 100],
+            vec![0x81, 36, 64],
+            vec![0xb0, 7, 127],
+            vec![0xc4, 12],
+            vec![0xe2, 0x12, 0x34],
+            vec![0xf2, 0x10, 0x01],
+            vec![0xfa],
+        ] {
+            assert_eq!(decode(&data).to_bytes(), data);
         }
     }
 }
@@ -0,0 +1,694 @@
+use crate::input::{ControllerState, InputEvent, PresetSlot};
+use crate::midi::MidiMessage;
+use crate::preset::{self, PresetParameter, TurnStrategy};
+use crate::preset_library::PresetLibrary;
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+// Used when no mapping file is given
+const DEFAULT_MAPPING: &str = include_str!("../midi/mpd218.toml");
+
+// Where learned bindings are saved when no mapping file was given
+const LEARNED_MAPPING_FILE: &str = "midi_mapping.toml";
+
+// Knobs bound to the beat transition time go up to this many seconds
+const MAX_BEAT_TRANSITION_TIME: f32 = 0.5;
+
+// What a control does. Buttons fire when pressed, knobs and faders set a value
+// from their position.
+#[derive(Copy, Clone, PartialEq, Debug, Serialize, Deserialize)]
+#[serde(tag = "action")]
+pub enum MidiAction {
+    // Index of the preset within the current preset bank
+    LoadPreset {
+        slot: PresetSlot,
+        preset: usize,
+    },
+    RandomizePreset {
+        slot: PresetSlot,
+    },
+    ToggleAutomation,
+    ToggleFullscreen,
+    NextPresetBank,
+    PreviousPresetBank,
+    TapTempo,
+    ReleaseTapTempo,
+    SetDownbeat,
+    DumpState,
+    ClearTextures,
+    ResetPoints,
+    TakeScreenshot,
+    BlendValue,
+    BeatTransitionTime,
+    // Sweeps a preset field across its whole range
+    Parameter {
+        #[serde(default)]
+        slot: PresetSlot,
+        parameter: PresetParameter,
+    },
+    // Steer, Strongest then Weighted from one end to the other
+    TurnStrategy {
+        #[serde(default)]
+        slot: PresetSlot,
+    },
+}
+
+impl MidiAction {
+    // Everything a control can be bound to in learn mode, with the slot used
+    // by the actions that have one
+    fn learn_targets(slot: PresetSlot) -> Vec<MidiAction> {
+        let mut targets = vec![
+            MidiAction::BlendValue,
+            MidiAction::BeatTransitionTime,
+            MidiAction::TurnStrategy { slot },
+        ];
+        targets.extend(
+            PresetParameter::ALL
+                .iter()
+                .map(|&parameter| MidiAction::Parameter { slot, parameter }),
+        );
+        targets.extend([
+            MidiAction::RandomizePreset { slot },
+            MidiAction::ToggleAutomation,
+            MidiAction::ToggleFullscreen,
+            MidiAction::NextPresetBank,
+            MidiAction::PreviousPresetBank,
+            MidiAction::TapTempo,
+            MidiAction::ReleaseTapTempo,
+            MidiAction::SetDownbeat,
+            MidiAction::DumpState,
+            MidiAction::ClearTextures,
+            MidiAction::ResetPoints,
+            MidiAction::TakeScreenshot,
+        ]);
+        targets
+    }
+
+    // The event for a control at a position from 0 to 1
+    fn input_event(self, value: f32) -> Option<InputEvent> {
+        let pressed = value > 0.0;
+        let event = match self {
+            MidiAction::LoadPreset { slot, preset } if pressed => {
+                InputEvent::LoadPreset(slot, preset)
+            }
+            MidiAction::RandomizePreset { slot } if pressed => InputEvent::RandomizePreset(slot),
+            MidiAction::ToggleAutomation if pressed => InputEvent::ToggleAutomation,
+            MidiAction::ToggleFullscreen if pressed => InputEvent::ToggleFullscreen,
+            MidiAction::NextPresetBank if pressed => InputEvent::NextPresetBank,
+            MidiAction::PreviousPresetBank if pressed => InputEvent::PreviousPresetBank,
+            MidiAction::TapTempo if pressed => InputEvent::TapTempo,
+            MidiAction::ReleaseTapTempo if pressed => InputEvent::ReleaseTapTempo,
+            MidiAction::SetDownbeat if pressed => InputEvent::SetDownbeat,
+            MidiAction::DumpState if pressed => InputEvent::DumpState,
+            MidiAction::ClearTextures if pressed => InputEvent::ClearTextures,
+            MidiAction::ResetPoints if pressed => InputEvent::ResetPoints,
+            MidiAction::TakeScreenshot if pressed => InputEvent::TakeScreenshot,
+            MidiAction::BlendValue => InputEvent::UpdateBlendValue(value),
+            MidiAction::BeatTransitionTime => {
+                InputEvent::UpdateBeatTransitionTime(value * MAX_BEAT_TRANSITION_TIME)
+            }
+            MidiAction::Parameter { slot, parameter } => {
+                let (min, max) = parameter.range();
+                InputEvent::UpdateParameter(slot, parameter, min + (max - min) * value)
+            }
+            MidiAction::TurnStrategy { slot } => {
+                let turn_strategy = if value < 1.0 / 3.0 {
+                    TurnStrategy::Steer
+                } else if value < 2.0 / 3.0 {
+                    TurnStrategy::Strongest
+                } else {
+                    TurnStrategy::Weighted
+                };
+                InputEvent::UpdateTurnStrategy(slot, turn_strategy)
+            }
+            // A button being released
+            _ => return None,
+        };
+        Some(event)
+    }
+}
+
+// One control bound to one action. A binding names a note, a controller, a
+// program or the pitch bend.
+#[derive(Copy, Clone, PartialEq, Debug, Serialize, Deserialize)]
+pub struct MidiBinding {
+    // 1-16, any channel when left out
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub channel: Option<u8>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub note: Option<u8>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub controller: Option<u8>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub program: Option<u8>,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub pitch_bend: bool,
+    #[serde(flatten)]
+    pub action: MidiAction,
+}
+
+impl MidiBinding {
+    // Binds whichever control sent the message, if it's one that can be bound
+    fn learn(message: &MidiMessage, action: MidiAction) -> Option<MidiBinding> {
+        let mut binding = MidiBinding {
+            channel: message.channel().map(|channel| channel + 1),
+            note: None,
+            controller: None,
+            program: None,
+            pitch_bend: false,
+            action,
+        };
+        match *message {
+            MidiMessage::NoteOn { note, .. } => binding.note = Some(note),
+            MidiMessage::ControlChange { controller, .. } => binding.controller = Some(controller),
+            MidiMessage::ProgramChange { program, .. } => binding.program = Some(program),
+            MidiMessage::PitchBend { .. } => binding.pitch_bend = true,
+            _ => return None,
+        }
+        Some(binding)
+    }
+
+    // Whether both bindings can be triggered by the same control
+    fn same_control(&self, other: &MidiBinding) -> bool {
+        let same_channel = match (self.channel, other.channel) {
+            (Some(channel), Some(other_channel)) => channel == other_channel,
+            _ => true,
+        };
+        same_channel
+            && self.note == other.note
+            && self.controller == other.controller
+            && self.program == other.program
+            && self.pitch_bend == other.pitch_bend
+    }
+
+    // Sets the bound note or controller, on the first channel when the binding
+    // is for any channel
+    fn feedback(&self, value: u8) -> Option<MidiMessage> {
+        let channel = self.channel.unwrap_or(1).saturating_sub(1);
+        if let Some(note) = self.note {
+            Some(MidiMessage::NoteOn {
+                channel,
+                note,
+                velocity: value,
+            })
+        } else {
+            self.controller
+                .map(|controller| MidiMessage::ControlChange {
+                    channel,
+                    controller,
+                    value,
+                })
+        }
+    }
+
+    pub fn control_name(&self) -> String {
+        let control = if let Some(note) = self.note {
+            format!("note {note}")
+        } else if let Some(controller) = self.controller {
+            format!("controller {controller}")
+        } else if let Some(program) = self.program {
+            format!("program {program}")
+        } else {
+            "pitch bend".to_string()
+        };
+        match self.channel {
+            Some(channel) => format!("{control} on channel {channel}"),
+            None => control,
+        }
+    }
+
+    // Where the bound control is from 0 to 1, if the message comes from it.
+    // Notes are at their velocity while they're down, note offs are ignored.
+    fn value(&self, message: &MidiMessage) -> Option<f32> {
+        let channel = message.channel()?;
+        if self.channel.is_some_and(|bound| bound != channel + 1) {
+            return None;
+        }
+        match *message {
+            MidiMessage::NoteOn { note, velocity, .. } if self.note == Some(note) => {
+                Some(velocity as f32 / 127.0)
+            }
+            MidiMessage::ControlChange {
+                controller, value, ..
+            } if self.controller == Some(controller) => Some(value as f32 / 127.0),
+            MidiMessage::ProgramChange { program, .. } if self.program == Some(program) => {
+                Some(1.0)
+            }
+            MidiMessage::PitchBend { value, .. } if self.pitch_bend => Some(value as f32 / 16383.0),
+            _ => None,
+        }
+    }
+}
+
+// Turns MIDI messages into input events
+#[derive(Clone, PartialEq, Debug)]
+pub struct MidiMapping {
+    bindings: Vec<MidiBinding>,
+    lights: Lights,
+    // Where learned bindings are saved
+    path: PathBuf,
+    // The action the next control moved is bound to
+    learning: Option<MidiAction>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct MidiMappingFile {
+    #[serde(default)]
+    lights: Lights,
+    #[serde(default, rename = "binding")]
+    bindings: Vec<MidiBinding>,
+}
+
+// The velocities that turn pad lights on and off. Some controllers pick the
+// color from the velocity.
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub struct Lights {
+    pub on: u8,
+    pub off: u8,
+}
+
+impl Default for Lights {
+    fn default() -> Self {
+        Lights { on: 127, off: 0 }
+    }
+}
+
+impl MidiMapping {
+    // The bindings learned in an earlier run when they were saved, the MPD218
+    // profile otherwise
+    pub fn builtin() -> Self {
+        if Path::new(LEARNED_MAPPING_FILE).exists() {
+            match MidiMapping::load_from_file(LEARNED_MAPPING_FILE) {
+                Ok(mapping) => return mapping,
+                Err(e) => eprintln!("Failed to load MIDI mapping {LEARNED_MAPPING_FILE}: {e}"),
+            }
+        }
+        let file: MidiMappingFile = toml::from_str(DEFAULT_MAPPING).unwrap();
+        MidiMapping {
+            bindings: file.bindings,
+            lights: file.lights,
+            path: PathBuf::from(LEARNED_MAPPING_FILE),
+            learning: None,
+        }
+    }
+
+    // Read the same way as presets, anything other than .json is TOML
+    pub fn load_from_file(path: impl AsRef<Path>) -> Result<Self, Box<dyn Error>> {
+        let path = path.as_ref();
+        let contents = fs::read_to_string(path)?;
+        let file: MidiMappingFile = if preset::is_json(path) {
+            serde_json::from_str(&contents)?
+        } else {
+            toml::from_str(&contents)?
+        };
+        println!(
+            "Loaded {} MIDI bindings from {}",
+            file.bindings.len(),
+            path.display()
+        );
+        Ok(MidiMapping {
+            bindings: file.bindings,
+            lights: file.lights,
+            path: path.to_path_buf(),
+            learning: None,
+        })
+    }
+
+    pub fn save(&self) -> Result<(), Box<dyn Error>> {
+        let file = MidiMappingFile {
+            lights: self.lights,
+            bindings: self.bindings.clone(),
+        };
+        let contents = if preset::is_json(&self.path) {
+            serde_json::to_string_pretty(&file)?
+        } else {
+            toml::to_string(&file)?
+        };
+        fs::write(&self.path, contents)?;
+        Ok(())
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn start_learning(&mut self, action: MidiAction) {
+        self.learning = Some(action);
+    }
+
+    pub fn stop_learning(&mut self) {
+        self.learning = None;
+    }
+
+    pub fn is_learning(&self) -> bool {
+        self.learning.is_some()
+    }
+
+    // Replaces whatever the binding's control was bound to before
+    pub fn bind(&mut self, binding: MidiBinding) {
+        self.bindings.retain(|bound| !bound.same_control(&binding));
+        self.bindings.push(binding);
+    }
+
+    // While learning, the first control moved is bound instead of doing
+    // anything
+    pub fn input_events(&mut self, message: &MidiMessage) -> Vec<InputEvent> {
+        if let Some(action) = self.learning {
+            if let Some(binding) = MidiBinding::learn(message, action) {
+                self.learning = None;
+                return vec![InputEvent::LearnMidiBinding(binding)];
+            }
+        }
+        self.bindings
+            .iter()
+            .filter_map(|binding| {
+                let value = binding.value(message)?;
+                binding.action.input_event(value)
+            })
+            .collect()
+    }
+
+    // Lights the pads of the presets loaded into each slot and the tap tempo
+    // pads on beats, and sends knobs and faders where their targets are.
+    // Encoders that turn forever can then pick up from there.
+    pub fn feedback(&self, state: &ControllerState) -> Vec<MidiMessage> {
+        let light = |lit: bool| if lit { self.lights.on } else { self.lights.off };
+        let position = |value: f32| (value.clamp(0.0, 1.0) * 127.0).round() as u8;
+        self.bindings
+            .iter()
+            .filter_map(|binding| {
+                let value = match binding.action {
+                    MidiAction::LoadPreset { slot, preset } => light(
+                        state.loaded_presets[slot.index()]
+                            == Some(PresetLibrary::bank_index(state.preset_bank, preset)),
+                    ),
+                    MidiAction::TapTempo => light(state.beat),
+                    MidiAction::BlendValue => position(state.blend_value),
+                    MidiAction::BeatTransitionTime => {
+                        position(state.beat_transition_time / MAX_BEAT_TRANSITION_TIME)
+                    }
+                    MidiAction::Parameter { slot, parameter } => {
+                        let (min, max) = parameter.range();
+                        let value = parameter.get(&state.presets[slot.index()]);
+                        position((value - min) / (max - min))
+                    }
+                    MidiAction::TurnStrategy { slot } => {
+                        match state.presets[slot.index()].turn_strategy {
+                            TurnStrategy::Steer => 0,
+                            TurnStrategy::Strongest => 64,
+                            TurnStrategy::Weighted => 127,
+                        }
+                    }
+                    _ => return None,
+                };
+                binding.feedback(value)
+            })
+            .collect()
+    }
+}
+
+// Picks what learn mode binds to
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default)]
+pub struct LearnTarget {
+    index: usize,
+    slot: PresetSlot,
+}
+
+impl LearnTarget {
+    pub fn action(&self) -> MidiAction {
+        MidiAction::learn_targets(self.slot)[self.index]
+    }
+
+    // Moves through the targets, wrapping around at either end
+    pub fn step(&mut self, steps: i32) {
+        let count = MidiAction::learn_targets(self.slot).len() as i32;
+        self.index = (self.index as i32 + steps).rem_euclid(count) as usize;
+    }
+
+    pub fn next_slot(&mut self) {
+        self.slot = match self.slot {
+            PresetSlot::Primary => PresetSlot::Secondary,
+            PresetSlot::Secondary => PresetSlot::Beat,
+            PresetSlot::Beat => PresetSlot::Primary,
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::preset::{Preset, PresetName};
+
+    fn control_change(channel: u8, controller: u8, value: u8) -> MidiMessage {
+        MidiMessage::ControlChange {
+            channel,
+            controller,
+            value,
+        }
+    }
+
+    fn mpd218() -> MidiMapping {
+        MidiMapping::load_from_file("midi/mpd218.toml").unwrap()
+    }
+
+    #[test]
+    fn the_profiles_load() {
+        for profile in ["apc_mini", "mpd218", "nanokontrol2"] {
+            let mapping = MidiMapping::load_from_file(format!("midi/{profile}.toml")).unwrap();
+            assert!(!mapping.bindings.is_empty());
+        }
+    }
+
+    #[test]
+    fn bindings_only_hear_their_channel() {
+        let mut mapping = mpd218();
+        // The MPD218's first knob is on channel 1
+        let events = mapping.input_events(&control_change(0, 3, 127));
+        assert!(matches!(events[..], [InputEvent::UpdateBlendValue(value)] if value == 1.0));
+        assert!(mapping.input_events(&control_change(1, 3, 127)).is_empty());
+    }
+
+    #[test]
+    fn buttons_fire_when_pressed() {
+        let mut mapping = mpd218();
+        let press = MidiMessage::NoteOn {
+            channel: 9,
+            note: 46,
+            velocity: 100,
+        };
+        let release = MidiMessage::NoteOff {
+            channel: 9,
+            note: 46,
+            velocity: 0,
+        };
+        assert!(matches!(
+            mapping.input_events(&press)[..],
+            [InputEvent::ClearTextures]
+        ));
+        assert!(mapping.input_events(&release).is_empty());
+    }
+
+    #[test]
+    fn turn_strategies_follow_the_knob_in_their_slot() {
+        let action = MidiAction::TurnStrategy {
+            slot: PresetSlot::Beat,
+        };
+        assert!(matches!(
+            action.input_event(0.0),
+            Some(InputEvent::UpdateTurnStrategy(
+                PresetSlot::Beat,
+                TurnStrategy::Steer
+            ))
+        ));
+        assert!(matches!(
+            action.input_event(0.5),
+            Some(InputEvent::UpdateTurnStrategy(
+                PresetSlot::Beat,
+                TurnStrategy::Strongest
+            ))
+        ));
+        assert!(matches!(
+            action.input_event(1.0),
+            Some(InputEvent::UpdateTurnStrategy(
+                PresetSlot::Beat,
+                TurnStrategy::Weighted
+            ))
+        ));
+
+        // The slot is the primary preset's when left out
+        let binding: MidiBinding =
+            toml::from_str("controller = 14\naction = \"TurnStrategy\"").unwrap();
+        assert_eq!(
+            binding.action,
+            MidiAction::TurnStrategy {
+                slot: PresetSlot::Primary
+            }
+        );
+    }
+
+    #[test]
+    fn parameters_sweep_their_range() {
+        let action = MidiAction::Parameter {
+            slot: PresetSlot::Secondary,
+            parameter: PresetParameter::SearchAngle,
+        };
+        let (min, max) = PresetParameter::SearchAngle.range();
+        assert!(matches!(
+            action.input_event(0.0),
+            Some(InputEvent::UpdateParameter(PresetSlot::Secondary, PresetParameter::SearchAngle, value)) if value == min
+        ));
+        assert!(matches!(
+            action.input_event(1.0),
+            Some(InputEvent::UpdateParameter(PresetSlot::Secondary, PresetParameter::SearchAngle, value)) if value == max
+        ));
+    }
+
+    #[test]
+    fn learning_binds_the_next_control_moved() {
+        let mut mapping = mpd218();
+        mapping.start_learning(MidiAction::TapTempo);
+        // The first knob, which was the blend value
+        let events = mapping.input_events(&control_change(0, 3, 64));
+        let binding = match events[..] {
+            [InputEvent::LearnMidiBinding(binding)] => binding,
+            _ => panic!("expected a learned binding"),
+        };
+        assert!(!mapping.is_learning());
+
+        mapping.bind(binding);
+        assert!(matches!(
+            mapping.input_events(&control_change(0, 3, 127))[..],
+            [InputEvent::TapTempo]
+        ));
+    }
+
+    #[test]
+    fn learn_targets_wrap_around() {
+        let mut target = LearnTarget::default();
+        assert_eq!(target.action(), MidiAction::BlendValue);
+        target.step(-1);
+        target.step(1);
+        assert_eq!(target.action(), MidiAction::BlendValue);
+
+        target.step(2);
+        target.next_slot();
+        assert_eq!(
+            target.action(),
+            MidiAction::TurnStrategy {
+                slot: PresetSlot::Secondary
+            }
+        );
+    }
+
+    #[test]
+    fn learned_bindings_are_saved() {
+        let path = std::env::temp_dir().join(format!("midi-mapping-{}.toml", std::process::id()));
+        let mut mapping = mpd218();
+        mapping.path = path.clone();
+        mapping.bind(MidiBinding {
+            channel: Some(2),
+            note: None,
+            controller: Some(20),
+            program: None,
+            pitch_bend: false,
+            action: MidiAction::Parameter {
+                slot: PresetSlot::Beat,
+                parameter: PresetParameter::SearchAngle,
+            },
+        });
+        mapping.save().unwrap();
+
+        let loaded = MidiMapping::load_from_file(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(loaded.bindings, mapping.bindings);
+    }
+
+    #[test]
+    fn feedback_shows_the_loaded_presets_and_knob_positions() {
+        let binding = |channel, note, controller, action| MidiBinding {
+            channel,
+            note,
+            controller,
+            program: None,
+            pitch_bend: false,
+            action,
+        };
+        let mapping = MidiMapping {
+            bindings: vec![
+                binding(
+                    Some(10),
+                    Some(38),
+                    None,
+                    MidiAction::LoadPreset {
+                        slot: PresetSlot::Primary,
+                        preset: 2,
+                    },
+                ),
+                binding(
+                    Some(10),
+                    Some(39),
+                    None,
+                    MidiAction::LoadPreset {
+                        slot: PresetSlot::Primary,
+                        preset: 3,
+                    },
+                ),
+                binding(None, None, Some(3), MidiAction::BlendValue),
+                binding(
+                    Some(1),
+                    None,
+                    Some(14),
+                    MidiAction::TurnStrategy {
+                        slot: PresetSlot::Beat,
+                    },
+                ),
+            ],
+            path: PathBuf::from(LEARNED_MAPPING_FILE),
+            learning: None,
+            lights: Lights { on: 5, off: 1 },
+        };
+        let mut beat_preset = Preset::new(PresetName::GreenSlime);
+        beat_preset.turn_strategy = TurnStrategy::Weighted;
+        let state = ControllerState {
+            loaded_presets: [Some(PresetLibrary::bank_index(1, 2)), None, None],
+            preset_bank: 1,
+            presets: [
+                Preset::new(PresetName::GreenSlime),
+                Preset::new(PresetName::GreenSlime),
+                beat_preset,
+            ],
+            blend_value: 0.5,
+            beat_transition_time: 0.0,
+            beat: false,
+            bpm: 120.0,
+        };
+
+        assert_eq!(
+            mapping.feedback(&state),
+            vec![
+                MidiMessage::NoteOn {
+                    channel: 9,
+                    note: 38,
+                    velocity: 5
+                },
+                MidiMessage::NoteOn {
+                    channel: 9,
+                    note: 39,
+                    velocity: 1
+                },
+                MidiMessage::ControlChange {
+                    channel: 0,
+                    controller: 3,
+                    value: 64
+                },
+                MidiMessage::ControlChange {
+                    channel: 0,
+                    controller: 14,
+                    value: 127
+                },
+            ]
+        );
+    }
+}
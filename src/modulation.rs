@@ -0,0 +1,404 @@
+use crate::analysis::AudioFeatures;
+use crate::clock::ClockPosition;
+use crate::preset::{self, Preset, PresetParameter};
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+
+// What drives a modulation route
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub enum ModulationSource {
+    // Jumps to full on every detected beat
+    Beat,
+    // The detected tempo, from 60 BPM at zero to 180 BPM at full
+    Bpm,
+    // Jumps to the onset strength whenever the spectrum suddenly grows
+    Onset,
+    // Follows the loudness of the audio
+    Envelope,
+    // Follow the loudness of each frequency band
+    Bass,
+    Mid,
+    High,
+    // Follows how bright the audio sounds
+    Centroid,
+    // Jump to full on every beat and every downbeat of the beat clock, which
+    // keeps going between detected beats
+    ClockBeat,
+    Downbeat,
+    // Ramp from zero to full over each beat or bar of the beat clock
+    BeatPhase,
+    BarPhase,
+    // Swing from zero to full and back once per beat or bar, starting at full
+    // on the beat
+    BeatLfo,
+    BarLfo,
+}
+
+impl ModulationSource {
+    // Triggered sources start an attack and decay each time they fire, the
+    // others are smoothed by them instead
+    fn is_trigger(self) -> bool {
+        matches!(
+            self,
+            ModulationSource::Beat
+                | ModulationSource::Onset
+                | ModulationSource::ClockBeat
+                | ModulationSource::Downbeat
+        )
+    }
+
+    fn triggered(self, inputs: &ModulationInputs) -> bool {
+        match self {
+            ModulationSource::Beat => inputs.beat,
+            ModulationSource::ClockBeat => inputs.clock.crossed_beat,
+            ModulationSource::Downbeat => inputs.clock.crossed_bar,
+            _ => self.value(inputs) > 0.0,
+        }
+    }
+
+    fn value(self, inputs: &ModulationInputs) -> f32 {
+        let audio = &inputs.audio;
+        let lfo = |phase: f32| 0.5 + 0.5 * (2.0 * std::f32::consts::PI * phase).cos();
+        let value = match self {
+            ModulationSource::Beat | ModulationSource::ClockBeat | ModulationSource::Downbeat => {
+                1.0
+            }
+            ModulationSource::Bpm => (inputs.bpm - 60.0) / 120.0,
+            ModulationSource::Onset => audio.onset_strength,
+            ModulationSource::Envelope => audio.rms,
+            ModulationSource::Bass => audio.bass,
+            ModulationSource::Mid => audio.mid,
+            ModulationSource::High => audio.high,
+            ModulationSource::Centroid => audio.spectral_centroid,
+            ModulationSource::BeatPhase => inputs.clock.beat_phase,
+            ModulationSource::BarPhase => inputs.clock.bar_phase,
+            ModulationSource::BeatLfo => lfo(inputs.clock.beat_phase),
+            ModulationSource::BarLfo => lfo(inputs.clock.bar_phase),
+        };
+        value.clamp(0.0, 1.0)
+    }
+}
+
+// How the level of a route is shaped before it's scaled by the depth
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default, Serialize, Deserialize)]
+pub enum Curve {
+    #[default]
+    Linear,
+    // Stays low and rises sharply near the top
+    Exponential,
+    // Rises sharply and flattens out near the top
+    Logarithmic,
+    // Eases in and out
+    SCurve,
+}
+
+impl Curve {
+    fn apply(self, level: f32) -> f32 {
+        match self {
+            Curve::Linear => level,
+            Curve::Exponential => level * level,
+            Curve::Logarithmic => level.sqrt(),
+            Curve::SCurve => level * level * (3.0 - 2.0 * level),
+        }
+    }
+}
+
+// One source driving one preset field
+#[derive(Copy, Clone, PartialEq, Debug, Serialize, Deserialize)]
+pub struct ModulationRoute {
+    pub source: ModulationSource,
+    pub target: PresetParameter,
+    // Fraction of the target's range added at full level, negative subtracts
+    pub depth: f32,
+    // Seconds to rise to the source's level
+    #[serde(default)]
+    pub attack: f32,
+    // Seconds to fall back
+    #[serde(default)]
+    pub decay: f32,
+    #[serde(default)]
+    pub curve: Curve,
+}
+
+// The audio the routes react to this frame
+#[derive(Copy, Clone, PartialEq, Debug, Default)]
+pub struct ModulationInputs {
+    pub beat: bool,
+    // The beat clock's tempo, which may have been tapped in
+    pub bpm: f32,
+    pub audio: AudioFeatures,
+    pub clock: ClockPosition,
+}
+
+#[derive(Copy, Clone, PartialEq, Debug, Default)]
+struct RouteLevel {
+    value: f32,
+    // Where a triggered attack is heading, zero once it's decaying
+    peak: f32,
+}
+
+// The modulation matrix, routes are applied on top of whatever preset is
+// live without changing the presets themselves
+#[derive(Clone, PartialEq, Debug, Default)]
+pub struct Modulation {
+    routes: Vec<ModulationRoute>,
+    levels: Vec<RouteLevel>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct ModulationFile {
+    #[serde(default, rename = "route")]
+    routes: Vec<ModulationRoute>,
+}
+
+impl Modulation {
+    pub fn new(routes: Vec<ModulationRoute>) -> Self {
+        let levels = vec![RouteLevel::default(); routes.len()];
+        Modulation { routes, levels }
+    }
+
+    pub fn load_from_file(path: impl AsRef<Path>) -> Result<Self, Box<dyn Error>> {
+        let path = path.as_ref();
+        let contents = fs::read_to_string(path)?;
+        let file: ModulationFile = if preset::is_json(path) {
+            serde_json::from_str(&contents)?
+        } else {
+            toml::from_str(&contents)?
+        };
+        println!(
+            "Loaded {} modulation routes from {}",
+            file.routes.len(),
+            path.display()
+        );
+        Ok(Modulation::new(file.routes))
+    }
+
+    // Moves every route's level on by the given number of seconds
+    pub fn update(&mut self, inputs: &ModulationInputs, seconds: f32) {
+        for (route, level) in self.routes.iter().zip(&mut self.levels) {
+            if route.source.is_trigger() {
+                // A weaker trigger doesn't cut short the decay of a stronger one
+                if route.source.triggered(inputs) && route.source.value(inputs) > level.value {
+                    level.peak = route.source.value(inputs);
+                }
+                if level.peak > 0.0 {
+                    level.value = approach(level.value, level.peak, seconds, route.attack);
+                    if level.value >= level.peak {
+                        level.peak = 0.0;
+                    }
+                } else {
+                    level.value = approach(level.value, 0.0, seconds, route.decay);
+                }
+            } else {
+                let target = route.source.value(inputs);
+                let time = if target > level.value {
+                    route.attack
+                } else {
+                    route.decay
+                };
+                level.value = approach(level.value, target, seconds, time);
+            }
+        }
+    }
+
+    pub fn apply(&self, preset: &mut Preset) {
+        for (route, level) in self.routes.iter().zip(&self.levels) {
+            let (min, max) = route.target.range();
+            let offset = route.depth * (max - min) * route.curve.apply(level.value);
+            // Fields that are already out of range aren't pulled back into it
+            let base = route.target.get(preset);
+            let value = (base + offset).clamp(min.min(base), max.max(base));
+            route.target.set(preset, value);
+        }
+    }
+}
+
+// Moves linearly so a full swing from 0 to 1 takes the given time
+fn approach(value: f32, target: f32, seconds: f32, time: f32) -> f32 {
+    if time <= 0.0 {
+        return target;
+    }
+    let step = seconds / time;
+    if value < target {
+        (value + step).min(target)
+    } else {
+        (value - step).max(target)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::preset::PresetName;
+
+    fn route(source: ModulationSource, depth: f32, attack: f32, decay: f32) -> ModulationRoute {
+        ModulationRoute {
+            source,
+            // Ranges from 0 to 1, so the field is the route's level times the depth
+            target: PresetParameter::TrailStrength,
+            depth,
+            attack,
+            decay,
+            curve: Curve::Linear,
+        }
+    }
+
+    fn applied(modulation: &Modulation, base: f32) -> f32 {
+        let mut preset = Preset::new(PresetName::GreenSlime);
+        preset.trail_strength = base;
+        modulation.apply(&mut preset);
+        preset.trail_strength
+    }
+
+    fn assert_close(actual: f32, expected: f32) {
+        assert!(
+            (actual - expected).abs() < 1e-4,
+            "expected {expected}, got {actual}"
+        );
+    }
+
+    fn beat(beat: bool) -> ModulationInputs {
+        ModulationInputs {
+            beat,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn curves() {
+        for curve in [
+            Curve::Linear,
+            Curve::Exponential,
+            Curve::Logarithmic,
+            Curve::SCurve,
+        ] {
+            assert_close(curve.apply(0.0), 0.0);
+            assert_close(curve.apply(1.0), 1.0);
+        }
+        assert_close(Curve::Linear.apply(0.25), 0.25);
+        assert_close(Curve::Exponential.apply(0.5), 0.25);
+        assert_close(Curve::Logarithmic.apply(0.25), 0.5);
+        assert_close(Curve::SCurve.apply(0.5), 0.5);
+        assert_close(Curve::SCurve.apply(0.25), 0.15625);
+    }
+
+    #[test]
+    fn triggers_attack_then_decay() {
+        let mut modulation = Modulation::new(vec![route(ModulationSource::Beat, 1.0, 0.1, 0.2)]);
+        assert_close(applied(&modulation, 0.0), 0.0);
+
+        // The attack keeps going after the beat frame
+        modulation.update(&beat(true), 0.05);
+        assert_close(applied(&modulation, 0.0), 0.5);
+        modulation.update(&beat(false), 0.05);
+        assert_close(applied(&modulation, 0.0), 1.0);
+
+        modulation.update(&beat(false), 0.05);
+        assert_close(applied(&modulation, 0.0), 0.75);
+        modulation.update(&beat(false), 0.1);
+        assert_close(applied(&modulation, 0.0), 0.25);
+        modulation.update(&beat(false), 1.0);
+        assert_close(applied(&modulation, 0.0), 0.0);
+    }
+
+    #[test]
+    fn triggers_without_an_attack_jump() {
+        let mut modulation = Modulation::new(vec![route(ModulationSource::Beat, 0.5, 0.0, 0.0)]);
+        modulation.update(&beat(true), 0.01);
+        assert_close(applied(&modulation, 0.0), 0.5);
+        modulation.update(&beat(false), 0.01);
+        assert_close(applied(&modulation, 0.0), 0.0);
+    }
+
+    #[test]
+    fn weaker_triggers_dont_cut_a_decay_short() {
+        let mut modulation = Modulation::new(vec![route(ModulationSource::Onset, 1.0, 0.0, 1.0)]);
+        let onset = |onset_strength| ModulationInputs {
+            audio: AudioFeatures {
+                onset_strength,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        modulation.update(&onset(0.8), 0.1);
+        assert_close(applied(&modulation, 0.0), 0.8);
+        modulation.update(&onset(0.2), 0.1);
+        assert_close(applied(&modulation, 0.0), 0.7);
+        modulation.update(&onset(0.9), 0.1);
+        assert_close(applied(&modulation, 0.0), 0.9);
+    }
+
+    #[test]
+    fn continuous_sources_are_smoothed() {
+        let mut modulation = Modulation::new(vec![route(ModulationSource::Bass, 1.0, 0.1, 0.5)]);
+        let bass = |bass| ModulationInputs {
+            audio: AudioFeatures {
+                bass,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        modulation.update(&bass(1.0), 0.05);
+        assert_close(applied(&modulation, 0.0), 0.5);
+        modulation.update(&bass(0.6), 0.05);
+        assert_close(applied(&modulation, 0.0), 0.6);
+        modulation.update(&bass(0.0), 0.1);
+        assert_close(applied(&modulation, 0.0), 0.4);
+    }
+
+    #[test]
+    fn sources_are_clamped() {
+        let mut modulation = Modulation::new(vec![route(ModulationSource::Bpm, 1.0, 0.0, 0.0)]);
+        let bpm = |bpm| ModulationInputs {
+            bpm,
+            ..Default::default()
+        };
+        modulation.update(&bpm(300.0), 0.01);
+        assert_close(applied(&modulation, 0.0), 1.0);
+        modulation.update(&bpm(120.0), 0.01);
+        assert_close(applied(&modulation, 0.0), 0.5);
+        modulation.update(&bpm(20.0), 0.01);
+        assert_close(applied(&modulation, 0.0), 0.0);
+    }
+
+    #[test]
+    fn modulated_fields_stay_in_range() {
+        let mut modulation = Modulation::new(vec![
+            route(ModulationSource::Beat, 1.0, 0.0, 1.0),
+            route(ModulationSource::Beat, -0.25, 0.0, 1.0),
+        ]);
+        modulation.update(&beat(true), 0.01);
+        // Up to the top of the range, then down from there
+        assert_close(applied(&modulation, 0.5), 0.75);
+
+        let mut modulation = Modulation::new(vec![route(ModulationSource::Beat, -1.0, 0.0, 1.0)]);
+        modulation.update(&beat(true), 0.01);
+        assert_close(applied(&modulation, 0.5), 0.0);
+
+        // but ones that start out of range aren't pulled back into it
+        let mut modulation = Modulation::new(vec![route(ModulationSource::Beat, 0.5, 0.0, 1.0)]);
+        modulation.update(&beat(true), 0.01);
+        assert_close(applied(&modulation, 1.5), 1.5);
+        assert_close(applied(&modulation, 0.25), 0.75);
+    }
+
+    #[test]
+    fn curves_shape_the_level() {
+        let mut route = route(ModulationSource::Bass, 1.0, 0.0, 0.0);
+        route.curve = Curve::Exponential;
+        let mut modulation = Modulation::new(vec![route]);
+        modulation.update(
+            &ModulationInputs {
+                audio: AudioFeatures {
+                    bass: 0.5,
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+            0.01,
+        );
+        assert_close(applied(&modulation, 0.0), 0.25);
+    }
+}
@@ -0,0 +1,771 @@
+use crate::input::{ControllerState, InputEvent, PresetSlot};
+use crate::palette::Palette;
+use crate::preset::{PresetField, PresetParameter};
+use serde::de::{value::StrDeserializer, DeserializeOwned};
+use std::collections::HashMap;
+use std::error::Error;
+use std::net::{SocketAddr, UdpSocket};
+use std::path::PathBuf;
+use std::sync::mpsc::{sync_channel, Receiver};
+use std::thread;
+
+// Big enough for any packet UDP can carry
+const MAX_PACKET_SIZE: usize = 65_536;
+
+// OSC can stop the show and load files, so only this machine can send it
+// unless another address is given
+pub const DEFAULT_ADDRESS: &str = "127.0.0.1";
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum OscArg {
+    Int(i32),
+    Float(f32),
+    String(String),
+    Blob(Vec<u8>),
+    Bool(bool),
+}
+
+impl OscArg {
+    fn as_f32(&self) -> Option<f32> {
+        match self {
+            OscArg::Int(value) => Some(*value as f32),
+            OscArg::Float(value) => Some(*value),
+            OscArg::Bool(value) => Some(*value as i32 as f32),
+            _ => None,
+        }
+    }
+
+    fn as_str(&self) -> Option<&str> {
+        match self {
+            OscArg::String(value) => Some(value),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct OscMessage {
+    pub address: String,
+    pub args: Vec<OscArg>,
+}
+
+impl OscMessage {
+    fn new(address: impl Into<String>, args: Vec<OscArg>) -> Self {
+        OscMessage {
+            address: address.into(),
+            args,
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut packet = Vec::new();
+        write_string(&mut packet, &self.address);
+        let type_tags: String = std::iter::once(',')
+            .chain(self.args.iter().map(|arg| match arg {
+                OscArg::Int(_) => 'i',
+                OscArg::Float(_) => 'f',
+                OscArg::String(_) => 's',
+                OscArg::Blob(_) => 'b',
+                OscArg::Bool(true) => 'T',
+                OscArg::Bool(false) => 'F',
+            }))
+            .collect();
+        write_string(&mut packet, &type_tags);
+        for arg in &self.args {
+            match arg {
+                OscArg::Int(value) => packet.extend_from_slice(&value.to_be_bytes()),
+                OscArg::Float(value) => packet.extend_from_slice(&value.to_be_bytes()),
+                OscArg::String(value) => write_string(&mut packet, value),
+                OscArg::Blob(data) => {
+                    packet.extend_from_slice(&(data.len() as i32).to_be_bytes());
+                    packet.extend_from_slice(data);
+                    pad(&mut packet);
+                }
+                OscArg::Bool(_) => (),
+            }
+        }
+        packet
+    }
+
+    // The messages in a packet, with bundles unpacked. Time tags are ignored,
+    // everything happens as soon as it arrives.
+    pub fn decode_packet(packet: &[u8]) -> Result<Vec<OscMessage>, String> {
+        let mut reader = Reader { data: packet };
+        if packet.starts_with(b"#bundle\0") {
+            reader.read_string()?;
+            // Time tag
+            reader.take(8)?;
+            let mut messages = Vec::new();
+            while !reader.data.is_empty() {
+                let size = reader.read_i32()?;
+                let element = reader.take(size.max(0) as usize)?;
+                messages.extend(OscMessage::decode_packet(element)?);
+            }
+            return Ok(messages);
+        }
+
+        let address = reader.read_string()?;
+        if !address.starts_with('/') {
+            return Err(format!("Not an OSC address: {address}"));
+        }
+        // Some old clients leave the type tags out when there are no arguments
+        if reader.data.is_empty() {
+            return Ok(vec![OscMessage::new(address, Vec::new())]);
+        }
+        let type_tags = reader.read_string()?;
+        let mut args = Vec::new();
+        for tag in type_tags.chars().skip(1) {
+            let arg = match tag {
+                'i' => OscArg::Int(reader.read_i32()?),
+                'f' => OscArg::Float(f32::from_bits(reader.read_i32()? as u32)),
+                's' | 'S' => OscArg::String(reader.read_string()?),
+                'b' => {
+                    let size = reader.read_i32()?.max(0) as usize;
+                    let data = reader.take(size)?.to_vec();
+                    reader.take((4 - size % 4) % 4)?;
+                    OscArg::Blob(data)
+                }
+                'T' => OscArg::Bool(true),
+                'F' => OscArg::Bool(false),
+                // Nil and infinitum carry nothing
+                'N' | 'I' => continue,
+                'h' | 't' | 'd' => {
+                    reader.take(8)?;
+                    continue;
+                }
+                _ => return Err(format!("Unsupported OSC type tag: {tag}")),
+            };
+            args.push(arg);
+        }
+        Ok(vec![OscMessage::new(address, args)])
+    }
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, size: usize) -> Result<&'a [u8], String> {
+        if size > self.data.len() {
+            return Err("OSC packet is too short".to_string());
+        }
+        let (taken, rest) = self.data.split_at(size);
+        self.data = rest;
+        Ok(taken)
+    }
+
+    fn read_i32(&mut self) -> Result<i32, String> {
+        let bytes = self.take(4)?;
+        Ok(i32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    // Strings end with a null and are padded to a multiple of four bytes
+    fn read_string(&mut self) -> Result<String, String> {
+        let length = self
+            .data
+            .iter()
+            .position(|&byte| byte == 0)
+            .ok_or("OSC string isn't terminated")?;
+        let string = String::from_utf8_lossy(&self.data[..length]).into_owned();
+        self.take((length / 4 + 1) * 4)?;
+        Ok(string)
+    }
+}
+
+fn write_string(packet: &mut Vec<u8>, string: &str) {
+    packet.extend_from_slice(string.as_bytes());
+    packet.push(0);
+    pad(packet);
+}
+
+fn pad(packet: &mut Vec<u8>) {
+    while packet.len() % 4 != 0 {
+        packet.push(0);
+    }
+}
+
+// Listens for OSC messages on a UDP port. Input events have addresses, and so
+// does every preset field of each slot, e.g. /slot/primary/trail_strength or
+// /slot/beat/initial_parameters/number_of_points. A
+// message with no arguments to an address with a value asks for it, or for
+// everything under it, and the reply goes back to the sender.
+pub struct OscServer {
+    socket: UdpSocket,
+    receiver: Receiver<(OscMessage, SocketAddr)>,
+    // Sent every change to the values
+    subscribers: Vec<SocketAddr>,
+    // Asked for a value, or everything under an address
+    queries: Vec<(SocketAddr, String)>,
+    // The last values sent to the subscribers
+    sent: HashMap<String, Vec<OscArg>>,
+}
+
+impl OscServer {
+    pub fn start(address: &str, port: u16) -> Result<Self, Box<dyn Error>> {
+        let socket = UdpSocket::bind((address, port))?;
+        let listener = socket.try_clone()?;
+        let (sender, receiver) = sync_channel(256);
+        thread::spawn(move || {
+            let mut buffer = vec![0; MAX_PACKET_SIZE];
+            loop {
+                let (size, address) = match listener.recv_from(&mut buffer) {
+                    Ok(received) => received,
+                    Err(e) => {
+                        eprintln!("OSC receive error: {e}");
+                        continue;
+                    }
+                };
+                match OscMessage::decode_packet(&buffer[..size]) {
+                    Ok(messages) => {
+                        for message in messages {
+                            // The render loop is gone once sending fails
+                            if sender.send((message, address)).is_err() {
+                                return;
+                            }
+                        }
+                    }
+                    Err(e) => eprintln!("Bad OSC packet from {address}: {e}"),
+                }
+            }
+        });
+        println!("Listening for OSC on {address} port {port}");
+        Ok(OscServer {
+            socket,
+            receiver,
+            subscribers: Vec::new(),
+            queries: Vec::new(),
+            sent: HashMap::new(),
+        })
+    }
+
+    // Everything that arrived since the last frame. Queries are answered in
+    // send_feedback once the frame's events have been handled.
+    pub fn input_events(&mut self) -> Vec<InputEvent> {
+        let mut input_events = Vec::new();
+        let messages: Vec<_> = self.receiver.try_iter().collect();
+        for (message, sender) in messages {
+            match message.address.as_str() {
+                // Optionally with a port to send to on the sender's host
+                "/subscribe" => {
+                    let mut subscriber = sender;
+                    if let Some(port) = message.args.first().and_then(OscArg::as_f32) {
+                        subscriber.set_port(port as u16);
+                    }
+                    if !self.subscribers.contains(&subscriber) {
+                        println!("OSC feedback to {subscriber}");
+                        self.subscribers.push(subscriber);
+                    }
+                    self.queries.push((subscriber, "/".to_string()));
+                }
+                "/unsubscribe" => {
+                    let port = message.args.first().and_then(OscArg::as_f32);
+                    self.subscribers.retain(|subscriber| {
+                        subscriber.ip() != sender.ip()
+                            || port.is_some_and(|port| subscriber.port() != port as u16)
+                    });
+                }
+                _ => match input_event(&message) {
+                    Some(event) => input_events.push(event),
+                    None if message.args.is_empty() && is_value_address(&message.address) => {
+                        self.queries.push((sender, message.address));
+                    }
+                    None if !is_button_release(&message) => {
+                        eprintln!("Unknown OSC message: {message:?}")
+                    }
+                    None => (),
+                },
+            }
+        }
+        input_events
+    }
+
+    // Answers queries and sends subscribers the values that changed
+    pub fn send_feedback(&mut self, state: &ControllerState) {
+        let values = values(state);
+        for (client, query) in std::mem::take(&mut self.queries) {
+            for message in values
+                .iter()
+                .filter(|message| under(&message.address, &query))
+            {
+                self.send(message, client);
+            }
+        }
+
+        if self.subscribers.is_empty() {
+            return;
+        }
+        for message in values {
+            if self.sent.get(&message.address) == Some(&message.args) {
+                continue;
+            }
+            for &subscriber in &self.subscribers {
+                self.send(&message, subscriber);
+            }
+            self.sent.insert(message.address, message.args);
+        }
+    }
+
+    fn send(&self, message: &OscMessage, client: SocketAddr) {
+        if let Err(e) = self.socket.send_to(&message.encode(), client) {
+            eprintln!("Failed to send OSC to {client}: {e}");
+        }
+    }
+}
+
+// Whether an address is the query address or under it
+fn under(address: &str, query: &str) -> bool {
+    let query = query.trim_end_matches('/');
+    address == query || address.starts_with(&format!("{query}/"))
+}
+
+// Whether anything can be asked for at or under this address
+fn is_value_address(address: &str) -> bool {
+    address == "/"
+        || matches!(
+            address,
+            "/blend_value" | "/beat_transition_time" | "/bpm" | "/beat"
+        )
+        || matches!(address, "/preset_bank" | "/slot")
+        || address
+            .strip_prefix("/slot/")
+            .and_then(|rest| PresetSlot::from_name(rest.split('/').next().unwrap_or_default()))
+            .is_some()
+}
+
+// Every value that can be asked for and subscribed to
+fn values(state: &ControllerState) -> Vec<OscMessage> {
+    let mut values = vec![
+        OscMessage::new("/blend_value", vec![OscArg::Float(state.blend_value)]),
+        OscMessage::new(
+            "/beat_transition_time",
+            vec![OscArg::Float(state.beat_transition_time)],
+        ),
+        OscMessage::new("/bpm", vec![OscArg::Float(state.bpm)]),
+        OscMessage::new("/beat", vec![OscArg::Int(state.beat as i32)]),
+        OscMessage::new("/preset_bank", vec![OscArg::Int(state.preset_bank as i32)]),
+    ];
+    for slot in PresetSlot::ALL {
+        let preset = &state.presets[slot.index()];
+        let loaded = state.loaded_presets[slot.index()].map_or(-1, |index| index as i32);
+        values.push(OscMessage::new(
+            format!("/slot/{}/loaded", slot.name()),
+            vec![OscArg::Int(loaded)],
+        ));
+        for parameter in PresetParameter::ALL {
+            values.push(OscMessage::new(
+                format!("/slot/{}/{}", slot.name(), parameter.name()),
+                vec![OscArg::Float(parameter.get(preset))],
+            ));
+        }
+
+        let initial_parameters = &preset.initial_parameters;
+        let fields = [
+            ("turn_strategy", variant_name(preset.turn_strategy)),
+            ("wall_strategy", variant_name(preset.wall_strategy)),
+            ("color_strategy", variant_name(preset.color_strategy)),
+            (
+                "palette",
+                preset
+                    .palette
+                    .stops()
+                    .into_iter()
+                    .flatten()
+                    .map(OscArg::Float)
+                    .collect(),
+            ),
+            (
+                "initial_parameters/number_of_points",
+                vec![OscArg::Int(initial_parameters.number_of_points as i32)],
+            ),
+            (
+                "initial_parameters/starting_arrangement",
+                variant_name(initial_parameters.starting_arrangement),
+            ),
+            (
+                "initial_parameters/average_starting_speed",
+                vec![OscArg::Float(initial_parameters.average_starting_speed)],
+            ),
+            (
+                "initial_parameters/starting_speed_spread",
+                vec![OscArg::Float(initial_parameters.starting_speed_spread)],
+            ),
+        ];
+        for (field, args) in fields {
+            values.push(OscMessage::new(
+                format!("/slot/{}/{field}", slot.name()),
+                args,
+            ));
+        }
+    }
+    values
+}
+
+// Enum fields are sent and set by the names they have in preset files
+fn variant_name(value: impl std::fmt::Debug) -> Vec<OscArg> {
+    vec![OscArg::String(format!("{value:?}"))]
+}
+
+fn variant<T: DeserializeOwned>(name: &str) -> Option<T> {
+    T::deserialize(StrDeserializer::<serde::de::value::Error>::new(name)).ok()
+}
+
+// A list of [position, r, g, b] stops, or the path of a gradient image
+fn palette(args: &[OscArg]) -> Option<Palette> {
+    let palette = match args {
+        [OscArg::String(path)] => Palette::load_image(path),
+        _ => {
+            let numbers: Option<Vec<f32>> = args.iter().map(OscArg::as_f32).collect();
+            let numbers = numbers?;
+            if numbers.len() % 4 != 0 {
+                return None;
+            }
+            let stops: Vec<[f32; 4]> = numbers
+                .chunks(4)
+                .map(|stop| [stop[0], stop[1], stop[2], stop[3]])
+                .collect();
+            Palette::from_stops(&stops)
+        }
+    };
+    palette.map_err(|e| eprintln!("Bad OSC palette: {e}")).ok()
+}
+
+// Buttons on OSC surfaces send 1 when pressed and 0 when released, or nothing
+// at all
+fn is_button_release(message: &OscMessage) -> bool {
+    message.args.first().and_then(OscArg::as_f32) == Some(0.0)
+}
+
+fn input_event(message: &OscMessage) -> Option<InputEvent> {
+    let number = message.args.first().and_then(OscArg::as_f32);
+    let text = message.args.first().and_then(OscArg::as_str);
+    if is_button_release(message) && !has_value(&message.address) {
+        return None;
+    }
+
+    let path: Vec<&str> = message.address.split('/').skip(1).collect();
+    let event = match path.as_slice() {
+        ["toggle_fullscreen"] => InputEvent::ToggleFullscreen,
+        ["toggle_automation"] => InputEvent::ToggleAutomation,
+        ["next_preset_bank"] => InputEvent::NextPresetBank,
+        ["previous_preset_bank"] => InputEvent::PreviousPresetBank,
+        ["tap_tempo"] => InputEvent::TapTempo,
+        ["release_tap_tempo"] => InputEvent::ReleaseTapTempo,
+        ["set_downbeat"] => InputEvent::SetDownbeat,
+        ["nudge_beat"] => InputEvent::NudgeBeat(number?),
+        ["dump_state"] => InputEvent::DumpState,
+        ["restore_session"] => InputEvent::RestoreSession(PathBuf::from(text?)),
+        ["clear_textures"] => InputEvent::ClearTextures,
+        ["reset_points"] => InputEvent::ResetPoints,
+        ["take_screenshot"] => InputEvent::TakeScreenshot,
+        ["stop_event_loop"] => InputEvent::StopEventLoop,
+        ["blend_value"] => InputEvent::UpdateBlendValue(number?),
+        ["beat_transition_time"] => InputEvent::UpdateBeatTransitionTime(number?),
+        ["slot", slot, action] => {
+            let slot = PresetSlot::from_name(slot)?;
+            match *action {
+                "randomize" => InputEvent::RandomizePreset(slot),
+                // Index of the preset within the current preset bank
+                "load" => InputEvent::LoadPreset(slot, number? as usize),
+                "load_file" => InputEvent::LoadPresetFile(slot, PathBuf::from(text?)),
+                "turn_strategy" => InputEvent::UpdateTurnStrategy(slot, variant(text?)?),
+                "wall_strategy" => {
+                    InputEvent::UpdatePresetField(slot, PresetField::WallStrategy(variant(text?)?))
+                }
+                "color_strategy" => {
+                    InputEvent::UpdatePresetField(slot, PresetField::ColorStrategy(variant(text?)?))
+                }
+                "palette" => InputEvent::UpdatePresetField(
+                    slot,
+                    PresetField::Palette(palette(&message.args)?),
+                ),
+                field => {
+                    InputEvent::UpdateParameter(slot, PresetParameter::from_name(field)?, number?)
+                }
+            }
+        }
+        ["slot", slot, "initial_parameters", field] => {
+            let slot = PresetSlot::from_name(slot)?;
+            let field = match *field {
+                "number_of_points" => PresetField::NumberOfPoints(number?.max(0.0) as u32),
+                "starting_arrangement" => PresetField::StartingArrangement(variant(text?)?),
+                "average_starting_speed" => PresetField::AverageStartingSpeed(number?),
+                "starting_speed_spread" => PresetField::StartingSpeedSpread(number?),
+                _ => return None,
+            };
+            InputEvent::UpdatePresetField(slot, field)
+        }
+        _ => return None,
+    };
+    Some(event)
+}
+
+// Addresses that take a value, where 0 isn't a button being released
+fn has_value(address: &str) -> bool {
+    let field = address.rsplit('/').next().unwrap_or_default();
+    matches!(
+        field,
+        "nudge_beat"
+            | "blend_value"
+            | "beat_transition_time"
+            | "load"
+            | "palette"
+            | "number_of_points"
+            | "average_starting_speed"
+            | "starting_speed_spread"
+    ) || PresetParameter::from_name(field).is_some()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::preset::{
+        ColorStrategy, Preset, PresetName, StartingArrangement, TurnStrategy, WallStrategy,
+    };
+
+    fn round_trip(message: &OscMessage) -> OscMessage {
+        let mut messages = OscMessage::decode_packet(&message.encode()).unwrap();
+        assert_eq!(messages.len(), 1);
+        messages.remove(0)
+    }
+
+    fn bundle(elements: &[Vec<u8>]) -> Vec<u8> {
+        let mut packet = Vec::new();
+        write_string(&mut packet, "#bundle");
+        // Immediately
+        packet.extend_from_slice(&1u64.to_be_bytes());
+        for element in elements {
+            packet.extend_from_slice(&(element.len() as i32).to_be_bytes());
+            packet.extend_from_slice(element);
+        }
+        packet
+    }
+
+    #[test]
+    fn strings_are_null_terminated_and_padded() {
+        let message = OscMessage::new("/abc", vec![]);
+        assert_eq!(message.encode(), b"/abc\0\0\0\0,\0\0\0");
+        let message = OscMessage::new("/ab", vec![OscArg::String("xyz".to_string())]);
+        assert_eq!(message.encode(), b"/ab\0,s\0\0xyz\0");
+    }
+
+    #[test]
+    fn every_argument_type_round_trips() {
+        for length in 0..=8 {
+            let message = OscMessage::new(
+                "/slot/primary/point_size",
+                vec![
+                    OscArg::Int(-7),
+                    OscArg::Float(2.5),
+                    OscArg::String("a".repeat(length)),
+                    OscArg::Blob((0..length as u8).collect()),
+                    OscArg::Bool(true),
+                    OscArg::Bool(false),
+                    OscArg::Float(-0.125),
+                ],
+            );
+            let encoded = message.encode();
+            assert_eq!(encoded.len() % 4, 0);
+            assert_eq!(round_trip(&message), message);
+        }
+    }
+
+    #[test]
+    fn blobs_are_padded() {
+        let message = OscMessage::new("/b", vec![OscArg::Blob(vec![1, 2, 3, 4, 5])]);
+        let encoded = message.encode();
+        // Address, type tags, size, then five bytes padded to eight
+        assert_eq!(encoded.len(), 4 + 4 + 4 + 8);
+        assert_eq!(&encoded[8..12], &5i32.to_be_bytes());
+        assert_eq!(&encoded[12..], &[1, 2, 3, 4, 5, 0, 0, 0]);
+    }
+
+    #[test]
+    fn messages_without_type_tags() {
+        assert_eq!(
+            OscMessage::decode_packet(b"/tap_tempo\0\0").unwrap(),
+            vec![OscMessage::new("/tap_tempo", vec![])]
+        );
+    }
+
+    #[test]
+    fn bundles_are_unpacked() {
+        let first = OscMessage::new("/blend_value", vec![OscArg::Float(0.5)]);
+        let second = OscMessage::new("/tap_tempo", vec![]);
+        let third = OscMessage::new("/nudge_beat", vec![OscArg::Int(1)]);
+        let inner = bundle(&[third.encode()]);
+        let packet = bundle(&[first.encode(), second.encode(), inner]);
+
+        assert_eq!(
+            OscMessage::decode_packet(&packet).unwrap(),
+            vec![first, second, third]
+        );
+    }
+
+    #[test]
+    fn truncated_packets_are_errors() {
+        let message = OscMessage::new(
+            "/slot/beat/load_file",
+            vec![
+                OscArg::String("presets/one.toml".to_string()),
+                OscArg::Blob(vec![1, 2, 3]),
+                OscArg::Float(1.0),
+            ],
+        );
+        let encoded = message.encode();
+        // Every cut that doesn't leave just the address, which is a message
+        // with no arguments
+        for length in 0..encoded.len() {
+            if length == 24 {
+                continue;
+            }
+            assert!(
+                OscMessage::decode_packet(&encoded[..length]).is_err(),
+                "{length} bytes decoded"
+            );
+        }
+
+        let packet = bundle(&[message.encode()]);
+        assert!(OscMessage::decode_packet(&packet[..packet.len() - 4]).is_err());
+        assert!(OscMessage::decode_packet(&packet[..18]).is_err());
+    }
+
+    #[test]
+    fn packets_that_arent_osc_are_errors() {
+        assert!(OscMessage::decode_packet(b"").is_err());
+        assert!(OscMessage::decode_packet(b"hello\0\0\0").is_err());
+        assert!(OscMessage::decode_packet(b"/x\0\0,q\0\0").is_err());
+    }
+
+    #[test]
+    fn slot_fields_become_parameter_updates() {
+        let message = OscMessage::new("/slot/secondary/trail_strength", vec![OscArg::Float(0.4)]);
+        assert!(matches!(
+            input_event(&message),
+            Some(InputEvent::UpdateParameter(
+                PresetSlot::Secondary,
+                PresetParameter::TrailStrength,
+                value
+            )) if value == 0.4
+        ));
+        // A button being released
+        let message = OscMessage::new("/tap_tempo", vec![OscArg::Int(0)]);
+        assert!(input_event(&message).is_none());
+    }
+
+    #[test]
+    fn queries_match_everything_under_them() {
+        assert!(under("/slot/primary/point_size", "/"));
+        assert!(under("/slot/primary/point_size", "/slot/primary"));
+        assert!(under("/slot/primary/point_size", "/slot/primary/"));
+        assert!(!under("/slot/primary_other", "/slot/primary"));
+        assert!(is_value_address("/slot/beat"));
+        assert!(!is_value_address("/slot/nowhere"));
+    }
+
+    #[test]
+    fn enum_fields_are_set_by_name() {
+        let message = OscMessage::new(
+            "/slot/beat/wall_strategy",
+            vec![OscArg::String("Bounce".to_string())],
+        );
+        assert!(matches!(
+            input_event(&message),
+            Some(InputEvent::UpdatePresetField(
+                PresetSlot::Beat,
+                PresetField::WallStrategy(WallStrategy::Bounce)
+            ))
+        ));
+        let message = OscMessage::new(
+            "/slot/secondary/turn_strategy",
+            vec![OscArg::String("Weighted".to_string())],
+        );
+        assert!(matches!(
+            input_event(&message),
+            Some(InputEvent::UpdateTurnStrategy(
+                PresetSlot::Secondary,
+                TurnStrategy::Weighted
+            ))
+        ));
+        let message = OscMessage::new(
+            "/slot/primary/color_strategy",
+            vec![OscArg::String("Plaid".to_string())],
+        );
+        assert!(input_event(&message).is_none());
+    }
+
+    #[test]
+    fn palettes_are_set_from_stops() {
+        let message = OscMessage::new(
+            "/slot/primary/palette",
+            [0.0, 0.0, 0.0, 0.0, 1.0, 1.0, 0.5, 0.0]
+                .into_iter()
+                .map(OscArg::Float)
+                .collect(),
+        );
+        let expected = Palette::from_stops(&[[0.0, 0.0, 0.0, 0.0], [1.0, 1.0, 0.5, 0.0]]).unwrap();
+        assert!(matches!(
+            input_event(&message),
+            Some(InputEvent::UpdatePresetField(PresetSlot::Primary, PresetField::Palette(palette)))
+                if palette == expected
+        ));
+        // Stops have four numbers
+        let message = OscMessage::new("/slot/primary/palette", vec![OscArg::Float(0.0)]);
+        assert!(input_event(&message).is_none());
+    }
+
+    #[test]
+    fn zero_initial_parameters_are_values() {
+        let message = OscMessage::new(
+            "/slot/primary/initial_parameters/starting_speed_spread",
+            vec![OscArg::Int(0)],
+        );
+        assert!(matches!(
+            input_event(&message),
+            Some(InputEvent::UpdatePresetField(
+                PresetSlot::Primary,
+                PresetField::StartingSpeedSpread(value)
+            )) if value == 0.0
+        ));
+    }
+
+    #[test]
+    fn every_preset_field_sent_can_be_set() {
+        let mut preset = Preset::new(PresetName::Tartan);
+        preset.wall_strategy = WallStrategy::BounceRandom;
+        preset.color_strategy = ColorStrategy::PaletteSpeed;
+        preset.turn_strategy = TurnStrategy::Strongest;
+        preset.initial_parameters.starting_arrangement = StartingArrangement::Ring;
+        let state = ControllerState {
+            loaded_presets: [None; 3],
+            preset_bank: 0,
+            presets: [Preset::new(PresetName::GreenSlime), preset, preset],
+            blend_value: 0.0,
+            beat_transition_time: 0.0,
+            beat: false,
+            bpm: 120.0,
+        };
+
+        let mut copy = Preset::new(PresetName::GreenSlime);
+        for message in values(&state)
+            .iter()
+            .filter(|message| under(&message.address, "/slot/secondary"))
+        {
+            match input_event(message) {
+                Some(InputEvent::UpdateParameter(PresetSlot::Secondary, parameter, value)) => {
+                    parameter.set(&mut copy, value)
+                }
+                Some(InputEvent::UpdateTurnStrategy(PresetSlot::Secondary, turn_strategy)) => {
+                    copy.turn_strategy = turn_strategy
+                }
+                Some(InputEvent::UpdatePresetField(PresetSlot::Secondary, field)) => {
+                    field.set(&mut copy)
+                }
+                // Which library preset is loaded can only be asked for
+                None if message.address == "/slot/secondary/loaded" => (),
+                _ => panic!("{} can't be set", message.address),
+            }
+        }
+        assert_eq!(copy, preset);
+    }
+}
@@ -0,0 +1,199 @@
+use lerp::Lerp;
+use rand::{
+    distributions::{Distribution, Standard},
+    Rng,
+};
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+
+// Every palette is resampled to this many evenly spaced colors
+pub const PALETTE_SIZE: usize = 16;
+
+// A color gradient for the palette color strategies. Keeping the colors evenly
+// spaced means two palettes can be cross-faded color by color.
+#[derive(PartialEq, Debug, Copy, Clone, Serialize, Deserialize)]
+#[serde(try_from = "PaletteFile", into = "PaletteFile")]
+pub struct Palette {
+    colors: [[f32; 3]; PALETTE_SIZE],
+}
+
+// How a palette is written in a preset file
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+enum PaletteFile {
+    // Color stops as [position, r, g, b] with positions from 0 to 1
+    Stops { stops: Vec<[f32; 4]> },
+    // A gradient image read from left to right along its middle row. Saving
+    // the preset again writes the resampled colors as stops.
+    Image { image: String },
+}
+
+impl Palette {
+    // Stops don't have to be sorted, colors before the first and after the
+    // last stop are the color of that stop
+    pub fn from_stops(stops: &[[f32; 4]]) -> Result<Palette, Box<dyn Error>> {
+        if stops.is_empty() {
+            return Err("A palette needs at least one color stop".into());
+        }
+        let mut stops = stops.to_vec();
+        stops.sort_by(|a, b| a[0].total_cmp(&b[0]));
+
+        let mut colors = [[0.0; 3]; PALETTE_SIZE];
+        for (index, color) in colors.iter_mut().enumerate() {
+            let position = index as f32 / (PALETTE_SIZE - 1) as f32;
+            let next = stops
+                .iter()
+                .position(|stop| stop[0] >= position)
+                .unwrap_or(stops.len() - 1);
+            let (low, high) = (stops[next.saturating_sub(1)], stops[next]);
+            let t = if high[0] > low[0] {
+                ((position - low[0]) / (high[0] - low[0])).clamp(0.0, 1.0)
+            } else {
+                1.0
+            };
+            *color = [
+                low[1].lerp(high[1], t),
+                low[2].lerp(high[2], t),
+                low[3].lerp(high[3], t),
+            ];
+        }
+        Ok(Palette { colors })
+    }
+
+    pub fn load_image(path: &str) -> Result<Palette, Box<dyn Error>> {
+        let image = image::open(path)?.to_rgb32f();
+        let (width, height) = image.dimensions();
+        let row = height / 2;
+        let stops = (0..width)
+            .map(|x| {
+                let pixel = image.get_pixel(x, row);
+                let position = if width > 1 {
+                    x as f32 / (width - 1) as f32
+                } else {
+                    0.0
+                };
+                [position, pixel[0], pixel[1], pixel[2]]
+            })
+            .collect::<Vec<_>>();
+        Palette::from_stops(&stops)
+    }
+
+    pub fn colors(&self) -> [[f32; 3]; PALETTE_SIZE] {
+        self.colors
+    }
+
+    // The colors as evenly spaced [position, r, g, b] stops
+    pub fn stops(&self) -> Vec<[f32; 4]> {
+        self.colors
+            .iter()
+            .enumerate()
+            .map(|(index, [r, g, b])| [index as f32 / (PALETTE_SIZE - 1) as f32, *r, *g, *b])
+            .collect()
+    }
+}
+
+// From black through deep purple and orange to pale yellow
+impl Default for Palette {
+    fn default() -> Self {
+        Palette::from_stops(&[
+            [0.0, 0.0, 0.0, 0.0],
+            [0.35, 0.33, 0.07, 0.45],
+            [0.7, 0.95, 0.42, 0.15],
+            [1.0, 1.0, 0.95, 0.7],
+        ])
+        .unwrap()
+    }
+}
+
+impl TryFrom<PaletteFile> for Palette {
+    type Error = String;
+
+    fn try_from(file: PaletteFile) -> Result<Self, Self::Error> {
+        match file {
+            PaletteFile::Stops { stops } => Palette::from_stops(&stops),
+            PaletteFile::Image { image } => Palette::load_image(&image),
+        }
+        .map_err(|e| e.to_string())
+    }
+}
+
+impl From<Palette> for PaletteFile {
+    fn from(palette: Palette) -> Self {
+        PaletteFile::Stops {
+            stops: palette.stops(),
+        }
+    }
+}
+
+impl Lerp<f32> for Palette {
+    fn lerp(self, other: Self, t: f32) -> Self {
+        let mut colors = self.colors;
+        for (color, other) in colors.iter_mut().zip(other.colors) {
+            for (channel, other) in color.iter_mut().zip(other) {
+                *channel = (*channel).lerp(other, t);
+            }
+        }
+        Palette { colors }
+    }
+}
+
+// Two to four random stops, always starting dark so the background stays dark
+impl Distribution<Palette> for Standard {
+    fn sample<R: Rng + ?Sized>(&self, rng: &mut R) -> Palette {
+        let mut stops = vec![[0.0, 0.0, 0.0, 0.0]];
+        let count = rng.gen_range(1..=3);
+        for index in 1..=count {
+            stops.push([
+                index as f32 / count as f32,
+                rng.gen_range(0.0..=1.0),
+                rng.gen_range(0.0..=1.0),
+                rng.gen_range(0.0..=1.0),
+            ]);
+        }
+        Palette::from_stops(&stops).unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stops_are_resampled_evenly() {
+        let palette = Palette::from_stops(&[[1.0, 1.0, 0.0, 0.0], [0.0, 0.0, 0.0, 1.0]]).unwrap();
+        let colors = palette.colors();
+        assert_eq!(colors[0], [0.0, 0.0, 1.0]);
+        assert_eq!(colors[PALETTE_SIZE - 1], [1.0, 0.0, 0.0]);
+        assert!((colors[5][0] - 1.0 / 3.0).abs() < 1e-6);
+        assert!((colors[5][2] - 2.0 / 3.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn colors_outside_the_stops_are_the_nearest_stop() {
+        let palette = Palette::from_stops(&[[0.4, 1.0, 1.0, 1.0], [0.6, 0.0, 0.0, 0.0]]).unwrap();
+        assert_eq!(palette.colors()[0], [1.0; 3]);
+        assert_eq!(palette.colors()[PALETTE_SIZE - 1], [0.0; 3]);
+        assert!(Palette::from_stops(&[]).is_err());
+    }
+
+    #[test]
+    fn palettes_cross_fade_color_by_color() {
+        let black = Palette::from_stops(&[[0.0, 0.0, 0.0, 0.0]]).unwrap();
+        let white = Palette::from_stops(&[[0.0, 1.0, 1.0, 1.0]]).unwrap();
+        let grey = black.lerp(white, 0.5);
+        assert!(grey.colors().iter().all(|&color| color == [0.5; 3]));
+    }
+
+    #[test]
+    fn palettes_are_saved_as_stops() {
+        let palette = Palette::default();
+        let saved = serde_json::to_string(&palette).unwrap();
+        assert!(saved.starts_with(r#"{"stops":"#));
+        let loaded: Palette = serde_json::from_str(&saved).unwrap();
+        for (loaded, original) in loaded.colors().iter().zip(palette.colors()) {
+            for (loaded, original) in loaded.iter().zip(original) {
+                assert!((loaded - original).abs() < 1e-6);
+            }
+        }
+    }
+}
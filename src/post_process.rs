@@ -0,0 +1,510 @@
+use crate::config::AppConfig;
+use crate::preset::PostProcessParameters;
+use glium::backend::Facade;
+use glium::framebuffer::SimpleFrameBuffer;
+use glium::uniforms::{self, Sampler};
+use glium::{implement_vertex, uniform, Surface};
+use std::error::Error;
+use std::path::Path;
+
+#[derive(Copy, Clone)]
+struct Vertex {
+    a_vertex: [f32; 2],
+}
+
+implement_vertex!(Vertex, a_vertex);
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Effect {
+    Feedback,
+    Kaleidoscope,
+    Bloom,
+    ChromaticAberration,
+    Lut,
+    Vignette,
+}
+
+impl Effect {
+    pub const DEFAULT_CHAIN: [Effect; 6] = [
+        Effect::Feedback,
+        Effect::Kaleidoscope,
+        Effect::Bloom,
+        Effect::ChromaticAberration,
+        Effect::Lut,
+        Effect::Vignette,
+    ];
+
+    pub fn from_name(name: &str) -> Effect {
+        match name {
+            "feedback" => Effect::Feedback,
+            "kaleidoscope" => Effect::Kaleidoscope,
+            "bloom" => Effect::Bloom,
+            "chromatic_aberration" => Effect::ChromaticAberration,
+            "lut" => Effect::Lut,
+            "vignette" => Effect::Vignette,
+            name => panic!("Unknown post-process effect: {name}"),
+        }
+    }
+
+    // A comma separated list in the order the effects are applied, e.g.
+    // "kaleidoscope,bloom", where an empty list turns post-processing off
+    pub fn chain_from_names(names: Option<&str>) -> Vec<Effect> {
+        match names {
+            None => Effect::DEFAULT_CHAIN.to_vec(),
+            Some(names) => names
+                .split(',')
+                .map(str::trim)
+                .filter(|name| !name.is_empty())
+                .map(Effect::from_name)
+                .collect(),
+        }
+    }
+
+    // Passes that wouldn't change anything are skipped
+    fn is_active(self, parameters: &PostProcessParameters) -> bool {
+        match self {
+            Effect::Feedback => parameters.feedback > 0.0,
+            Effect::Kaleidoscope => parameters.kaleidoscope_segments >= 2.0,
+            Effect::Bloom => parameters.bloom > 0.0,
+            Effect::ChromaticAberration => parameters.chromatic_aberration != 0.0,
+            Effect::Lut => parameters.lut_mix > 0.0,
+            Effect::Vignette => parameters.vignette > 0.0,
+        }
+    }
+
+    fn fragment_shader(self) -> &'static str {
+        match self {
+            Effect::Feedback => FEEDBACK_SHADER,
+            Effect::Kaleidoscope => KALEIDOSCOPE_SHADER,
+            Effect::Bloom => BLOOM_SHADER,
+            Effect::ChromaticAberration => CHROMATIC_ABERRATION_SHADER,
+            Effect::Lut => LUT_SHADER,
+            Effect::Vignette => VIGNETTE_SHADER,
+        }
+    }
+}
+
+// Runs the displayed image through a chain of full screen passes before it
+// reaches the frame. The passes ping-pong between two textures the size of
+// the frame, and the final image is kept for the feedback pass of the next
+// frame.
+pub struct PostProcessChain {
+    vertex_buffer: glium::VertexBuffer<Vertex>,
+    effects: Vec<(Effect, glium::Program)>,
+    copy_program: glium::Program,
+    // A strip of lut_size squares of lut_size x lut_size texels
+    lut: glium::texture::Texture2d,
+    lut_size: u32,
+    targets: [glium::texture::Texture2d; 2],
+    previous: glium::texture::Texture2d,
+}
+
+impl PostProcessChain {
+    // The chain set up in the config, if it has any effects
+    pub fn from_config(display: &dyn Facade, app_config: &AppConfig) -> Option<Self> {
+        let effects = Effect::chain_from_names(app_config.post_process.as_deref());
+        (!effects.is_empty()).then(|| {
+            Self::new(
+                display,
+                effects,
+                app_config.post_process_lut.as_deref().map(Path::new),
+            )
+            .unwrap()
+        })
+    }
+
+    pub fn new(
+        display: &dyn Facade,
+        effects: Vec<Effect>,
+        lut_path: Option<&Path>,
+    ) -> Result<Self, Box<dyn Error>> {
+        let vertex_buffer = glium::VertexBuffer::new(
+            display,
+            &[
+                Vertex {
+                    a_vertex: [-1.0, -1.0],
+                },
+                Vertex {
+                    a_vertex: [1.0, -1.0],
+                },
+                Vertex {
+                    a_vertex: [1.0, 1.0],
+                },
+                Vertex {
+                    a_vertex: [-1.0, 1.0],
+                },
+            ],
+        )?;
+
+        println!("Post-processing with {effects:?}");
+        let effects = effects
+            .into_iter()
+            .map(|effect| {
+                Self::get_program(display, effect.fragment_shader())
+                    .map(|program| (effect, program))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let (lut, lut_size) = match lut_path {
+            Some(path) => Self::load_lut(display, path)?,
+            None => Self::identity_lut(display)?,
+        };
+
+        Ok(Self {
+            vertex_buffer,
+            effects,
+            copy_program: Self::get_program(display, COPY_SHADER)?,
+            lut,
+            lut_size,
+            targets: [
+                Self::empty_texture(display, 1, 1),
+                Self::empty_texture(display, 1, 1),
+            ],
+            previous: Self::empty_texture(display, 1, 1),
+        })
+    }
+
+    // draw_scene draws the image to be post-processed into the first target
+    pub fn draw(
+        &mut self,
+        frame: &mut impl Surface,
+        display: &dyn Facade,
+        parameters: PostProcessParameters,
+        draw_scene: impl FnOnce(&mut SimpleFrameBuffer),
+    ) {
+        let (width, height) = frame.get_dimensions();
+        if self.previous.dimensions() != (width, height) {
+            self.targets = [
+                Self::empty_texture(display, width, height),
+                Self::empty_texture(display, width, height),
+            ];
+            self.previous = Self::empty_texture(display, width, height);
+        }
+
+        {
+            let mut framebuffer = SimpleFrameBuffer::new(display, &self.targets[0]).unwrap();
+            framebuffer.clear_color(0.0, 0.0, 0.0, 1.0);
+            draw_scene(&mut framebuffer);
+        }
+
+        let mut source = 0;
+        for (effect, program) in &self.effects {
+            if !effect.is_active(&parameters) {
+                continue;
+            }
+            let target = 1 - source;
+            let mut framebuffer = SimpleFrameBuffer::new(display, &self.targets[target]).unwrap();
+            self.draw_pass(&mut framebuffer, program, &self.targets[source], parameters);
+            source = target;
+        }
+
+        self.draw_pass(frame, &self.copy_program, &self.targets[source], parameters);
+        std::mem::swap(&mut self.previous, &mut self.targets[source]);
+    }
+
+    fn draw_pass(
+        &self,
+        surface: &mut impl Surface,
+        program: &glium::Program,
+        texture: &glium::texture::Texture2d,
+        parameters: PostProcessParameters,
+    ) {
+        let (width, height) = texture.dimensions();
+        // Every pass gets every uniform, the ones a program doesn't declare
+        // are ignored
+        let uniforms = uniform! {
+            u_texture: Self::sampler(texture),
+            u_previous: Self::sampler(&self.previous),
+            u_lut: Self::sampler(&self.lut),
+            u_lut_size: self.lut_size as f32,
+            u_aspect: width as f32 / height as f32,
+            u_feedback: parameters.feedback,
+            u_feedback_zoom: parameters.feedback_zoom,
+            u_feedback_rotation: parameters.feedback_rotation,
+            u_kaleidoscope_segments: parameters.kaleidoscope_segments,
+            u_bloom: parameters.bloom,
+            u_bloom_threshold: parameters.bloom_threshold,
+            u_chromatic_aberration: parameters.chromatic_aberration,
+            u_lut_mix: parameters.lut_mix,
+            u_vignette: parameters.vignette,
+        };
+        surface
+            .draw(
+                &self.vertex_buffer,
+                glium::index::NoIndices(glium::index::PrimitiveType::TriangleFan),
+                program,
+                &uniforms,
+                &Default::default(),
+            )
+            .unwrap();
+    }
+
+    fn sampler(texture: &glium::texture::Texture2d) -> Sampler<'_, glium::texture::Texture2d> {
+        Sampler::new(texture)
+            .wrap_function(uniforms::SamplerWrapFunction::Clamp)
+            .minify_filter(uniforms::MinifySamplerFilter::Linear)
+            .magnify_filter(uniforms::MagnifySamplerFilter::Linear)
+    }
+
+    // The LUT is a strip of squares like the ones most grading tools export,
+    // e.g. 256x16 for 16 levels per channel. Red increases to the right within
+    // a square, green increases down and blue from one square to the next.
+    fn load_lut(
+        display: &dyn Facade,
+        path: &Path,
+    ) -> Result<(glium::texture::Texture2d, u32), Box<dyn Error>> {
+        let image = image::open(path)?.to_rgba8();
+        let (width, height) = image.dimensions();
+        if width != height * height {
+            return Err(format!(
+                "A LUT has to be a strip of {height} squares of {height}x{height}, not {width}x{height}"
+            )
+            .into());
+        }
+        // Not flipping the rows means green = 0 ends up at the bottom of the
+        // texture, which is where the shader looks for it
+        let texture = glium::texture::Texture2d::new(
+            display,
+            glium::texture::RawImage2d::from_raw_rgba(image.into_raw(), (width, height)),
+        )?;
+        println!("Loaded LUT from {}", path.display());
+        Ok((texture, height))
+    }
+
+    // Two levels per channel, which linear filtering turns into every color
+    fn identity_lut(
+        display: &dyn Facade,
+    ) -> Result<(glium::texture::Texture2d, u32), Box<dyn Error>> {
+        let level = |value: u32| (value * 255) as u8;
+        let mut data = Vec::with_capacity(4 * 2 * 4);
+        for green in 0..2 {
+            for x in 0..4 {
+                data.extend([level(x % 2), level(green), level(x / 2), 255]);
+            }
+        }
+        let texture = glium::texture::Texture2d::new(
+            display,
+            glium::texture::RawImage2d::from_raw_rgba(data, (4, 2)),
+        )?;
+        Ok((texture, 2))
+    }
+
+    fn empty_texture(display: &dyn Facade, width: u32, height: u32) -> glium::texture::Texture2d {
+        glium::texture::Texture2d::empty_with_format(
+            display,
+            glium::texture::UncompressedFloatFormat::F32F32F32F32,
+            glium::texture::MipmapsOption::NoMipmap,
+            width,
+            height,
+        )
+        .unwrap()
+    }
+
+    fn get_program(
+        display: &dyn Facade,
+        fragment_shader_src: &str,
+    ) -> Result<glium::Program, glium::ProgramCreationError> {
+        glium::Program::from_source(display, VERTEX_SHADER, fragment_shader_src, None)
+    }
+}
+
+const VERTEX_SHADER: &str = r#"
+        #version 140
+        attribute vec2 a_vertex;
+
+        varying vec2 v_texcoord;
+
+        void main(void) {
+            gl_Position = vec4(a_vertex.x, a_vertex.y, 0.0, 1.0);
+            v_texcoord = (a_vertex + 1.0) / 2.0;
+        }
+    "#;
+
+const COPY_SHADER: &str = r#"
+        #version 140
+        precision highp float;
+        uniform sampler2D u_texture;
+
+        varying vec2 v_texcoord;
+
+        void main() {
+            gl_FragColor = texture2D(u_texture, v_texcoord);
+        }
+    "#;
+
+const FEEDBACK_SHADER: &str = r#"
+        #version 140
+        precision highp float;
+        uniform sampler2D u_texture;
+        uniform sampler2D u_previous; // The previous frame as it was displayed
+        uniform float u_aspect;
+        uniform float u_feedback;
+        uniform float u_feedback_zoom;
+        uniform float u_feedback_rotation;
+
+        varying vec2 v_texcoord;
+
+        void main() {
+            // Zoom and rotate around the center without stretching
+            vec2 centred = (v_texcoord - 0.5) * vec2(u_aspect, 1.0);
+            float c = cos(u_feedback_rotation);
+            float s = sin(u_feedback_rotation);
+            centred = mat2(c, s, -s, c) * centred / (1.0 + u_feedback_zoom);
+            vec2 previous_texcoord = centred / vec2(u_aspect, 1.0) + 0.5;
+
+            vec4 color = texture2D(u_texture, v_texcoord);
+            vec4 previous = texture2D(u_previous, previous_texcoord);
+            gl_FragColor = max(color, previous * u_feedback);
+        }
+    "#;
+
+const KALEIDOSCOPE_SHADER: &str = r#"
+        #version 140
+        precision highp float;
+        uniform sampler2D u_texture;
+        uniform float u_aspect;
+        uniform float u_kaleidoscope_segments;
+
+        varying vec2 v_texcoord;
+
+        const float PI = 3.14159265;
+
+        void main() {
+            vec2 centred = (v_texcoord - 0.5) * vec2(u_aspect, 1.0);
+            float segment = 2.0 * PI / floor(u_kaleidoscope_segments);
+            float angle = mod(atan(centred.y, centred.x), segment);
+            // Mirror the second half of every segment so the edges line up
+            angle = min(angle, segment - angle);
+            vec2 folded = vec2(cos(angle), sin(angle)) * length(centred);
+            gl_FragColor = texture2D(u_texture, folded / vec2(u_aspect, 1.0) + 0.5);
+        }
+    "#;
+
+const BLOOM_SHADER: &str = r#"
+        #version 140
+        precision highp float;
+        uniform sampler2D u_texture;
+        uniform float u_aspect;
+        uniform float u_bloom;
+        uniform float u_bloom_threshold;
+
+        varying vec2 v_texcoord;
+
+        const float DIRECTIONS = 12.0;
+        const float RINGS = 4.0;
+        const float RADIUS = 0.02; // As a fraction of the height
+
+        void main() {
+            vec4 color = texture2D(u_texture, v_texcoord);
+
+            // Blur only what's brighter than the threshold
+            vec3 glow = vec3(0.0);
+            float count = 0.0;
+            for (float d = 0.0; d < 6.283; d += 6.283 / DIRECTIONS) {
+                for (float ring = 1.0; ring <= RINGS; ring += 1.0) {
+                    vec2 offset = vec2(cos(d) / u_aspect, sin(d)) * RADIUS * ring / RINGS;
+                    vec3 texel = texture2D(u_texture, v_texcoord + offset).rgb;
+                    glow += max(texel - u_bloom_threshold, 0.0);
+                    count += 1.0;
+                }
+            }
+
+            gl_FragColor = vec4(color.rgb + glow / count * u_bloom, color.a);
+        }
+    "#;
+
+const CHROMATIC_ABERRATION_SHADER: &str = r#"
+        #version 140
+        precision highp float;
+        uniform sampler2D u_texture;
+        uniform float u_chromatic_aberration;
+
+        varying vec2 v_texcoord;
+
+        void main() {
+            vec2 offset = (v_texcoord - 0.5) * u_chromatic_aberration;
+            vec4 color = texture2D(u_texture, v_texcoord);
+            float red = texture2D(u_texture, v_texcoord + offset).r;
+            float blue = texture2D(u_texture, v_texcoord - offset).b;
+            gl_FragColor = vec4(red, color.g, blue, color.a);
+        }
+    "#;
+
+const LUT_SHADER: &str = r#"
+        #version 140
+        precision highp float;
+        uniform sampler2D u_texture;
+        uniform sampler2D u_lut;
+        uniform float u_lut_size;
+        uniform float u_lut_mix;
+
+        varying vec2 v_texcoord;
+
+        // Red and green are filtered by the sampler, blue is interpolated
+        // between two neighbouring squares
+        vec3 grade(vec3 color) {
+            vec3 scaled = clamp(color, 0.0, 1.0) * (u_lut_size - 1.0);
+            float square = floor(scaled.b);
+            float next_square = min(square + 1.0, u_lut_size - 1.0);
+            float y = (scaled.g + 0.5) / u_lut_size;
+            vec2 low = vec2((square * u_lut_size + scaled.r + 0.5) / (u_lut_size * u_lut_size), y);
+            vec2 high = vec2((next_square * u_lut_size + scaled.r + 0.5) / (u_lut_size * u_lut_size), y);
+            return mix(texture2D(u_lut, low).rgb, texture2D(u_lut, high).rgb, scaled.b - square);
+        }
+
+        void main() {
+            vec4 color = texture2D(u_texture, v_texcoord);
+            gl_FragColor = vec4(mix(color.rgb, grade(color.rgb), u_lut_mix), color.a);
+        }
+    "#;
+
+const VIGNETTE_SHADER: &str = r#"
+        #version 140
+        precision highp float;
+        uniform sampler2D u_texture;
+        uniform float u_aspect;
+        uniform float u_vignette;
+
+        varying vec2 v_texcoord;
+
+        void main() {
+            vec4 color = texture2D(u_texture, v_texcoord);
+            // 0 in the center and 1 in the corners
+            vec2 centred = (v_texcoord - 0.5) * vec2(u_aspect, 1.0);
+            float distance = length(centred) / length(vec2(u_aspect, 1.0) * 0.5);
+            gl_FragColor = vec4(color.rgb * (1.0 - u_vignette * smoothstep(0.4, 1.0, distance)), color.a);
+        }
+    "#;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chains_keep_the_given_order() {
+        assert_eq!(Effect::chain_from_names(None), Effect::DEFAULT_CHAIN);
+        assert_eq!(
+            Effect::chain_from_names(Some("bloom, kaleidoscope")),
+            [Effect::Bloom, Effect::Kaleidoscope]
+        );
+        assert!(Effect::chain_from_names(Some("")).is_empty());
+    }
+
+    #[test]
+    #[should_panic(expected = "Unknown post-process effect: blur")]
+    fn unknown_effects_are_rejected() {
+        Effect::chain_from_names(Some("bloom,blur"));
+    }
+
+    #[test]
+    fn effects_at_zero_are_skipped() {
+        let mut parameters = PostProcessParameters::default();
+        assert!(!Effect::DEFAULT_CHAIN
+            .iter()
+            .any(|effect| effect.is_active(&parameters)));
+
+        parameters.kaleidoscope_segments = 1.0;
+        parameters.chromatic_aberration = -0.1;
+        assert!(!Effect::Kaleidoscope.is_active(&parameters));
+        assert!(Effect::ChromaticAberration.is_active(&parameters));
+    }
+}
@@ -0,0 +1,212 @@
+use crate::palette::{Palette, PALETTE_SIZE};
+use crate::post_process::PostProcessChain;
+use crate::preset::PostProcessParameters;
+use crate::simulation::{DisplayMapping, SimulationBackend};
+use glium::backend::Facade;
+use glium::uniforms::{self, Sampler};
+use glium::{implement_vertex, uniform, Surface};
+use std::cell::{Cell, RefCell};
+
+#[derive(Copy, Clone)]
+struct Vertex {
+    a_vertex: [f32; 2],
+}
+
+implement_vertex!(Vertex, a_vertex);
+
+// Draws the trail map of any SimulationBackend to a glium surface, scaling it
+// to fill the surface. Backends that don't run on the OpenGL context have
+// their trails uploaded into a texture first.
+pub struct Presenter {
+    vertex_buffer: glium::VertexBuffer<Vertex>,
+    program: glium::Program,
+    post_process: Option<RefCell<PostProcessChain>>,
+    palette_texture: glium::texture::Texture2d,
+    // What's in palette_texture, it's only uploaded again when this changes
+    uploaded_palette: Cell<Option<Palette>>,
+}
+
+impl Presenter {
+    pub fn new(display: &dyn Facade, post_process: Option<PostProcessChain>) -> Self {
+        let vertex_buffer = glium::VertexBuffer::new(
+            display,
+            &[
+                Vertex {
+                    a_vertex: [-1.0, -1.0],
+                },
+                Vertex {
+                    a_vertex: [1.0, -1.0],
+                },
+                Vertex {
+                    a_vertex: [1.0, 1.0],
+                },
+                Vertex {
+                    a_vertex: [-1.0, 1.0],
+                },
+            ],
+        )
+        .unwrap();
+
+        Self {
+            vertex_buffer,
+            program: Self::get_program(display),
+            post_process: post_process.map(RefCell::new),
+            palette_texture: glium::texture::Texture2d::empty_with_format(
+                display,
+                glium::texture::UncompressedFloatFormat::F32F32F32,
+                glium::texture::MipmapsOption::NoMipmap,
+                PALETTE_SIZE as u32,
+                1,
+            )
+            .unwrap(),
+            uploaded_palette: Cell::new(None),
+        }
+    }
+
+    pub fn draw(
+        &self,
+        frame: &mut impl Surface,
+        display: &dyn Facade,
+        backend: &dyn SimulationBackend,
+        display_mapping: DisplayMapping,
+        post_process: PostProcessParameters,
+    ) {
+        match backend.trail_texture() {
+            Some(texture) => self.present(frame, display, &texture, display_mapping, post_process),
+            None => {
+                let (width, height) = backend.size();
+                let texture = glium::texture::Texture2d::with_format(
+                    display,
+                    glium::texture::RawImage2d::from_raw_rgba(
+                        backend.read_trails(),
+                        (width, height),
+                    ),
+                    glium::texture::UncompressedFloatFormat::F32F32F32F32,
+                    glium::texture::MipmapsOption::NoMipmap,
+                )
+                .unwrap();
+                self.present(frame, display, &texture, display_mapping, post_process);
+            }
+        }
+    }
+
+    // Straight to the surface, or through the post-processing chain first
+    fn present(
+        &self,
+        frame: &mut impl Surface,
+        display: &dyn Facade,
+        texture: &glium::texture::Texture2d,
+        display_mapping: DisplayMapping,
+        post_process: PostProcessParameters,
+    ) {
+        self.upload_palette(display_mapping.palette);
+
+        match &self.post_process {
+            Some(chain) => chain
+                .borrow_mut()
+                .draw(frame, display, post_process, |target| {
+                    self.draw_texture(target, texture, &self.palette_texture, display_mapping)
+                }),
+            None => self.draw_texture(frame, texture, &self.palette_texture, display_mapping),
+        }
+    }
+
+    // Palettes only change along with the preset
+    fn upload_palette(&self, palette: Palette) {
+        if self.uploaded_palette.get() == Some(palette) {
+            return;
+        }
+        self.palette_texture.write(
+            glium::Rect {
+                left: 0,
+                bottom: 0,
+                width: PALETTE_SIZE as u32,
+                height: 1,
+            },
+            glium::texture::RawImage2d::from_raw_rgb(
+                palette.colors().concat(),
+                (PALETTE_SIZE as u32, 1),
+            ),
+        );
+        self.uploaded_palette.set(Some(palette));
+    }
+
+    fn draw_texture(
+        &self,
+        frame: &mut impl Surface,
+        texture: &glium::texture::Texture2d,
+        palette: &glium::texture::Texture2d,
+        display_mapping: DisplayMapping,
+    ) {
+        let uniforms = uniform! {
+            u_display_matrix: display_mapping.matrix,
+            u_trail_palette: display_mapping.trail_palette,
+            u_attribute_palette: display_mapping.attribute_palette,
+            u_palette: Sampler::new(palette)
+                .wrap_function(uniforms::SamplerWrapFunction::Clamp)
+                .minify_filter(uniforms::MinifySamplerFilter::Linear)
+                .magnify_filter(uniforms::MagnifySamplerFilter::Linear),
+            u_palette_size: PALETTE_SIZE as f32,
+            u_texture: Sampler::new(texture)
+                .wrap_function(uniforms::SamplerWrapFunction::Repeat)
+                .minify_filter(uniforms::MinifySamplerFilter::Linear)
+                .magnify_filter(uniforms::MagnifySamplerFilter::Linear),
+        };
+        frame
+            .draw(
+                &self.vertex_buffer,
+                glium::index::NoIndices(glium::index::PrimitiveType::TriangleFan),
+                &self.program,
+                &uniforms,
+                &Default::default(),
+            )
+            .unwrap();
+    }
+
+    fn get_program(display: &dyn Facade) -> glium::Program {
+        let vertex_shader_src = r#"
+        #version 140
+        attribute vec2 a_vertex;
+
+        varying vec2 v_texcoord;
+
+        void main(void) {
+            gl_Position = vec4(a_vertex.x, a_vertex.y, 0.0, 1.0);
+            v_texcoord = (a_vertex + 1.0) / 2.0;
+        }
+    "#;
+
+        let fragment_shader_src = r#"
+        #version 140
+        precision highp float;
+        uniform sampler2D u_texture;
+        uniform mat3 u_display_matrix; // Colors the species channels of the trail map
+        uniform float u_trail_palette; // How much of each palette color is shown, see DisplayMapping
+        uniform float u_attribute_palette;
+        uniform sampler2D u_palette;
+        uniform float u_palette_size;
+
+        varying vec2 v_texcoord;
+
+        // Between the centers of the first and last texel, so the sampler
+        // interpolates between neighbouring colors
+        vec3 palette(float index) {
+            float x = (clamp(index, 0.0, 1.0) * (u_palette_size - 1.0) + 0.5) / u_palette_size;
+            return texture2D(u_palette, vec2(x, 0.5)).rgb;
+        }
+
+        void main() {
+            vec4 trails = texture2D(u_texture, v_texcoord);
+            float intensity = clamp(trails.b, 0.0, 1.0);
+            // The palette strategies leave the index times the trail strength in red
+            float index = trails.r / max(trails.b, 0.0001);
+            vec3 color = u_display_matrix * trails.rgb * (1.0 - u_trail_palette - u_attribute_palette)
+                + palette(intensity) * u_trail_palette
+                + palette(index) * intensity * u_attribute_palette;
+            gl_FragColor = vec4(color, trails.a);
+        }
+    "#;
+
+        glium::Program::from_source(display, vertex_shader_src, fragment_shader_src, None).unwrap()
+    }
+}
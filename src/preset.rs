@@ -1,10 +1,16 @@
+use crate::palette::Palette;
+use crate::rng;
 use lerp::Lerp;
 use rand::{
     distributions::{Distribution, Standard},
     Rng,
 };
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
 
-#[derive(Lerp, PartialEq, Debug, Copy, Clone)]
+#[derive(Lerp, PartialEq, Debug, Copy, Clone, Serialize, Deserialize)]
 pub struct Preset {
     pub initial_parameters: InitialParameters,
     // Vertex Shader Uniforms
@@ -14,20 +20,54 @@ pub struct Preset {
     pub constant_steer_factor: f32,
     pub trail_strength: f32,
     pub search_radius: f32,
+    // Older preset files were saved before the sensors could be configured,
+    // they get the original three sensors 0.2 radians apart
+    #[serde(default = "default_search_angle")]
+    pub search_angle: f32,
+    #[lerp(f32)]
+    #[serde(default)]
+    pub sensor_count: SensorCount,
+    #[lerp(f32)]
+    #[serde(default)]
+    pub turn_strategy: TurnStrategy,
     #[lerp(f32)]
     pub wall_strategy: WallStrategy,
     #[lerp(f32)]
     pub color_strategy: ColorStrategy,
+    // Only set on presets made by Preset::blend
+    #[lerp(skip)]
+    #[serde(skip)]
+    pub color_blend: ColorBlend,
+    // Used by the palette color strategies
+    #[lerp(f32)]
+    #[serde(default)]
+    pub palette: Palette,
 
     // Fragment Shader Uniforms
     pub fade_speed: f32,
     pub blurring: f32,
 
+    #[serde(default)]
+    pub post_process: PostProcessParameters,
+
     #[lerp(skip)]
+    #[serde(skip)]
     pub u_time: f32,
 }
 
-#[derive(Copy, Clone, PartialEq, Debug)]
+// Enum fields can't be in between two values, so they switch over at this
+// point of a transition. Color strategies are mixed instead, see ColorBlend.
+pub const SWITCH_OVER_POINT: f32 = 0.5;
+
+fn switch_over<T>(from: T, to: T, t: f32) -> T {
+    if t < SWITCH_OVER_POINT {
+        from
+    } else {
+        to
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Debug, Serialize, Deserialize)]
 pub enum StartingArrangement {
     Origin = 0,
     Random = 1,
@@ -36,15 +76,7 @@ pub enum StartingArrangement {
 
 impl Lerp<f32> for StartingArrangement {
     fn lerp(self, other: Self, t: f32) -> Self {
-        let a = self as u32 as f32;
-        let b = other as u32 as f32;
-        let result = a.lerp(b, t);
-        match result.round() as u32 {
-            0 => StartingArrangement::Origin,
-            1 => StartingArrangement::Random,
-            2 => StartingArrangement::Ring,
-            n => panic!("Invalid StartingArrangement: {n}"),
-        }
+        switch_over(self, other, t)
     }
 }
 
@@ -58,7 +90,7 @@ impl Distribution<StartingArrangement> for Standard {
     }
 }
 
-#[derive(Copy, Clone, PartialEq, Debug)]
+#[derive(Copy, Clone, PartialEq, Debug, Serialize, Deserialize)]
 pub enum WallStrategy {
     None = 0,
     Wrap = 1,
@@ -69,17 +101,7 @@ pub enum WallStrategy {
 
 impl Lerp<f32> for WallStrategy {
     fn lerp(self, other: Self, t: f32) -> Self {
-        let a = self as u32 as f32;
-        let b = other as u32 as f32;
-        let result = a.lerp(b, t);
-        match result.round() as u32 {
-            0 => WallStrategy::None,
-            1 => WallStrategy::Wrap,
-            2 => WallStrategy::Bounce,
-            3 => WallStrategy::BounceRandom,
-            4 => WallStrategy::SlowAndReverse,
-            n => panic!("Invalid WallStrategy: {n}"),
-        }
+        switch_over(self, other, t)
     }
 }
 
@@ -87,17 +109,15 @@ impl Distribution<WallStrategy> for Standard {
     fn sample<R: Rng + ?Sized>(&self, rng: &mut R) -> WallStrategy {
         // TODO: Fix mirror and put it back in rotation
         match rng.gen_range(1..=4) {
-            0 => WallStrategy::None,
             1 => WallStrategy::Wrap,
             2 => WallStrategy::Bounce,
             3 => WallStrategy::BounceRandom,
-            4 => WallStrategy::SlowAndReverse,
-            n => panic!("Invalid WallStrategy: {n}"),
+            _ => WallStrategy::SlowAndReverse,
         }
     }
 }
 
-#[derive(Copy, Clone, PartialEq, Debug)]
+#[derive(Copy, Clone, PartialEq, Debug, Serialize, Deserialize)]
 pub enum ColorStrategy {
     Direction = 0,
     Speed = 1,
@@ -106,29 +126,51 @@ pub enum ColorStrategy {
     Hue = 4,
     Distance = 5,
     Time = 6,
+    // The palette strategies color the trail map through the preset's palette
+    // when it's displayed, indexed by how strong the trails are
+    PaletteTrail = 7,
+    // or by the direction or speed of the agents that left them
+    PaletteDirection = 8,
+    PaletteSpeed = 9,
+}
+
+// The color strategy being blended towards and how far along the blend is,
+// the backends compute the colors of both strategies and mix them
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct ColorBlend {
+    pub target: ColorStrategy,
+    pub amount: f32,
+}
+
+impl Default for ColorBlend {
+    fn default() -> Self {
+        ColorBlend {
+            target: ColorStrategy::Direction,
+            amount: 0.0,
+        }
+    }
+}
+
+impl ColorStrategy {
+    pub fn is_palette(self) -> bool {
+        matches!(
+            self,
+            ColorStrategy::PaletteTrail
+                | ColorStrategy::PaletteDirection
+                | ColorStrategy::PaletteSpeed
+        )
+    }
 }
 
 impl Lerp<f32> for ColorStrategy {
     fn lerp(self, other: Self, t: f32) -> Self {
-        let a = self as u32 as f32;
-        let b = other as u32 as f32;
-        let result = a.lerp(b, t);
-        match result.round() as u32 {
-            0 => ColorStrategy::Direction,
-            1 => ColorStrategy::Speed,
-            2 => ColorStrategy::Position,
-            3 => ColorStrategy::Grey,
-            4 => ColorStrategy::Hue,
-            5 => ColorStrategy::Distance,
-            6 => ColorStrategy::Time,
-            n => panic!("Invalid ColorStrategy: {n}"),
-        }
+        switch_over(self, other, t)
     }
 }
 
 impl Distribution<ColorStrategy> for Standard {
     fn sample<R: Rng + ?Sized>(&self, rng: &mut R) -> ColorStrategy {
-        match rng.gen_range(0..=6) {
+        match rng.gen_range(0..=9) {
             0 => ColorStrategy::Direction,
             1 => ColorStrategy::Speed,
             2 => ColorStrategy::Position,
@@ -136,7 +178,83 @@ impl Distribution<ColorStrategy> for Standard {
             4 => ColorStrategy::Hue,
             5 => ColorStrategy::Distance,
             6 => ColorStrategy::Time,
-            n => panic!("Invalid ColorStrategy: {n}"),
+            7 => ColorStrategy::PaletteTrail,
+            8 => ColorStrategy::PaletteDirection,
+            _ => ColorStrategy::PaletteSpeed,
+        }
+    }
+}
+
+fn default_search_angle() -> f32 {
+    0.2
+}
+
+// The number of sensors spread evenly from search_angle on the left to
+// search_angle on the right of an agent's direction
+#[derive(Copy, Clone, PartialEq, Debug, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct SensorCount(u32);
+
+impl SensorCount {
+    pub const MIN: u32 = 3;
+    // Every sensor is another trail map read per agent per step
+    pub const MAX: u32 = 9;
+
+    pub fn new(count: u32) -> Self {
+        SensorCount(count.clamp(Self::MIN, Self::MAX))
+    }
+
+    pub fn get(self) -> u32 {
+        self.0.clamp(Self::MIN, Self::MAX)
+    }
+}
+
+impl Default for SensorCount {
+    fn default() -> Self {
+        SensorCount(Self::MIN)
+    }
+}
+
+impl Lerp<f32> for SensorCount {
+    fn lerp(self, other: Self, t: f32) -> Self {
+        let a = self.get() as f32;
+        let b = other.get() as f32;
+        SensorCount::new(a.lerp(b, t).round() as u32)
+    }
+}
+
+impl Distribution<SensorCount> for Standard {
+    fn sample<R: Rng + ?Sized>(&self, rng: &mut R) -> SensorCount {
+        SensorCount::new(rng.gen_range(SensorCount::MIN..=SensorCount::MAX))
+    }
+}
+
+// How an agent turns based on its sensor readings
+#[derive(Copy, Clone, PartialEq, Debug, Default, Serialize, Deserialize)]
+pub enum TurnStrategy {
+    // Turn by the steer amount towards whichever side senses the most, unless
+    // the middle senses more than either side
+    #[default]
+    Steer = 0,
+    // Turn straight to the strongest sensor
+    Strongest = 1,
+    // Turn by the steer amount scaled by the average sensor angle, weighted by
+    // what each sensor senses
+    Weighted = 2,
+}
+
+impl Lerp<f32> for TurnStrategy {
+    fn lerp(self, other: Self, t: f32) -> Self {
+        switch_over(self, other, t)
+    }
+}
+
+impl Distribution<TurnStrategy> for Standard {
+    fn sample<R: Rng + ?Sized>(&self, rng: &mut R) -> TurnStrategy {
+        match rng.gen_range(0..=2) {
+            0 => TurnStrategy::Steer,
+            1 => TurnStrategy::Strongest,
+            _ => TurnStrategy::Weighted,
         }
     }
 }
@@ -173,23 +291,21 @@ impl Distribution<PresetName> for Standard {
 }
 
 impl PresetName {
-    pub fn from_u32(value: u32) -> PresetName {
-        match value {
-            1 => PresetName::GreenSlime,
-            2 => PresetName::CollapsingBubble,
-            3 => PresetName::SlimeRing,
-            4 => PresetName::ShiftingWeb,
-            5 => PresetName::Waves,
-            6 => PresetName::Flower,
-            7 => PresetName::ChristmasChaos,
-            8 => PresetName::Explode,
-            9 => PresetName::Tartan,
-            _ => PresetName::Globe,
-        }
-    }
+    pub const ALL: [PresetName; 10] = [
+        PresetName::GreenSlime,
+        PresetName::CollapsingBubble,
+        PresetName::SlimeRing,
+        PresetName::ShiftingWeb,
+        PresetName::Waves,
+        PresetName::Flower,
+        PresetName::ChristmasChaos,
+        PresetName::Explode,
+        PresetName::Tartan,
+        PresetName::Globe,
+    ];
 }
 
-#[derive(Lerp, PartialEq, Debug, Copy, Clone)]
+#[derive(Lerp, PartialEq, Debug, Copy, Clone, Serialize, Deserialize)]
 pub struct InitialParameters {
     // Initial config
     #[lerp(skip)]
@@ -200,6 +316,241 @@ pub struct InitialParameters {
     pub starting_speed_spread: f32,
 }
 
+// Effects applied to the displayed image only, the trail map never sees them.
+// Everything at zero leaves the image as it is.
+#[derive(Lerp, PartialEq, Debug, Default, Copy, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PostProcessParameters {
+    // How much of the previous displayed frame shows through
+    pub feedback: f32,
+    // How much the previous frame grows every frame, negative shrinks it
+    pub feedback_zoom: f32,
+    // Radians the previous frame turns every frame
+    pub feedback_rotation: f32,
+    // Number of mirrored wedges, below 2 is off
+    pub kaleidoscope_segments: f32,
+    pub bloom: f32,
+    // Only brighter colors glow
+    pub bloom_threshold: f32,
+    // How far the red and blue channels are pulled apart towards the edges
+    pub chromatic_aberration: f32,
+    // Mix between the original colors and the color grading LUT
+    pub lut_mix: f32,
+    pub vignette: f32,
+}
+
+// The numeric fields of a preset, so they can be addressed by name from
+// modulation routes and controllers
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PresetParameter {
+    SpeedMultiplier,
+    PointSize,
+    RandomSteerFactor,
+    ConstantSteerFactor,
+    TrailStrength,
+    SearchRadius,
+    SearchAngle,
+    SensorCount,
+    FadeSpeed,
+    Blurring,
+    Feedback,
+    FeedbackZoom,
+    FeedbackRotation,
+    KaleidoscopeSegments,
+    Bloom,
+    BloomThreshold,
+    ChromaticAberration,
+    LutMix,
+    Vignette,
+}
+
+impl PresetParameter {
+    pub const ALL: [PresetParameter; 19] = [
+        PresetParameter::SpeedMultiplier,
+        PresetParameter::PointSize,
+        PresetParameter::RandomSteerFactor,
+        PresetParameter::ConstantSteerFactor,
+        PresetParameter::TrailStrength,
+        PresetParameter::SearchRadius,
+        PresetParameter::SearchAngle,
+        PresetParameter::SensorCount,
+        PresetParameter::FadeSpeed,
+        PresetParameter::Blurring,
+        PresetParameter::Feedback,
+        PresetParameter::FeedbackZoom,
+        PresetParameter::FeedbackRotation,
+        PresetParameter::KaleidoscopeSegments,
+        PresetParameter::Bloom,
+        PresetParameter::BloomThreshold,
+        PresetParameter::ChromaticAberration,
+        PresetParameter::LutMix,
+        PresetParameter::Vignette,
+    ];
+
+    // The same as the field name
+    pub fn name(self) -> &'static str {
+        match self {
+            PresetParameter::SpeedMultiplier => "speed_multiplier",
+            PresetParameter::PointSize => "point_size",
+            PresetParameter::RandomSteerFactor => "random_steer_factor",
+            PresetParameter::ConstantSteerFactor => "constant_steer_factor",
+            PresetParameter::TrailStrength => "trail_strength",
+            PresetParameter::SearchRadius => "search_radius",
+            PresetParameter::SearchAngle => "search_angle",
+            PresetParameter::SensorCount => "sensor_count",
+            PresetParameter::FadeSpeed => "fade_speed",
+            PresetParameter::Blurring => "blurring",
+            PresetParameter::Feedback => "feedback",
+            PresetParameter::FeedbackZoom => "feedback_zoom",
+            PresetParameter::FeedbackRotation => "feedback_rotation",
+            PresetParameter::KaleidoscopeSegments => "kaleidoscope_segments",
+            PresetParameter::Bloom => "bloom",
+            PresetParameter::BloomThreshold => "bloom_threshold",
+            PresetParameter::ChromaticAberration => "chromatic_aberration",
+            PresetParameter::LutMix => "lut_mix",
+            PresetParameter::Vignette => "vignette",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<PresetParameter> {
+        PresetParameter::ALL
+            .into_iter()
+            .find(|parameter| parameter.name() == name)
+    }
+
+    // Automation leaves these alone, so effects stay off unless a preset or
+    // controller turns them on
+    pub fn is_post_process(self) -> bool {
+        matches!(
+            self,
+            PresetParameter::Feedback
+                | PresetParameter::FeedbackZoom
+                | PresetParameter::FeedbackRotation
+                | PresetParameter::KaleidoscopeSegments
+                | PresetParameter::Bloom
+                | PresetParameter::BloomThreshold
+                | PresetParameter::ChromaticAberration
+                | PresetParameter::LutMix
+                | PresetParameter::Vignette
+        )
+    }
+
+    // The range random presets are picked from, which is also what
+    // automation keeps them within
+    pub fn range(self) -> (f32, f32) {
+        match self {
+            PresetParameter::SpeedMultiplier => (0.0, 2.0),
+            PresetParameter::PointSize => (0.0, 5.0),
+            PresetParameter::RandomSteerFactor => (0.0, 0.1),
+            PresetParameter::ConstantSteerFactor => (0.0, 5.0),
+            PresetParameter::TrailStrength => (0.0, 1.0),
+            PresetParameter::SearchRadius => (0.0, 0.1),
+            PresetParameter::SearchAngle => (0.0, 1.0),
+            PresetParameter::SensorCount => (SensorCount::MIN as f32, SensorCount::MAX as f32),
+            PresetParameter::FadeSpeed => (0.0, 0.1),
+            PresetParameter::Blurring => (0.0, 1.0),
+            PresetParameter::Feedback => (0.0, 0.95),
+            PresetParameter::FeedbackZoom => (-0.02, 0.02),
+            PresetParameter::FeedbackRotation => (-0.02, 0.02),
+            PresetParameter::KaleidoscopeSegments => (0.0, 8.0),
+            PresetParameter::Bloom => (0.0, 1.5),
+            PresetParameter::BloomThreshold => (0.0, 1.0),
+            PresetParameter::ChromaticAberration => (0.0, 0.03),
+            PresetParameter::LutMix => (0.0, 1.0),
+            PresetParameter::Vignette => (0.0, 1.0),
+        }
+    }
+
+    pub fn get(self, preset: &Preset) -> f32 {
+        match self {
+            PresetParameter::SpeedMultiplier => preset.speed_multiplier,
+            PresetParameter::PointSize => preset.point_size,
+            PresetParameter::RandomSteerFactor => preset.random_steer_factor,
+            PresetParameter::ConstantSteerFactor => preset.constant_steer_factor,
+            PresetParameter::TrailStrength => preset.trail_strength,
+            PresetParameter::SearchRadius => preset.search_radius,
+            PresetParameter::SearchAngle => preset.search_angle,
+            PresetParameter::SensorCount => preset.sensor_count.get() as f32,
+            PresetParameter::FadeSpeed => preset.fade_speed,
+            PresetParameter::Blurring => preset.blurring,
+            PresetParameter::Feedback => preset.post_process.feedback,
+            PresetParameter::FeedbackZoom => preset.post_process.feedback_zoom,
+            PresetParameter::FeedbackRotation => preset.post_process.feedback_rotation,
+            PresetParameter::KaleidoscopeSegments => preset.post_process.kaleidoscope_segments,
+            PresetParameter::Bloom => preset.post_process.bloom,
+            PresetParameter::BloomThreshold => preset.post_process.bloom_threshold,
+            PresetParameter::ChromaticAberration => preset.post_process.chromatic_aberration,
+            PresetParameter::LutMix => preset.post_process.lut_mix,
+            PresetParameter::Vignette => preset.post_process.vignette,
+        }
+    }
+
+    pub fn set(self, preset: &mut Preset, value: f32) {
+        match self {
+            PresetParameter::SpeedMultiplier => preset.speed_multiplier = value,
+            PresetParameter::PointSize => preset.point_size = value,
+            PresetParameter::RandomSteerFactor => preset.random_steer_factor = value,
+            PresetParameter::ConstantSteerFactor => preset.constant_steer_factor = value,
+            PresetParameter::TrailStrength => preset.trail_strength = value,
+            PresetParameter::SearchRadius => preset.search_radius = value,
+            PresetParameter::SearchAngle => preset.search_angle = value,
+            PresetParameter::SensorCount => {
+                preset.sensor_count = SensorCount::new(value.round().max(0.0) as u32)
+            }
+            PresetParameter::FadeSpeed => preset.fade_speed = value,
+            PresetParameter::Blurring => preset.blurring = value,
+            PresetParameter::Feedback => preset.post_process.feedback = value,
+            PresetParameter::FeedbackZoom => preset.post_process.feedback_zoom = value,
+            PresetParameter::FeedbackRotation => preset.post_process.feedback_rotation = value,
+            PresetParameter::KaleidoscopeSegments => {
+                preset.post_process.kaleidoscope_segments = value
+            }
+            PresetParameter::Bloom => preset.post_process.bloom = value,
+            PresetParameter::BloomThreshold => preset.post_process.bloom_threshold = value,
+            PresetParameter::ChromaticAberration => {
+                preset.post_process.chromatic_aberration = value
+            }
+            PresetParameter::LutMix => preset.post_process.lut_mix = value,
+            PresetParameter::Vignette => preset.post_process.vignette = value,
+        }
+    }
+}
+
+// A new value for one of the fields of a preset that isn't a number
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum PresetField {
+    WallStrategy(WallStrategy),
+    ColorStrategy(ColorStrategy),
+    Palette(Palette),
+    // The initial parameters only show once the points are reset
+    NumberOfPoints(u32),
+    StartingArrangement(StartingArrangement),
+    AverageStartingSpeed(f32),
+    StartingSpeedSpread(f32),
+}
+
+impl PresetField {
+    pub fn set(self, preset: &mut Preset) {
+        let initial_parameters = &mut preset.initial_parameters;
+        match self {
+            PresetField::WallStrategy(value) => preset.wall_strategy = value,
+            PresetField::ColorStrategy(value) => preset.color_strategy = value,
+            PresetField::Palette(value) => preset.palette = value,
+            PresetField::NumberOfPoints(value) => initial_parameters.number_of_points = value,
+            PresetField::StartingArrangement(value) => {
+                initial_parameters.starting_arrangement = value
+            }
+            PresetField::AverageStartingSpeed(value) => {
+                initial_parameters.average_starting_speed = value
+            }
+            PresetField::StartingSpeedSpread(value) => {
+                initial_parameters.starting_speed_spread = value
+            }
+        }
+    }
+}
+
 impl Preset {
     pub fn new(preset_name: PresetName) -> Preset {
         println!("Creating preset: {:?}", preset_name);
@@ -217,11 +568,17 @@ impl Preset {
                 constant_steer_factor: 0.1,
                 trail_strength: 0.01,
                 search_radius: 0.01,
+                search_angle: 0.2,
+                sensor_count: SensorCount::default(),
+                turn_strategy: TurnStrategy::Steer,
                 wall_strategy: WallStrategy::Bounce,
                 color_strategy: ColorStrategy::Hue,
+                color_blend: ColorBlend::default(),
+                palette: Palette::default(),
 
                 fade_speed: 0.01,
                 blurring: 1.0,
+                post_process: PostProcessParameters::default(),
 
                 u_time: 0.0,
             },
@@ -239,11 +596,17 @@ impl Preset {
                 constant_steer_factor: 0.5,
                 trail_strength: 0.2,
                 search_radius: 0.1,
+                search_angle: 0.2,
+                sensor_count: SensorCount::default(),
+                turn_strategy: TurnStrategy::Steer,
                 wall_strategy: WallStrategy::Wrap,
                 color_strategy: ColorStrategy::Direction,
+                color_blend: ColorBlend::default(),
+                palette: Palette::default(),
 
                 fade_speed: 0.005,
                 blurring: 1.0,
+                post_process: PostProcessParameters::default(),
 
                 u_time: 0.0,
             },
@@ -261,11 +624,17 @@ impl Preset {
                 constant_steer_factor: 0.4,
                 trail_strength: 0.2,
                 search_radius: 0.01,
+                search_angle: 0.2,
+                sensor_count: SensorCount::default(),
+                turn_strategy: TurnStrategy::Steer,
                 wall_strategy: WallStrategy::Wrap,
                 color_strategy: ColorStrategy::Grey,
+                color_blend: ColorBlend::default(),
+                palette: Palette::default(),
 
                 fade_speed: 0.05,
                 blurring: 1.0,
+                post_process: PostProcessParameters::default(),
 
                 u_time: 0.0,
             },
@@ -283,11 +652,17 @@ impl Preset {
                 constant_steer_factor: 0.45,
                 trail_strength: 0.2,
                 search_radius: 0.05,
+                search_angle: 0.2,
+                sensor_count: SensorCount::default(),
+                turn_strategy: TurnStrategy::Steer,
                 wall_strategy: WallStrategy::Wrap,
                 color_strategy: ColorStrategy::Position,
+                color_blend: ColorBlend::default(),
+                palette: Palette::default(),
 
                 fade_speed: 0.07,
                 blurring: 1.0,
+                post_process: PostProcessParameters::default(),
 
                 u_time: 0.0,
             },
@@ -305,11 +680,17 @@ impl Preset {
                 constant_steer_factor: 0.07,
                 trail_strength: 0.1,
                 search_radius: 0.01,
+                search_angle: 0.2,
+                sensor_count: SensorCount::default(),
+                turn_strategy: TurnStrategy::Steer,
                 wall_strategy: WallStrategy::Bounce,
                 color_strategy: ColorStrategy::Direction,
+                color_blend: ColorBlend::default(),
+                palette: Palette::default(),
 
                 fade_speed: 0.04,
                 blurring: 1.0,
+                post_process: PostProcessParameters::default(),
 
                 u_time: 0.0,
             },
@@ -327,11 +708,17 @@ impl Preset {
                 constant_steer_factor: 0.04,
                 trail_strength: 0.5,
                 search_radius: 0.1,
+                search_angle: 0.2,
+                sensor_count: SensorCount::default(),
+                turn_strategy: TurnStrategy::Steer,
                 wall_strategy: WallStrategy::Bounce,
                 color_strategy: ColorStrategy::Direction,
+                color_blend: ColorBlend::default(),
+                palette: Palette::default(),
 
                 fade_speed: 0.02,
                 blurring: 1.0,
+                post_process: PostProcessParameters::default(),
 
                 u_time: 0.0,
             },
@@ -349,11 +736,17 @@ impl Preset {
                 constant_steer_factor: 4.0,
                 trail_strength: 0.2,
                 search_radius: 0.1,
+                search_angle: 0.2,
+                sensor_count: SensorCount::default(),
+                turn_strategy: TurnStrategy::Steer,
                 wall_strategy: WallStrategy::Wrap,
                 color_strategy: ColorStrategy::Direction,
+                color_blend: ColorBlend::default(),
+                palette: Palette::default(),
 
                 fade_speed: 0.02,
                 blurring: 1.0,
+                post_process: PostProcessParameters::default(),
 
                 u_time: 0.0,
             },
@@ -371,11 +764,17 @@ impl Preset {
                 constant_steer_factor: 0.1,
                 trail_strength: 0.2,
                 search_radius: 0.1,
+                search_angle: 0.2,
+                sensor_count: SensorCount::default(),
+                turn_strategy: TurnStrategy::Steer,
                 wall_strategy: WallStrategy::None,
                 color_strategy: ColorStrategy::Grey,
+                color_blend: ColorBlend::default(),
+                palette: Palette::default(),
 
                 fade_speed: 0.0,
                 blurring: 0.0,
+                post_process: PostProcessParameters::default(),
 
                 u_time: 0.0,
             },
@@ -393,11 +792,17 @@ impl Preset {
                 constant_steer_factor: 0.01,
                 trail_strength: 0.01,
                 search_radius: 0.1,
+                search_angle: 0.2,
+                sensor_count: SensorCount::default(),
+                turn_strategy: TurnStrategy::Steer,
                 wall_strategy: WallStrategy::Wrap,
                 color_strategy: ColorStrategy::Direction,
+                color_blend: ColorBlend::default(),
+                palette: Palette::default(),
 
                 fade_speed: 0.01,
                 blurring: 1.0,
+                post_process: PostProcessParameters::default(),
 
                 u_time: 0.0,
             },
@@ -415,17 +820,44 @@ impl Preset {
                 constant_steer_factor: 0.0,
                 trail_strength: 0.2,
                 search_radius: 0.01,
+                search_angle: 0.2,
+                sensor_count: SensorCount::default(),
+                turn_strategy: TurnStrategy::Steer,
                 wall_strategy: WallStrategy::Bounce,
                 color_strategy: ColorStrategy::Grey,
+                color_blend: ColorBlend::default(),
+                palette: Palette::default(),
 
                 fade_speed: 0.005,
                 blurring: 1.0,
+                post_process: PostProcessParameters::default(),
 
                 u_time: 0.0,
             },
         }
     }
 
+    // Like lerp, except the color strategy stays the one of this preset and
+    // the other preset's strategy is blended in through color_blend
+    pub fn blend(self, other: Preset, t: f32) -> Preset {
+        Preset {
+            color_strategy: self.color_strategy,
+            color_blend: ColorBlend {
+                target: other.color_strategy,
+                amount: t.clamp(0.0, 1.0),
+            },
+            ..self.lerp(other, t)
+        }
+    }
+
+    // Each color strategy in use and how much of it is shown
+    pub fn color_strategies(&self) -> [(ColorStrategy, f32); 2] {
+        [
+            (self.color_strategy, 1.0 - self.color_blend.amount),
+            (self.color_blend.target, self.color_blend.amount),
+        ]
+    }
+
     fn clamp(input: f32, min: f32, max: f32) -> f32 {
         if input < min {
             min
@@ -438,26 +870,115 @@ impl Preset {
 
     fn rand_clamp(input: f32, time_change: f32, min: f32, max: f32) -> f32 {
         let mut input = input;
-        input += rand::thread_rng().gen_range(-1.0..=1.0) * time_change * max / 2.0;
+        input += rng::with_rng(|rng| rng.gen_range(-1.0..=1.0)) * time_change * max / 2.0;
         Preset::clamp(input, min, max)
     }
 
     pub fn update(&mut self, u_time: f32) {
         let time_change = u_time - self.u_time;
 
-        self.speed_multiplier = Preset::rand_clamp(self.speed_multiplier, time_change, 0.0, 2.0);
-        self.point_size = Preset::rand_clamp(self.point_size, time_change, 0.0, 5.0);
-        self.random_steer_factor =
-            Preset::rand_clamp(self.random_steer_factor, time_change, 0.0, 0.1);
-        self.constant_steer_factor =
-            Preset::rand_clamp(self.constant_steer_factor, time_change, 0.0, 5.0);
-        self.trail_strength = Preset::rand_clamp(self.trail_strength, time_change, 0.0, 1.0);
-        self.search_radius = Preset::rand_clamp(self.search_radius, time_change, 0.0, 0.1);
-        self.fade_speed = Preset::rand_clamp(self.fade_speed, time_change, 0.0, 0.1);
-        self.blurring = Preset::rand_clamp(self.blurring, time_change, 0.0, 1.0);
+        for parameter in PresetParameter::ALL {
+            if parameter.is_post_process() {
+                continue;
+            }
+            let value = parameter.get(self);
+            match parameter {
+                // Sensors are only ever added or removed one at a time
+                PresetParameter::SensorCount => {
+                    if rng::with_rng(|rng| rng.gen_bool((time_change * 0.1).clamp(0.0, 1.0) as f64))
+                    {
+                        let step = rng::with_rng(|rng| rng.gen_range(-1..=1));
+                        parameter.set(self, value + step as f32);
+                    }
+                }
+                _ => {
+                    let (min, max) = parameter.range();
+                    parameter.set(self, Preset::rand_clamp(value, time_change, min, max));
+                }
+            }
+        }
 
         self.u_time = u_time;
     }
+
+    // The file format is picked from the extension, anything other
+    // than .json is treated as TOML
+    pub fn save_to_file(&self, path: impl AsRef<Path>) -> Result<(), Box<dyn Error>> {
+        let path = path.as_ref();
+        let contents = if is_json(path) {
+            serde_json::to_string_pretty(self)?
+        } else {
+            toml::to_string_pretty(self)?
+        };
+        fs::write(path, contents)?;
+        println!("Saved preset to {}", path.display());
+        Ok(())
+    }
+
+    pub fn load_from_file(path: impl AsRef<Path>) -> Result<Preset, Box<dyn Error>> {
+        let path = path.as_ref();
+        let contents = fs::read_to_string(path)?;
+        let preset = if is_json(path) {
+            serde_json::from_str(&contents)?
+        } else {
+            toml::from_str(&contents)?
+        };
+        println!("Loaded preset from {}", path.display());
+        Ok(preset)
+    }
+}
+
+// Files other than .json are TOML, for everything read and saved like presets
+pub(crate) fn is_json(path: &Path) -> bool {
+    path.extension()
+        .map(|extension| extension.eq_ignore_ascii_case("json"))
+        .unwrap_or(false)
+}
+
+// What newly saved presets are written as
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum PresetFormat {
+    Toml,
+    Json,
+}
+
+impl PresetFormat {
+    pub fn from_name(name: Option<&str>) -> PresetFormat {
+        match name {
+            None | Some("toml") => PresetFormat::Toml,
+            Some("json") => PresetFormat::Json,
+            Some(name) => panic!("Unknown preset format: {name}"),
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            PresetFormat::Toml => "toml",
+            PresetFormat::Json => "json",
+        }
+    }
+}
+
+pub const PRESET_FILE_PREFIX: &str = "slime_mould-preset-";
+
+// Finds the most recently dumped preset file in the given directory
+pub fn latest_preset_file(dir: impl AsRef<Path>) -> Option<PathBuf> {
+    fs::read_dir(dir)
+        .ok()?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| {
+            path.file_name()
+                .and_then(|name| name.to_str())
+                .map(|name| name.starts_with(PRESET_FILE_PREFIX))
+                .unwrap_or(false)
+        })
+        .filter_map(|path| {
+            let modified = fs::metadata(&path).ok()?.modified().ok()?;
+            Some((modified, path))
+        })
+        .max_by_key(|(modified, _)| *modified)
+        .map(|(_, path)| path)
 }
 
 impl Distribution<InitialParameters> for Standard {
@@ -481,11 +1002,121 @@ impl Distribution<Preset> for Standard {
             constant_steer_factor: rng.gen_range(0.0..=5.0),
             trail_strength: rng.gen_range(0.0..=1.0),
             search_radius: rng.gen_range(0.0..=0.1),
+            search_angle: rng.gen_range(0.05..=1.0),
+            sensor_count: rng.gen(),
+            turn_strategy: rng.gen(),
             wall_strategy: rng.gen(),
             color_strategy: rng.gen(),
+            color_blend: ColorBlend::default(),
+            palette: rng.gen(),
             fade_speed: rng.gen_range(0.0..=0.1),
             blurring: rng.gen_range(0.0..=1.0),
+            // Effects are only ever turned on on purpose
+            post_process: PostProcessParameters::default(),
             u_time: 0.0,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(format: PresetFormat) {
+        let preset: Preset = rand::random();
+        let path = std::env::temp_dir().join(format!(
+            "{PRESET_FILE_PREFIX}round-trip-{}.{}",
+            std::process::id(),
+            format.extension()
+        ));
+        preset.save_to_file(&path).unwrap();
+        let loaded = Preset::load_from_file(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(loaded, preset);
+    }
+
+    #[test]
+    fn presets_round_trip_through_toml() {
+        round_trip(PresetFormat::Toml);
+    }
+
+    #[test]
+    fn presets_round_trip_through_json() {
+        round_trip(PresetFormat::Json);
+    }
+
+    #[test]
+    fn old_preset_files_get_the_original_sensors() {
+        let mut value = toml::Value::try_from(Preset::new(PresetName::GreenSlime)).unwrap();
+        let table = value.as_table_mut().unwrap();
+        for key in ["search_angle", "sensor_count", "turn_strategy"] {
+            table.remove(key);
+        }
+        let preset: Preset = value.try_into().unwrap();
+        assert_eq!(preset.search_angle, 0.2);
+        assert_eq!(preset.sensor_count, SensorCount::new(3));
+        assert_eq!(preset.turn_strategy, TurnStrategy::Steer);
+    }
+
+    #[test]
+    fn enum_fields_switch_halfway_through_a_transition() {
+        let mut from = Preset::new(PresetName::GreenSlime);
+        from.wall_strategy = WallStrategy::Wrap;
+        from.color_strategy = ColorStrategy::Hue;
+        let mut to = from;
+        to.wall_strategy = WallStrategy::Bounce;
+        to.color_strategy = ColorStrategy::Grey;
+
+        assert_eq!(from.lerp(to, 0.49).wall_strategy, WallStrategy::Wrap);
+        assert_eq!(from.lerp(to, 0.5).wall_strategy, WallStrategy::Bounce);
+
+        let blended = from.blend(to, 0.75);
+        assert_eq!(blended.wall_strategy, WallStrategy::Bounce);
+        assert_eq!(
+            blended.color_strategies(),
+            [(ColorStrategy::Hue, 0.25), (ColorStrategy::Grey, 0.75)]
+        );
+    }
+
+    #[test]
+    fn automation_keeps_parameters_in_range() {
+        rng::seed(Some(42));
+        let mut preset = Preset::new(PresetName::GreenSlime);
+        preset.u_time = 0.0;
+        for step in 1..=100 {
+            preset.update(step as f32 * 5.0);
+        }
+        for parameter in PresetParameter::ALL {
+            let (min, max) = parameter.range();
+            let value = parameter.get(&preset);
+            if parameter.is_post_process() {
+                assert_eq!(value, 0.0, "{parameter:?} was automated");
+            } else {
+                assert!(
+                    (min..=max).contains(&value),
+                    "{parameter:?} is {value}, outside {min} to {max}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn sensor_counts_stay_in_range() {
+        assert_eq!(SensorCount::new(0).get(), SensorCount::MIN);
+        assert_eq!(SensorCount::new(100).get(), SensorCount::MAX);
+        let blended = SensorCount::new(3).lerp(SensorCount::new(9), 0.5);
+        assert_eq!(blended.get(), 6);
+    }
+
+    #[test]
+    fn preset_format_defaults_to_toml() {
+        assert_eq!(PresetFormat::from_name(None), PresetFormat::Toml);
+        assert_eq!(PresetFormat::from_name(Some("json")), PresetFormat::Json);
+    }
+
+    #[test]
+    #[should_panic(expected = "Unknown preset format: yaml")]
+    fn unknown_preset_formats_are_rejected() {
+        PresetFormat::from_name(Some("yaml"));
+    }
+}
@@ -0,0 +1,150 @@
+use crate::preset::{Preset, PresetName};
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+
+// Number of presets reachable from the number keys and a single row of pads
+pub const PRESET_BANK_SIZE: usize = 10;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum PresetId {
+    Index(usize),
+    Name(String),
+}
+
+pub struct PresetLibrary {
+    presets: Vec<(String, Preset)>,
+}
+
+impl PresetLibrary {
+    // The built-in presets always come first so their indices stay stable,
+    // followed by any .toml/.json files in preset_directory sorted by filename.
+    // A file named after a built-in overrides it.
+    pub fn load(preset_directory: Option<&Path>) -> Self {
+        let mut presets: Vec<(String, Preset)> = PresetName::ALL
+            .iter()
+            .map(|name| (format!("{name:?}"), Preset::new(*name)))
+            .collect();
+
+        if let Some(dir) = preset_directory {
+            match fs::read_dir(dir) {
+                Ok(entries) => {
+                    let mut paths: Vec<_> = entries
+                        .filter_map(|entry| entry.ok())
+                        .map(|entry| entry.path())
+                        .filter(|path| {
+                            path.extension()
+                                .and_then(|extension| extension.to_str())
+                                .map(|extension| {
+                                    extension.eq_ignore_ascii_case("toml")
+                                        || extension.eq_ignore_ascii_case("json")
+                                })
+                                .unwrap_or(false)
+                        })
+                        .collect();
+                    paths.sort();
+
+                    for path in paths {
+                        let name = path
+                            .file_stem()
+                            .and_then(|stem| stem.to_str())
+                            .unwrap_or_default()
+                            .to_string();
+                        let preset = match Preset::load_from_file(&path) {
+                            Ok(preset) => preset,
+                            Err(e) => {
+                                eprintln!("Skipping preset {}: {e}", path.display());
+                                continue;
+                            }
+                        };
+                        // A file named like an earlier preset replaces it in its place
+                        match presets
+                            .iter_mut()
+                            .find(|(existing, _)| existing.eq_ignore_ascii_case(&name))
+                        {
+                            Some(existing) => {
+                                println!("{} replaces the preset {}", path.display(), existing.0);
+                                existing.1 = preset;
+                            }
+                            None => presets.push((name, preset)),
+                        }
+                    }
+                }
+                Err(e) => eprintln!("Could not read preset directory {}: {e}", dir.display()),
+            }
+        }
+
+        println!("Loaded {} presets:", presets.len());
+        for (i, (name, _)) in presets.iter().enumerate() {
+            println!(" [{i}] {name}");
+        }
+
+        Self { presets }
+    }
+
+    pub fn number_of_banks(&self) -> usize {
+        self.presets.len().div_ceil(PRESET_BANK_SIZE)
+    }
+
+    pub fn index_of(&self, name: &str) -> Option<usize> {
+        self.presets
+            .iter()
+            .position(|(preset_name, _)| preset_name.eq_ignore_ascii_case(name))
+    }
+
+    pub fn name(&self, index: usize) -> Option<&str> {
+        self.presets.get(index).map(|(name, _)| &name[..])
+    }
+
+    pub fn get(&self, id: &PresetId) -> Option<Preset> {
+        let index = match id {
+            PresetId::Index(index) => *index,
+            PresetId::Name(name) => self.index_of(name)?,
+        };
+        self.presets.get(index).map(|(_, preset)| *preset)
+    }
+
+    // Presets given on the command line can be either a library name or a path
+    pub fn get_by_name_or_path(&self, preset: &str) -> Result<Preset, Box<dyn Error>> {
+        match self.get(&PresetId::Name(preset.to_string())) {
+            Some(preset) => Ok(preset),
+            None => Preset::load_from_file(preset),
+        }
+    }
+
+    pub fn bank_index(bank: usize, index: usize) -> usize {
+        bank * PRESET_BANK_SIZE + index
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn files_named_after_built_ins_override_them() {
+        let dir = std::env::temp_dir().join(format!("preset-library-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let built_in = format!("{:?}", PresetName::ALL[0]);
+        let mut replacement = Preset::new(PresetName::ALL[0]);
+        replacement.speed_multiplier += 1.0;
+        replacement
+            .save_to_file(dir.join(format!("{}.toml", built_in.to_lowercase())))
+            .unwrap();
+        let added = Preset::new(PresetName::ALL[1]);
+        added.save_to_file(dir.join("added.json")).unwrap();
+
+        let library = PresetLibrary::load(Some(&dir));
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(library.presets.len(), PresetName::ALL.len() + 1);
+        assert_eq!(library.index_of(&built_in), Some(0));
+        assert_eq!(library.name(0), Some(&built_in[..]));
+        assert_eq!(library.get(&PresetId::Index(0)), Some(replacement));
+        assert_eq!(
+            library.get(&PresetId::Name("added".to_string())),
+            Some(added)
+        );
+        assert_eq!(library.index_of("added"), Some(PresetName::ALL.len()));
+    }
+}
@@ -0,0 +1,48 @@
+use rand::distributions::{Distribution, Standard};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::cell::RefCell;
+
+// All randomness that affects the simulation goes through this generator so
+// that a run with the same seed and the same inputs renders the same frames.
+// Everything using it runs on the event loop thread.
+thread_local! {
+    static RNG: RefCell<StdRng> = RefCell::new(StdRng::from_entropy());
+}
+
+// Seeds the generator, picking a random seed if none was given. The seed is
+// returned so it can be printed and used to reproduce the run.
+pub fn seed(seed: Option<u64>) -> u64 {
+    let seed = seed.unwrap_or_else(|| rand::thread_rng().gen());
+    RNG.with(|rng| *rng.borrow_mut() = StdRng::seed_from_u64(seed));
+    seed
+}
+
+pub fn with_rng<T>(f: impl FnOnce(&mut StdRng) -> T) -> T {
+    RNG.with(|rng| f(&mut rng.borrow_mut()))
+}
+
+pub fn random<T>() -> T
+where
+    Standard: Distribution<T>,
+{
+    with_rng(|rng| rng.gen())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_same_seed_gives_the_same_numbers() {
+        assert_eq!(seed(Some(42)), 42);
+        let first: [u64; 4] = random();
+        seed(Some(42));
+        let second: [u64; 4] = random();
+        assert_eq!(first, second);
+
+        seed(Some(43));
+        let other: [u64; 4] = random();
+        assert_ne!(first, other);
+    }
+}
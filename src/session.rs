@@ -0,0 +1,188 @@
+use crate::preset::Preset;
+use crate::shader_pipeline::Position;
+use crate::simulation::PipelineState;
+use crate::slime_mould::SlimeMouldState;
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+pub const SESSION_DIR_PREFIX: &str = "slime_mould-session-";
+
+const STATE_FILE: &str = "session.toml";
+const PARTICLES_FILE: &str = "particles.bin";
+const TEXTURE0_FILE: &str = "texture0.bin";
+const TEXTURE1_FILE: &str = "texture1.bin";
+
+// Everything in the render loop that affects the current visual
+#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
+pub struct SessionState {
+    pub u_time: f32,
+    pub blend_value: f32,
+    pub beat_transition_time: f32,
+    pub beat_start_time: f32,
+    pub automate_presets: bool,
+    // Preset::u_time isn't saved with presets, but automation needs it to
+    // pick up where it left off
+    pub automation_time: f32,
+    pub beat_preset: Preset,
+    pub non_beat_preset: Preset,
+    pub slime_mould: SlimeMouldState,
+    pub width: u32,
+    pub height: u32,
+}
+
+// A session is saved as a directory holding the state as TOML plus the
+// particle buffers and trail textures as little endian f32 dumps
+pub fn save_session(
+    dir: impl AsRef<Path>,
+    state: &SessionState,
+    pipeline_state: &PipelineState,
+) -> Result<(), Box<dyn Error>> {
+    let dir = dir.as_ref();
+    fs::create_dir_all(dir)?;
+
+    fs::write(dir.join(STATE_FILE), toml::to_string_pretty(state)?)?;
+
+    for (species, particles) in pipeline_state.particles.iter().enumerate() {
+        let particles: Vec<f32> = particles
+            .iter()
+            .flat_map(|position| position.a_position)
+            .collect();
+        write_f32s(&dir.join(particles_file(species)), &particles)?;
+    }
+    write_f32s(&dir.join(TEXTURE0_FILE), &pipeline_state.texture0)?;
+    write_f32s(&dir.join(TEXTURE1_FILE), &pipeline_state.texture1)?;
+
+    println!("Saved session to {}", dir.display());
+    Ok(())
+}
+
+pub fn load_session(
+    dir: impl AsRef<Path>,
+) -> Result<(SessionState, PipelineState), Box<dyn Error>> {
+    let dir = dir.as_ref();
+
+    let mut state: SessionState = toml::from_str(&fs::read_to_string(dir.join(STATE_FILE))?)?;
+    for preset in [
+        &mut state.beat_preset,
+        &mut state.non_beat_preset,
+        &mut state.slime_mould.old_preset,
+        &mut state.slime_mould.preset,
+        &mut state.slime_mould.secondary_preset,
+    ] {
+        preset.u_time = state.automation_time;
+    }
+
+    let mut particles = Vec::new();
+    while particles.is_empty() || dir.join(particles_file(particles.len())).exists() {
+        particles.push(
+            read_f32s(&dir.join(particles_file(particles.len())))?
+                .chunks_exact(4)
+                .map(|chunk| Position {
+                    a_position: [chunk[0], chunk[1], chunk[2], chunk[3]],
+                })
+                .collect(),
+        );
+    }
+    let texture0 = read_f32s(&dir.join(TEXTURE0_FILE))?;
+    let texture1 = read_f32s(&dir.join(TEXTURE1_FILE))?;
+
+    let texture_len = (state.width * state.height * 4) as usize;
+    if texture0.len() != texture_len || texture1.len() != texture_len {
+        return Err(format!(
+            "Textures in {} do not match {}x{}",
+            dir.display(),
+            state.width,
+            state.height
+        )
+        .into());
+    }
+
+    println!("Loaded session from {}", dir.display());
+    Ok((
+        state,
+        PipelineState {
+            width: state.width,
+            height: state.height,
+            particles,
+            texture0,
+            texture1,
+        },
+    ))
+}
+
+// Finds the most recently saved session directory in the given directory
+pub fn latest_session_dir(dir: impl AsRef<Path>) -> Option<PathBuf> {
+    fs::read_dir(dir)
+        .ok()?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| {
+            path.is_dir()
+                && path
+                    .file_name()
+                    .and_then(|name| name.to_str())
+                    .map(|name| name.starts_with(SESSION_DIR_PREFIX))
+                    .unwrap_or(false)
+        })
+        .filter_map(|path| {
+            let modified = fs::metadata(&path).ok()?.modified().ok()?;
+            Some((modified, path))
+        })
+        .max_by_key(|(modified, _)| *modified)
+        .map(|(_, path)| path)
+}
+
+// The first species keeps the original file name so older sessions still load
+fn particles_file(species: usize) -> String {
+    match species {
+        0 => PARTICLES_FILE.to_string(),
+        species => format!("particles-{species}.bin"),
+    }
+}
+
+fn write_f32s(path: &Path, values: &[f32]) -> Result<(), Box<dyn Error>> {
+    let bytes: Vec<u8> = values
+        .iter()
+        .flat_map(|value| value.to_le_bytes())
+        .collect();
+    fs::write(path, bytes)?;
+    Ok(())
+}
+
+fn read_f32s(path: &Path) -> Result<Vec<f32>, Box<dyn Error>> {
+    let bytes = fs::read(path)?;
+    if bytes.len() % 4 != 0 {
+        return Err(format!("{} is not a list of f32 values", path.display()).into());
+    }
+    Ok(bytes
+        .chunks_exact(4)
+        .map(|chunk| f32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn f32_dumps_round_trip() {
+        let path = std::env::temp_dir().join(format!("session-f32s-{}.bin", std::process::id()));
+        let values = [0.0, -1.5, f32::MAX, f32::MIN_POSITIVE, 0.25];
+        write_f32s(&path, &values).unwrap();
+        let read = read_f32s(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(read, values);
+    }
+
+    #[test]
+    fn truncated_f32_dumps_are_rejected() {
+        let path =
+            std::env::temp_dir().join(format!("session-truncated-{}.bin", std::process::id()));
+        fs::write(&path, [0u8; 6]).unwrap();
+        let read = read_f32s(&path);
+        fs::remove_file(&path).unwrap();
+        assert!(read.is_err());
+    }
+}
@@ -0,0 +1,147 @@
+use crate::shader_pipeline::{
+    SHADER_1_FRAGMENT, SHADER_1_VERTEX, SHADER_2_FRAGMENT, SHADER_2_VERTEX,
+};
+use notify::{RecursiveMode, Watcher};
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{sync_channel, Receiver};
+
+// The file names the glium backend's programs are loaded from
+const SHADER_1_VERTEX_FILE: &str = "shader_1.vert";
+const SHADER_1_FRAGMENT_FILE: &str = "shader_1.frag";
+const SHADER_2_VERTEX_FILE: &str = "shader_2.vert";
+const SHADER_2_FRAGMENT_FILE: &str = "shader_2.frag";
+
+// The GLSL of shader_1 (the agent step) and shader_2 (the diffuse step)
+#[derive(Debug, Clone)]
+pub struct ShaderSources {
+    pub shader_1_vertex: String,
+    pub shader_1_fragment: String,
+    pub shader_2_vertex: String,
+    pub shader_2_fragment: String,
+}
+
+impl ShaderSources {
+    pub fn builtin() -> Self {
+        Self {
+            shader_1_vertex: SHADER_1_VERTEX.to_string(),
+            shader_1_fragment: SHADER_1_FRAGMENT.to_string(),
+            shader_2_vertex: SHADER_2_VERTEX.to_string(),
+            shader_2_fragment: SHADER_2_FRAGMENT.to_string(),
+        }
+    }
+
+    // Any file that doesn't exist yet is written out with the built-in
+    // source first, so an empty directory is a starting point for editing
+    pub fn load(directory: impl AsRef<Path>) -> Result<Self, Box<dyn Error>> {
+        let directory = directory.as_ref();
+        fs::create_dir_all(directory)?;
+        let load = |file_name: &str, builtin: &str| -> Result<String, Box<dyn Error>> {
+            let path = directory.join(file_name);
+            if !path.exists() {
+                fs::write(&path, builtin)?;
+                println!("Wrote built-in shader to {}", path.display());
+            }
+            Ok(fs::read_to_string(path)?)
+        };
+
+        Ok(Self {
+            shader_1_vertex: load(SHADER_1_VERTEX_FILE, SHADER_1_VERTEX)?,
+            shader_1_fragment: load(SHADER_1_FRAGMENT_FILE, SHADER_1_FRAGMENT)?,
+            shader_2_vertex: load(SHADER_2_VERTEX_FILE, SHADER_2_VERTEX)?,
+            shader_2_fragment: load(SHADER_2_FRAGMENT_FILE, SHADER_2_FRAGMENT)?,
+        })
+    }
+}
+
+fn is_shader_file(path: &Path) -> bool {
+    path.file_name()
+        .and_then(|name| name.to_str())
+        .map(|name| {
+            [
+                SHADER_1_VERTEX_FILE,
+                SHADER_1_FRAGMENT_FILE,
+                SHADER_2_VERTEX_FILE,
+                SHADER_2_FRAGMENT_FILE,
+            ]
+            .contains(&name)
+        })
+        .unwrap_or(false)
+}
+
+// Watches a shader directory for saved changes. The watcher runs on its own
+// thread and only tells the event loop that something changed, the files are
+// read and compiled on the main thread where the OpenGL context lives.
+pub struct ShaderWatcher {
+    directory: PathBuf,
+    receiver: Receiver<()>,
+    _watcher: notify::RecommendedWatcher,
+}
+
+impl ShaderWatcher {
+    pub fn new(directory: impl Into<PathBuf>) -> Result<Self, Box<dyn Error>> {
+        let directory = directory.into();
+        // Writing the built-in sources out before watching means creating
+        // them doesn't count as a change
+        ShaderSources::load(&directory)?;
+
+        let (sender, receiver) = sync_channel(1);
+        // Load the files once at startup as if they had just been saved
+        sender.send(())?;
+        let mut watcher =
+            notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+                if let Ok(event) = event {
+                    if (event.kind.is_modify() || event.kind.is_create())
+                        && event.paths.iter().any(|path| is_shader_file(path))
+                    {
+                        // A reload is already pending if the channel is full
+                        let _ = sender.try_send(());
+                    }
+                }
+            })?;
+        watcher.watch(&directory, RecursiveMode::NonRecursive)?;
+        println!("Watching {} for shader changes", directory.display());
+
+        Ok(Self {
+            directory,
+            receiver,
+            _watcher: watcher,
+        })
+    }
+
+    // The current sources if any of the files changed since the last call
+    pub fn changed_sources(&self) -> Option<Result<ShaderSources, Box<dyn Error>>> {
+        if self.receiver.try_iter().count() == 0 {
+            return None;
+        }
+        Some(ShaderSources::load(&self.directory))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn missing_files_start_as_the_built_in_shaders() {
+        let directory = std::env::temp_dir().join(format!("shader-files-{}", std::process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        fs::write(directory.join(SHADER_2_FRAGMENT_FILE), "edited").unwrap();
+
+        let sources = ShaderSources::load(&directory).unwrap();
+        let written = fs::read_to_string(directory.join(SHADER_1_VERTEX_FILE)).unwrap();
+        fs::remove_dir_all(&directory).unwrap();
+
+        assert_eq!(sources.shader_1_vertex, SHADER_1_VERTEX);
+        assert_eq!(written, SHADER_1_VERTEX);
+        assert_eq!(sources.shader_2_fragment, "edited");
+    }
+
+    #[test]
+    fn only_the_shader_files_are_watched() {
+        assert!(is_shader_file(Path::new("shaders/shader_1.frag")));
+        assert!(!is_shader_file(Path::new("shaders/shader_1.frag.swp")));
+        assert!(!is_shader_file(Path::new("shaders")));
+    }
+}
@@ -1,8 +1,13 @@
 use crate::preset::{InitialParameters, Preset, StartingArrangement};
+use crate::rng;
+use crate::shader_files::ShaderSources;
+use crate::simulation::{self, PipelineState, SimulationBackend, Species, BLUR_RADIUS};
+use glium::backend::Facade;
 use glium::uniforms::{self, Sampler};
 use glium::{implement_vertex, uniform, Surface};
 use rand::Rng;
-use std::cell::RefCell;
+use std::cell::{Ref, RefCell};
+use std::rc::Rc;
 
 #[derive(Copy, Clone)]
 struct Vertex {
@@ -10,8 +15,8 @@ struct Vertex {
 }
 
 #[derive(Copy, Clone)]
-struct Position {
-    a_position: [f32; 4],
+pub struct Position {
+    pub a_position: [f32; 4],
 }
 
 impl Default for Position {
@@ -25,15 +30,29 @@ impl Default for Position {
 implement_vertex!(Vertex, a_vertex);
 implement_vertex!(Position, a_position);
 
+// The agents of one species, shader_1 reads buffer_a and writes buffer_b
+struct ParticleBuffers {
+    buffer_a: RefCell<glium::VertexBuffer<Position>>,
+    buffer_b: RefCell<glium::VertexBuffer<Position>>,
+}
+
+impl ParticleBuffers {
+    fn new(display: &dyn Facade, particles: &[Position]) -> Self {
+        Self {
+            buffer_a: RefCell::new(glium::VertexBuffer::new(display, particles).unwrap()),
+            buffer_b: RefCell::new(glium::VertexBuffer::new(display, particles).unwrap()),
+        }
+    }
+}
+
 pub struct ShaderPipeline {
-    reset_points_before_draw: bool,
+    context: Rc<glium::backend::Context>,
     clear_textures_before_draw: bool,
-    initial_parameters: InitialParameters,
     shader_1: glium::Program,
     shader_2: glium::Program,
     vertex_buffer: glium::VertexBuffer<Vertex>,
-    buffer_a: RefCell<glium::VertexBuffer<Position>>,
-    buffer_b: RefCell<glium::VertexBuffer<Position>>,
+    // One set of buffers per species
+    particles: Vec<ParticleBuffers>,
     u_texture0: RefCell<glium::texture::Texture2d>,
     u_texture1: RefCell<glium::texture::Texture2d>,
     target_texture0: RefCell<glium::texture::Texture2d>,
@@ -43,12 +62,14 @@ pub struct ShaderPipeline {
 }
 
 impl ShaderPipeline {
-    pub fn new(display: &glium::Display, width: u32, height: u32, preset: Preset) -> Self {
+    pub fn new(display: &dyn Facade, width: u32, height: u32, preset: Preset) -> Self {
+        let sources = ShaderSources::builtin();
+
         // Shader 1
-        let shader_1 = Self::get_shader_1(display);
+        let shader_1 = Self::get_shader_1(display, &sources).unwrap();
 
         // Shader 2
-        let shader_2 = Self::get_shader_2(display);
+        let shader_2 = Self::get_shader_2(display, &sources).unwrap();
 
         // Textures
         let u_texture0 = glium::texture::Texture2d::empty_with_format(
@@ -88,16 +109,17 @@ impl ShaderPipeline {
         )
         .unwrap();
 
-        let (buffer_a, buffer_b) = Self::get_initial_locations(display, preset.initial_parameters);
+        let particles = vec![Self::get_initial_locations(
+            display,
+            preset.initial_parameters,
+        )];
 
         Self {
+            context: display.get_context().clone(),
             width,
             height,
-            reset_points_before_draw: false,
             clear_textures_before_draw: false,
-            initial_parameters: preset.initial_parameters,
-            buffer_a: RefCell::new(buffer_a),
-            buffer_b: RefCell::new(buffer_b),
+            particles,
             vertex_buffer,
             shader_1,
             shader_2,
@@ -125,80 +147,18 @@ impl ShaderPipeline {
             ),
         }
     }
-    pub fn draw(
-        &mut self,
-        frame: &mut impl glium::Surface,
-        display: &glium::Display,
-        preset: Preset,
-        u_time: f32,
-    ) {
-        if self.clear_textures_before_draw {
-            self.clear_textures(display, self.width, self.height);
-            self.clear_textures_before_draw = false;
-        }
-
-        if self.reset_points_before_draw {
-            let (buffer_a, buffer_b) =
-                Self::get_initial_locations(display, self.initial_parameters);
-
-            let (buffer_a, buffer_b) = (RefCell::new(buffer_a), RefCell::new(buffer_b));
-
-            self.buffer_a.swap(&buffer_a);
-            self.buffer_b.swap(&buffer_b);
-
-            self.reset_points_before_draw = false;
-        }
-
-        {
-            let target_texture = self.target_texture0.borrow();
-            let mut framebuffer =
-                glium::framebuffer::SimpleFrameBuffer::new(display, &*target_texture).unwrap();
-            framebuffer.clear_color(0.0, 0.0, 0.0, 1.0);
-            self.draw_1(&mut framebuffer, display, preset, u_time);
-        }
-
-        {
-            // Swap target_texture with u_texture0
-            std::mem::swap(
-                &mut *self.target_texture0.borrow_mut(),
-                &mut *self.u_texture0.borrow_mut(),
-            );
-        }
-
-        self.buffer_a.swap(&self.buffer_b);
-
-        frame.clear_color(0.0, 0.0, 0.0, 1.0);
-
-        //self.draw_1(frame, display, u_time);
-
-        {
-            let target_texture = self.target_texture1.borrow();
-            let mut framebuffer =
-                glium::framebuffer::SimpleFrameBuffer::new(display, &*target_texture).unwrap();
-            framebuffer.clear_color(0.0, 0.0, 0.0, 1.0);
-            self.draw_2(&mut framebuffer, display, preset, u_time);
-        }
-
-        self.draw_2(frame, display, preset, u_time);
-
-        {
-            // Swap target_texture with u_texture1
-            std::mem::swap(
-                &mut *self.target_texture1.borrow_mut(),
-                &mut *self.u_texture1.borrow_mut(),
-            );
-        }
-    }
-
     fn draw_1(
         &self,
         frame: &mut impl glium::Surface,
-        display: &glium::Display,
-        preset: Preset,
+        display: &dyn Facade,
+        species: &Species,
+        buffers: &ParticleBuffers,
+        channel: Option<usize>,
         u_time: f32,
     ) {
+        let preset = species.preset;
         {
-            let mut buffer_b = self.buffer_b.borrow_mut();
+            let mut buffer_b = buffers.buffer_b.borrow_mut();
             let session = glium::vertex::TransformFeedbackSession::new(
                 display,
                 &self.shader_1,
@@ -206,7 +166,7 @@ impl ShaderPipeline {
             )
             .unwrap();
 
-            let draw_parameters = Self::get_draw_parameters_shader_1(&session);
+            let draw_parameters = Self::get_draw_parameters_shader_1(&session, channel);
 
             let u_texture1 = &*self.u_texture1.borrow();
             let uniforms = uniform! {
@@ -214,19 +174,25 @@ impl ShaderPipeline {
                 u_speed_multiplier: preset.speed_multiplier,
                 u_wall_strategy: preset.wall_strategy as u8,
                 u_color_strategy: preset.color_strategy as u8,
+                u_blend_color_strategy: preset.color_blend.target as u8,
+                u_color_blend: preset.color_blend.amount,
                 u_random_steer_factor: preset.random_steer_factor,
                 u_constant_steer_factor: preset.constant_steer_factor,
                 u_search_radius: preset.search_radius,
                 u_trail_strength: preset.trail_strength,
-                u_vertex_radius: preset.point_size,
-                u_search_angle: 0.2f32,
+                u_vertex_radius: simulation::point_size(&preset, self.height),
+                u_search_angle: preset.search_angle,
+                u_sensor_count: preset.sensor_count.get() as i32,
+                u_turn_strategy: preset.turn_strategy as u8,
+                u_sense_weights: species.sense_weights,
+                u_species_mode: channel.is_some(),
                 u_time: u_time,
             };
 
             // Draw shader_1 to the frame
             frame
                 .draw(
-                    &*self.buffer_a.borrow(),
+                    &*buffers.buffer_a.borrow(),
                     glium::index::NoIndices(glium::index::PrimitiveType::Points),
                     &self.shader_1,
                     &uniforms,
@@ -239,19 +205,20 @@ impl ShaderPipeline {
     fn draw_2(
         &self,
         frame: &mut impl glium::Surface,
-        _display: &glium::Display,
-        preset: Preset,
+        _display: &dyn Facade,
+        species: &[Species],
         u_time: f32,
     ) {
+        let (fade_speed, blurring) = simulation::channel_diffusion(species);
         let u_texture0 = &*self.u_texture0.borrow();
         let u_texture1 = &*self.u_texture1.borrow();
         let uniforms = uniform! {
             u_texture0: Sampler::new(u_texture0).wrap_function(uniforms::SamplerWrapFunction::Repeat),
             u_texture1: Sampler::new(u_texture1).wrap_function(uniforms::SamplerWrapFunction::Repeat),
-            u_fade_speed: preset.fade_speed,
-            u_blur_fraction: preset.blurring,
+            u_fade_speed: fade_speed,
+            u_blur_fraction: blurring,
+            u_blur_radius: BLUR_RADIUS,
             u_time: u_time,
-            u_max_distance: 1.0f32,
         };
         // Draw the results of shader_2 to the screen
         frame
@@ -270,16 +237,23 @@ impl ShaderPipeline {
             ..Default::default()
         }
     }
+    // With several species each one only writes to its own channel
     fn get_draw_parameters_shader_1<'b>(
         session: &'b glium::vertex::TransformFeedbackSession,
+        channel: Option<usize>,
     ) -> glium::DrawParameters<'b> {
+        let color_mask = match channel {
+            Some(channel) => (channel == 0, channel == 1, channel == 2, false),
+            None => (true, true, true, true),
+        };
         glium::DrawParameters {
             transform_feedback: Some(session),
+            color_mask,
             ..Default::default()
         }
     }
 
-    fn clear_textures(&mut self, display: &glium::Display, width: u32, height: u32) {
+    fn clear_textures(&mut self, display: &dyn Facade, width: u32, height: u32) {
         let u_texture0 = RefCell::new(
             glium::texture::Texture2d::empty_with_format(
                 display,
@@ -306,21 +280,247 @@ impl ShaderPipeline {
         u_texture1.swap(&self.u_texture1);
     }
 
-    pub fn clear(&mut self) {
+    fn get_initial_locations(
+        display: &dyn Facade,
+        initial_parameters: InitialParameters,
+    ) -> ParticleBuffers {
+        ParticleBuffers::new(display, &initial_positions(initial_parameters))
+    }
+
+    fn get_shader_1(
+        display: &dyn Facade,
+        sources: &ShaderSources,
+    ) -> Result<glium::Program, glium::ProgramCreationError> {
+        glium::Program::new(
+            display,
+            glium::program::ProgramCreationInput::SourceCode {
+                vertex_shader: &sources.shader_1_vertex,
+                fragment_shader: &sources.shader_1_fragment,
+                geometry_shader: None,
+                tessellation_control_shader: None,
+                tessellation_evaluation_shader: None,
+                transform_feedback_varyings: Some((
+                    vec!["gl_Position".to_string()],
+                    glium::program::TransformFeedbackMode::Separate,
+                )),
+                outputs_srgb: false,
+                uses_point_size: true,
+            },
+        )
+    }
+
+    fn get_shader_2(
+        display: &dyn Facade,
+        sources: &ShaderSources,
+    ) -> Result<glium::Program, glium::ProgramCreationError> {
+        glium::Program::new(
+            display,
+            glium::program::ProgramCreationInput::SourceCode {
+                vertex_shader: &sources.shader_2_vertex,
+                fragment_shader: &sources.shader_2_fragment,
+                geometry_shader: None,
+                tessellation_control_shader: None,
+                tessellation_evaluation_shader: None,
+                transform_feedback_varyings: None,
+                outputs_srgb: false,
+                uses_point_size: false,
+            },
+        )
+    }
+
+    fn read_texture(texture: &glium::texture::Texture2d) -> Vec<f32> {
+        let rect = glium::Rect {
+            left: 0,
+            bottom: 0,
+            width: texture.get_width(),
+            height: texture.get_height().unwrap(),
+        };
+        let image: glium::texture::RawImage2d<f32> = texture
+            .main_level()
+            .first_layer()
+            .into_image(None)
+            .unwrap()
+            .raw_read::<_, (f32, f32, f32, f32)>(&rect);
+        image.data.into_owned()
+    }
+
+    fn empty_texture(display: &dyn Facade, width: u32, height: u32) -> glium::texture::Texture2d {
+        glium::texture::Texture2d::empty_with_format(
+            display,
+            glium::texture::UncompressedFloatFormat::F32F32F32F32,
+            glium::texture::MipmapsOption::NoMipmap,
+            width,
+            height,
+        )
+        .unwrap()
+    }
+
+    fn write_texture(
+        display: &dyn Facade,
+        data: Vec<f32>,
+        width: u32,
+        height: u32,
+    ) -> glium::texture::Texture2d {
+        glium::texture::Texture2d::with_format(
+            display,
+            glium::texture::RawImage2d::from_raw_rgba(data, (width, height)),
+            glium::texture::UncompressedFloatFormat::F32F32F32F32,
+            glium::texture::MipmapsOption::NoMipmap,
+        )
+        .unwrap()
+    }
+}
+
+impl SimulationBackend for ShaderPipeline {
+    fn step(&mut self, species: &[Species], u_time: f32) {
+        let context = self.context.clone();
+        let display = &context;
+
+        if self.clear_textures_before_draw {
+            self.clear_textures(display, self.width, self.height);
+            self.clear_textures_before_draw = false;
+        }
+
+        self.particles.truncate(species.len());
+        for added in &species[self.particles.len()..] {
+            let buffers = Self::get_initial_locations(display, added.preset.initial_parameters);
+            self.particles.push(buffers);
+        }
+
+        {
+            let target_texture = self.target_texture0.borrow();
+            let mut framebuffer =
+                glium::framebuffer::SimpleFrameBuffer::new(display, &*target_texture).unwrap();
+            framebuffer.clear_color(0.0, 0.0, 0.0, 1.0);
+            let multi_species = simulation::is_multi_species(species);
+            for (index, (current, buffers)) in species.iter().zip(&self.particles).enumerate() {
+                let channel = multi_species.then_some(index);
+                self.draw_1(&mut framebuffer, display, current, buffers, channel, u_time);
+            }
+        }
+
+        {
+            // Swap target_texture with u_texture0
+            std::mem::swap(
+                &mut *self.target_texture0.borrow_mut(),
+                &mut *self.u_texture0.borrow_mut(),
+            );
+        }
+
+        for buffers in &self.particles {
+            buffers.buffer_a.swap(&buffers.buffer_b);
+        }
+
+        {
+            let target_texture = self.target_texture1.borrow();
+            let mut framebuffer =
+                glium::framebuffer::SimpleFrameBuffer::new(display, &*target_texture).unwrap();
+            framebuffer.clear_color(0.0, 0.0, 0.0, 1.0);
+            self.draw_2(&mut framebuffer, display, species, u_time);
+        }
+
+        {
+            // Swap target_texture with u_texture1
+            std::mem::swap(
+                &mut *self.target_texture1.borrow_mut(),
+                &mut *self.u_texture1.borrow_mut(),
+            );
+        }
+    }
+
+    fn clear(&mut self) {
         self.clear_textures_before_draw = true;
     }
 
-    fn get_initial_locations(
-        display: &glium::Display,
-        initial_parameters: InitialParameters,
-    ) -> (glium::VertexBuffer<Position>, glium::VertexBuffer<Position>) {
-        let mut initial_locations =
-            vec![Position::default(); initial_parameters.number_of_points as usize];
+    fn read_state(&self) -> PipelineState {
+        PipelineState {
+            width: self.width,
+            height: self.height,
+            particles: self
+                .particles
+                .iter()
+                .map(|buffers| buffers.buffer_a.borrow().read().unwrap())
+                .collect(),
+            texture0: Self::read_texture(&self.u_texture0.borrow()),
+            texture1: Self::read_texture(&self.u_texture1.borrow()),
+        }
+    }
+
+    fn write_state(&mut self, state: PipelineState) {
+        let context = self.context.clone();
+        let display = &context;
+
+        self.particles = state
+            .particles
+            .iter()
+            .map(|particles| ParticleBuffers::new(display, particles))
+            .collect();
+
+        if state.width != self.width || state.height != self.height {
+            self.width = state.width;
+            self.height = state.height;
+            self.target_texture0 =
+                RefCell::new(Self::empty_texture(display, state.width, state.height));
+            self.target_texture1 =
+                RefCell::new(Self::empty_texture(display, state.width, state.height));
+        }
+
+        self.u_texture0 = RefCell::new(Self::write_texture(
+            display,
+            state.texture0,
+            state.width,
+            state.height,
+        ));
+        self.u_texture1 = RefCell::new(Self::write_texture(
+            display,
+            state.texture1,
+            state.width,
+            state.height,
+        ));
+        self.clear_textures_before_draw = false;
+    }
 
-        let pi_times_2_over_n =
-            std::f32::consts::PI * 2.0 / initial_parameters.number_of_points as f32;
+    fn reset_points(&mut self, species: usize, initial_parameters: InitialParameters) {
+        if species < self.particles.len() {
+            self.particles[species] =
+                Self::get_initial_locations(&self.context, initial_parameters);
+        }
+    }
+
+    fn read_trails(&self) -> Vec<f32> {
+        Self::read_texture(&self.u_texture1.borrow())
+    }
+
+    fn size(&self) -> (u32, u32) {
+        (self.width, self.height)
+    }
+
+    // Both programs are compiled before either is replaced, so a mistake in
+    // one of them keeps the last good pair running
+    fn reload_shaders(&mut self, sources: &ShaderSources) -> Result<(), String> {
+        let shader_1 =
+            Self::get_shader_1(&self.context, sources).map_err(|e| format!("shader_1: {e}"))?;
+        let shader_2 =
+            Self::get_shader_2(&self.context, sources).map_err(|e| format!("shader_2: {e}"))?;
+        self.shader_1 = shader_1;
+        self.shader_2 = shader_2;
+        Ok(())
+    }
+
+    fn trail_texture(&self) -> Option<Ref<'_, glium::texture::Texture2d>> {
+        Some(self.u_texture1.borrow())
+    }
+}
 
-        let mut rng = rand::thread_rng();
+// Particles are stored as (x, y, speed, direction) with the speed and
+// direction packed the same way shader_1 writes them back
+pub fn initial_positions(initial_parameters: InitialParameters) -> Vec<Position> {
+    let mut initial_locations =
+        vec![Position::default(); initial_parameters.number_of_points as usize];
+
+    let pi_times_2_over_n = std::f32::consts::PI * 2.0 / initial_parameters.number_of_points as f32;
+
+    rng::with_rng(|rng| {
         for i in 0..initial_parameters.number_of_points {
             let speed =
                 (rng.gen_range(0.0..1.00) * 0.01 * initial_parameters.starting_speed_spread
@@ -357,15 +557,13 @@ impl ShaderPipeline {
                 },
             };
         }
+    });
 
-        (
-            glium::VertexBuffer::new(display, &initial_locations).unwrap(),
-            glium::VertexBuffer::new(display, &initial_locations).unwrap(),
-        )
-    }
+    initial_locations
+}
 
-    fn get_shader_1(display: &glium::Display) -> glium::Program {
-        let vertex_shader_src = r#"
+// The built-in GLSL of both programs, see ShaderSources for editing them live
+pub const SHADER_1_VERTEX: &str = r#"
         #version 140
         precision highp float;
 
@@ -378,13 +576,18 @@ impl ShaderPipeline {
         uniform float u_speed_multiplier;
         uniform uint u_wall_strategy;
         uniform uint u_color_strategy;
+        uniform uint u_blend_color_strategy;
+        uniform float u_color_blend; // How much of u_blend_color_strategy is shown
         uniform float u_random_steer_factor;
         uniform float u_constant_steer_factor;
         uniform float u_search_radius;
         uniform float u_trail_strength;
         uniform float u_vertex_radius;
         uniform float u_search_angle;
-        uniform float u_max_distance;
+        uniform int u_sensor_count;
+        uniform uint u_turn_strategy;
+        uniform vec3 u_sense_weights; // How much each trail channel attracts this species
+        uniform bool u_species_mode; // Deposit only the trail strength, into one channel
 
         // Passed to fragment shader
         varying vec4 v_color;
@@ -399,6 +602,95 @@ impl ShaderPipeline {
             return c.z * mix(K.xxx, clamp(p - K.xxx, 0.0, 1.0), c.y);
         }
 
+        // The color deposited by each color strategy
+        vec3 strategy_color(uint strategy, float direction, float speed_var, float x_new, float y_new) {
+            float r = 0.0;
+            float g = 0.0;
+            float b = 0.0;
+
+            switch (strategy) {
+                case 0u:
+                    r = sin(direction);
+                    g = cos(direction);
+                    b = u_trail_strength;
+                    break;
+                case 1u:
+                    r = speed_var*50.0;
+                    g = r;
+                    b = u_trail_strength;
+                    break;
+                case 2u:
+                    r = abs(y_new)/2.0 + 0.5;
+                    g = abs(x_new)/2.0 + 0.5;
+                    b = u_trail_strength;
+                    break;
+                case 3u:
+                    r = u_trail_strength;
+                    g = r;
+                    b = r;
+                    break;
+                // Color strategy 4: Hue shifting based on position
+                case 4u:
+                    float distanceFromCenter = sqrt(x_new * x_new + y_new * y_new);
+                    float normalizedDistance = distanceFromCenter / 1.3;
+                    float hue = atan(y_new, x_new) / (2.0 * 3.14159) + 0.5;
+                    vec3 hsv = vec3(hue, 1.0-normalizedDistance, 1.0);
+                    vec3 rgb = hsv2rgb(hsv); 
+                    r = rgb.r;
+                    g = rgb.g;
+                    b = u_trail_strength;
+                    break;
+                // Color strategy 5: Gradient based on distance from center
+                case 5u:
+                    distanceFromCenter = sqrt(x_new * x_new + y_new * y_new);
+                    normalizedDistance = distanceFromCenter / 1.3;
+                    r = mix(0.2, 1.0, normalizedDistance);
+                    g = mix(0.5, 1.0, normalizedDistance);
+                    b = u_trail_strength;
+                    break;
+
+                // Color strategy 6: Color oscillation based on time
+                case 6u:
+                    float timeFactor = sin(u_time * 0.5);
+                    r = 0.5 + 0.5 * sin(2.0 * 3.14159 * (x_new + y_new) + timeFactor);
+                    g = 0.5 + 0.5 * sin(2.0 * 3.14159 * (x_new - y_new) + timeFactor);
+                    b = u_trail_strength;
+                    break;
+
+                // The palette strategies leave the palette index times the
+                // trail strength in red, so dividing by blue gives the average
+                // index of the trails at any point once they're blurred
+                case 7u:
+                    r = 0.0;
+                    g = 0.0;
+                    b = u_trail_strength;
+                    break;
+                case 8u:
+                    r = fract(direction / (2.0 * 3.14159)) * u_trail_strength;
+                    g = 0.0;
+                    b = u_trail_strength;
+                    break;
+                case 9u:
+                    r = clamp(speed_var * 50.0, 0.0, 1.0) * u_trail_strength;
+                    g = 0.0;
+                    b = u_trail_strength;
+                    break;
+            }
+
+            return vec3(r, g, b);
+        }
+
+        // What this species senses at u_search_radius in the given direction
+        float sense(vec2 texcoord, float direction) {
+            return dot(texture2D(
+                u_texture1,
+                vec2(
+                    texcoord.x+cos(direction)*u_search_radius,
+                    texcoord.y+sin(direction)*u_search_radius
+                )
+            ).rgb, u_sense_weights);
+        }
+
         void main() {
             // Coord in texture space
             vec2 texcoord = vec2((a_position.x+1.0)/2.0, (a_position.y+1.0)/2.0);
@@ -414,43 +706,66 @@ impl ShaderPipeline {
             // Calculate current speed
             float speed = u_speed_multiplier * speed_var;
 
-            // Read the underlying texture in three directions
-            float sense_radius = u_search_radius;
-            float sense_angle = u_search_angle;
-            float sense_left = texture2D(
-                u_texture1,
-                vec2(
-                    texcoord.x+cos(direction+sense_angle)*sense_radius,
-                    texcoord.y+sin(direction+sense_angle)*sense_radius
-                )
-            ).b;
-            float sense_right = texture2D(
-                u_texture1,
-                vec2(
-                    texcoord.x+cos(direction-sense_angle)*sense_radius,
-                    texcoord.y+sin(direction-sense_angle)*sense_radius
-                )
-            ).b;
-            float sense_forward = texture2D(
-                u_texture1,
-                vec2(
-                    texcoord.x+cos(direction)*sense_radius,
-                    texcoord.y+sin(direction)*sense_radius
-                )
-            ).b;
+            // Read the underlying texture with sensors spread evenly from
+            // u_search_angle on the right to u_search_angle on the left
+            float centre = float(u_sensor_count - 1) / 2.0;
+            float sense_forward = -1.0e38;
+            float sense_left = -1.0e38;
+            float sense_right = -1.0e38;
+            float strongest = -1.0e38;
+            float strongest_angle = 0.0;
+            float total = 0.0;
+            float weighted_angle = 0.0;
+            for (int i = 0; i < u_sensor_count; i++) {
+                float offset = float(i) - centre;
+                float angle = u_search_angle * offset / centre;
+                float sensed = sense(texcoord, direction + angle);
+
+                // The one or two sensors in the middle look forward
+                if (abs(offset) < 1.0) {
+                    sense_forward = max(sense_forward, sensed);
+                } else if (offset > 0.0) {
+                    sense_left = max(sense_left, sensed);
+                } else {
+                    sense_right = max(sense_right, sensed);
+                }
+
+                if (sensed > strongest) {
+                    strongest = sensed;
+                    strongest_angle = angle;
+                }
+                total += max(sensed, 0.0);
+                weighted_angle += max(sensed, 0.0) * angle;
+            }
 
             // Update direction based on sensed values
             float steer_amount = u_constant_steer_factor + u_random_steer_factor * rand(texcoord+tex_val.xy);
 
-            // Straight ahead
-            if (sense_forward > sense_left && sense_forward > sense_right) {
-                direction += 0.0;
-            } else if (sense_forward < sense_left && sense_forward < sense_right) { // random
-                direction += u_random_steer_factor*(rand(texcoord+tex_val.xy)-0.5);
-            } else if (sense_right > sense_left) {
-                direction -= steer_amount; // Turn right
-            } else if (sense_right < sense_left) {
-                direction += steer_amount; // Turn left
+            switch (u_turn_strategy) {
+                case 0u:
+                    // Steer
+                    if (sense_forward > sense_left && sense_forward > sense_right) {
+                        direction += 0.0; // Straight ahead
+                    } else if (sense_forward < sense_left && sense_forward < sense_right) { // random
+                        direction += u_random_steer_factor*(rand(texcoord+tex_val.xy)-0.5);
+                    } else if (sense_right > sense_left) {
+                        direction -= steer_amount; // Turn right
+                    } else if (sense_right < sense_left) {
+                        direction += steer_amount; // Turn left
+                    }
+                    break;
+                case 1u:
+                    // Strongest
+                    if (strongest > sense_forward) {
+                        direction += strongest_angle;
+                    }
+                    break;
+                case 2u:
+                    // Weighted
+                    if (total > 0.0 && u_search_angle > 0.0) {
+                        direction += steer_amount * weighted_angle / (total * u_search_angle);
+                    }
+                    break;
             }
 
             // Start calculating our new position
@@ -513,71 +828,27 @@ impl ShaderPipeline {
             y_new += speed*u_speed_multiplier*sin(direction);
             x_new += speed*u_speed_multiplier*cos(direction);
 
-            // Set the color of this vert
-            float r = 0.0;
-            float g = 0.0;
-            float b = 0.0;
-
-            // Color strategy
-            switch (u_color_strategy) {
-                case 0u:
-                    r = sin(direction);
-                    g = cos(direction);
-                    b = u_trail_strength;
-                    break;
-                case 1u:
-                    r = speed_var*50.0;
-                    g = r;
-                    b = u_trail_strength;
-                    break;
-                case 2u:
-                    r = abs(y_new)/2.0 + 0.5;
-                    g = abs(x_new)/2.0 + 0.5;
-                    b = u_trail_strength;
-                    break;
-                case 3u:
-                    r = u_trail_strength;
-                    g = r;
-                    b = r;
-                    break;
-                // Color strategy 4: Hue shifting based on position
-                case 4u:
-                    float distanceFromCenter = sqrt(x_new * x_new + y_new * y_new);
-                    float normalizedDistance = distanceFromCenter / 1.3;
-                    float hue = atan(y_new, x_new) / (2.0 * 3.14159) + 0.5;
-                    vec3 hsv = vec3(hue, 1.0-normalizedDistance, 1.0);
-                    vec3 rgb = hsv2rgb(hsv); 
-                    r = rgb.r;
-                    g = rgb.g;
-                    b = u_trail_strength;
-                    break;
-                // Color strategy 5: Gradient based on distance from center
-                case 5u:
-                    distanceFromCenter = sqrt(x_new * x_new + y_new * y_new);
-                    normalizedDistance = distanceFromCenter / 1.3;
-                    r = mix(0.2, 1.0, normalizedDistance);
-                    g = mix(0.5, 1.0, normalizedDistance);
-                    b = u_trail_strength;
-                    break;
+            // Set the color of this vert, mixing in the color strategy being
+            // blended towards
+            vec3 color = mix(
+                strategy_color(u_color_strategy, direction, speed_var, x_new, y_new),
+                strategy_color(u_blend_color_strategy, direction, speed_var, x_new, y_new),
+                u_color_blend
+            );
 
-                // Color strategy 6: Color oscillation based on time
-                case 6u:
-                    float timeFactor = sin(u_time * 0.5);
-                    r = 0.5 + 0.5 * sin(2.0 * 3.14159 * (x_new + y_new) + timeFactor);
-                    g = 0.5 + 0.5 * sin(2.0 * 3.14159 * (x_new - y_new) + timeFactor);
-                    b = u_trail_strength;
-                    break;
+            v_color = vec4(color, 1.0);
+            if (u_species_mode) {
+                // The draw call masks out every channel but this species' one
+                v_color = vec4(u_trail_strength, u_trail_strength, u_trail_strength, 1.0);
             }
 
-            v_color = vec4(r, g, b, 1.0);
-
             // Send back the position and size
             gl_Position = vec4(x_new, y_new, speed_var/1000.0, 1.0+direction/1000.0);
             gl_PointSize = u_vertex_radius;
         }
     "#;
 
-        let fragment_shader_src = r#"
+pub const SHADER_1_FRAGMENT: &str = r#"
         #version 140
         precision highp float;
 
@@ -588,27 +859,7 @@ impl ShaderPipeline {
         }
     "#;
 
-        glium::Program::new(
-            display,
-            glium::program::ProgramCreationInput::SourceCode {
-                vertex_shader: vertex_shader_src,
-                fragment_shader: fragment_shader_src,
-                geometry_shader: None,
-                tessellation_control_shader: None,
-                tessellation_evaluation_shader: None,
-                transform_feedback_varyings: Some((
-                    vec!["gl_Position".to_string()],
-                    glium::program::TransformFeedbackMode::Separate,
-                )),
-                outputs_srgb: false,
-                uses_point_size: true,
-            },
-        )
-        .unwrap()
-    }
-
-    fn get_shader_2(display: &glium::Display) -> glium::Program {
-        let vertex_shader_src = r#"
+pub const SHADER_2_VERTEX: &str = r#"
         #version 140
         attribute vec2 a_vertex;
         
@@ -621,13 +872,14 @@ impl ShaderPipeline {
         }
     "#;
 
-        let fragment_shader_src = r#"
+pub const SHADER_2_FRAGMENT: &str = r#"
             #version 140
             precision highp float;
             uniform sampler2D u_texture0; // A texture input - the output of shader 1
             uniform sampler2D u_texture1; // A texture input - the previous frame's output from shader 2
-            uniform float u_fade_speed; // TODO
-            uniform float u_blur_fraction; // TODO
+            uniform vec4 u_fade_speed; // Per channel, each species fades at its own speed
+            uniform vec4 u_blur_fraction; // Per channel
+            uniform float u_blur_radius; // As a fraction of the texture size
 
             uniform float u_time;
 
@@ -636,7 +888,6 @@ impl ShaderPipeline {
             // For blurring
             const float Directions = 8.0;
             const float Quality = 1.0; // 3 for snowflake
-            const float Radius = 1.0/1200.0; // TODO pass in resolution
             float pixelCount = 1.0;
 
             void main() {
@@ -648,7 +899,7 @@ impl ShaderPipeline {
               vec4 blurred = texture2D(u_texture1, texcoord); // sample the previous frame    
               for( float d=0.0; d<6.3; d+=6.3/Directions){
                   for(float i=1.0/Quality; i<=1.0; i+=1.0/Quality){
-                    blurred += texture2D(u_texture1, texcoord+vec2(cos(d),sin(d))*Radius*i); 		
+                    blurred += texture2D(u_texture1, texcoord+vec2(cos(d),sin(d))*u_blur_radius*i); 		
                     pixelCount += 1.0;
                    }
               }
@@ -664,25 +915,3 @@ impl ShaderPipeline {
               gl_FragColor = shader1_out + blurred*(1.0-u_fade_speed) - 0.0001;
             }
             "#;
-
-        glium::Program::new(
-            display,
-            glium::program::ProgramCreationInput::SourceCode {
-                vertex_shader: vertex_shader_src,
-                fragment_shader: fragment_shader_src,
-                geometry_shader: None,
-                tessellation_control_shader: None,
-                tessellation_evaluation_shader: None,
-                transform_feedback_varyings: None,
-                outputs_srgb: false,
-                uses_point_size: false,
-            },
-        )
-        .unwrap()
-    }
-
-    pub fn reset_points(&mut self, initial_parameters: InitialParameters) {
-        self.reset_points_before_draw = true;
-        self.initial_parameters = initial_parameters;
-    }
-}
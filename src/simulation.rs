@@ -0,0 +1,390 @@
+use crate::cpu_pipeline::CpuPipeline;
+use crate::palette::Palette;
+use crate::preset::{ColorStrategy, InitialParameters, Preset};
+use crate::shader_files::ShaderSources;
+use crate::shader_pipeline::{Position, ShaderPipeline};
+use crate::wgpu_pipeline::WgpuPipeline;
+use glium::backend::Facade;
+use std::cell::Ref;
+
+// The trail map height the built-in presets were made at
+pub const REFERENCE_HEIGHT: f32 = 1200.0;
+
+// How far the diffuse step samples around each texel. Like the preset's
+// search_radius this is a fraction of the trail map rather than a number of
+// pixels, so the simulation looks the same at every resolution.
+pub const BLUR_RADIUS: f32 = 1.0 / REFERENCE_HEIGHT;
+
+// A preset's point size is in pixels of a trail map REFERENCE_HEIGHT tall,
+// this is the size in pixels of one that's height tall
+pub fn point_size(preset: &Preset, height: u32) -> f32 {
+    preset.point_size * height as f32 / REFERENCE_HEIGHT
+}
+
+// Shows the trail map as it is, see Colony::display_matrix for how species
+// channels are colored instead
+pub const IDENTITY_DISPLAY_MATRIX: [[f32; 3]; 3] =
+    [[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]];
+
+// How the trail map is turned into screen colors
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct DisplayMapping {
+    // Each screen color is a mix of the trail channels
+    pub matrix: [[f32; 3]; 3],
+    pub palette: Palette,
+    // How much of the palette colors are shown instead of the matrix colors,
+    // indexed by the trail intensity in the blue channel
+    pub trail_palette: f32,
+    // or by the average agent attribute, which is red divided by blue
+    pub attribute_palette: f32,
+}
+
+impl DisplayMapping {
+    pub fn from_matrix(matrix: [[f32; 3]; 3]) -> DisplayMapping {
+        DisplayMapping {
+            matrix,
+            palette: Palette::default(),
+            trail_palette: 0.0,
+            attribute_palette: 0.0,
+        }
+    }
+
+    // How a single species running this preset is displayed, while the color
+    // strategy is being blended the palette colors fade in or out with it
+    pub fn for_preset(preset: &Preset) -> DisplayMapping {
+        let mut mapping = DisplayMapping::from_matrix(IDENTITY_DISPLAY_MATRIX);
+        mapping.palette = preset.palette;
+        for (strategy, amount) in preset.color_strategies() {
+            match strategy {
+                ColorStrategy::PaletteTrail => mapping.trail_palette += amount,
+                ColorStrategy::PaletteDirection | ColorStrategy::PaletteSpeed => {
+                    mapping.attribute_palette += amount
+                }
+                _ => (),
+            }
+        }
+        mapping
+    }
+}
+
+// A population of agents following one preset. What an agent senses is the
+// trail map's rgb weighted by sense_weights, so species can be attracted to or
+// repelled by each other's trails.
+#[derive(Debug, Copy, Clone)]
+pub struct Species {
+    pub preset: Preset,
+    pub sense_weights: [f32; 3],
+}
+
+impl Species {
+    // A lone species senses the blue channel, which every color strategy
+    // fills with the trail strength
+    pub fn single(preset: Preset) -> Species {
+        Species {
+            preset,
+            sense_weights: [0.0, 0.0, 1.0],
+        }
+    }
+}
+
+// With several species every species deposits its trail strength into its own
+// channel of the trail map instead of the colors from its color strategy
+pub fn is_multi_species(species: &[Species]) -> bool {
+    species.len() > 1
+}
+
+// Fade speed and blurring for each channel of the diffuse step, a channel
+// that belongs to a species fades like that species' preset
+pub fn channel_diffusion(species: &[Species]) -> ([f32; 4], [f32; 4]) {
+    let first = species[0].preset;
+    let mut fade_speed = [first.fade_speed; 4];
+    let mut blurring = [first.blurring; 4];
+    if is_multi_species(species) {
+        for (channel, species) in species.iter().enumerate() {
+            fade_speed[channel] = species.preset.fade_speed;
+            blurring[channel] = species.preset.blurring;
+        }
+    }
+    (fade_speed, blurring)
+}
+
+// Everything on the GPU needed to reproduce the current frame, the textures
+// are stored as raw RGBA f32 values with rows from the bottom up
+pub struct PipelineState {
+    pub width: u32,
+    pub height: u32,
+    // One particle buffer per species
+    pub particles: Vec<Vec<Position>>,
+    pub texture0: Vec<f32>,
+    pub texture1: Vec<f32>,
+}
+
+impl PipelineState {
+    // Scales the textures to a new size, the particles are in clip space so
+    // they stay where they are
+    pub fn resized(self, width: u32, height: u32) -> PipelineState {
+        if self.width == width && self.height == height {
+            return self;
+        }
+        PipelineState {
+            texture0: resample(&self.texture0, self.width, self.height, width, height),
+            texture1: resample(&self.texture1, self.width, self.height, width, height),
+            width,
+            height,
+            particles: self.particles,
+        }
+    }
+
+    // Keeps the particles but starts over with empty textures
+    pub fn cleared(self, width: u32, height: u32) -> PipelineState {
+        PipelineState {
+            texture0: vec![0.0; (width * height * 4) as usize],
+            texture1: vec![0.0; (width * height * 4) as usize],
+            width,
+            height,
+            particles: self.particles,
+        }
+    }
+}
+
+// Bilinear scaling of RGBA f32 texels, clamped at the edges
+fn resample(data: &[f32], from_width: u32, from_height: u32, width: u32, height: u32) -> Vec<f32> {
+    let texel = |x: u32, y: u32| &data[((y * from_width + x) * 4) as usize..][..4];
+    let mix = |a: f32, b: f32, t: f32| a * (1.0 - t) + b * t;
+    let source = |position: u32, size: u32, from_size: u32| {
+        let source = ((position as f32 + 0.5) * from_size as f32 / size as f32 - 0.5)
+            .clamp(0.0, (from_size - 1) as f32);
+        let low = source.floor() as u32;
+        (low, (low + 1).min(from_size - 1), source - low as f32)
+    };
+
+    let mut resampled = Vec::with_capacity((width * height * 4) as usize);
+    for y in 0..height {
+        let (y0, y1, fy) = source(y, height, from_height);
+        for x in 0..width {
+            let (x0, x1, fx) = source(x, width, from_width);
+            let (a, b) = (texel(x0, y0), texel(x1, y0));
+            let (c, d) = (texel(x0, y1), texel(x1, y1));
+            resampled.extend((0..4).map(|i| mix(mix(a[i], b[i], fx), mix(c[i], d[i], fx), fy)));
+        }
+    }
+    resampled
+}
+
+// One implementation of the agent step (shader_1) and diffuse step (shader_2)
+pub trait SimulationBackend {
+    // Runs every species in order, particle buffers are created or dropped to
+    // match the number of species
+    fn step(&mut self, species: &[Species], u_time: f32);
+    fn clear(&mut self);
+    fn reset_points(&mut self, species: usize, initial_parameters: InitialParameters);
+    fn read_state(&self) -> PipelineState;
+    // Replaces the particles and textures, reallocating the textures if the
+    // state is a different size
+    fn write_state(&mut self, state: PipelineState);
+
+    // The trail map after the last step, laid out like PipelineState::texture1
+    fn read_trails(&self) -> Vec<f32>;
+    fn size(&self) -> (u32, u32);
+
+    // Reallocates the textures at a new size, either scaling the current
+    // trails to fit or starting over with empty ones
+    fn resize(&mut self, width: u32, height: u32, resample: bool) {
+        let state = self.read_state();
+        if resample {
+            self.write_state(state.resized(width, height));
+        } else {
+            self.write_state(state.cleared(width, height));
+        }
+    }
+
+    // Backends that already keep the trail map in a glium texture can have it
+    // drawn directly instead of going through read_trails
+    fn trail_texture(&self) -> Option<Ref<'_, glium::texture::Texture2d>> {
+        None
+    }
+
+    // Replaces the GLSL programs, on error the error message (including the
+    // compile log) is returned and the previous programs are kept
+    fn reload_shaders(&mut self, _sources: &ShaderSources) -> Result<(), String> {
+        Err("Only the glium backend runs GLSL shaders".to_string())
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum BackendKind {
+    Glium,
+    Wgpu,
+    Cpu,
+}
+
+impl BackendKind {
+    pub fn from_name(name: Option<&str>) -> BackendKind {
+        match name {
+            None | Some("glium") => BackendKind::Glium,
+            Some("wgpu") => BackendKind::Wgpu,
+            Some("cpu") => BackendKind::Cpu,
+            Some(name) => panic!("Unknown simulation backend: {name}"),
+        }
+    }
+}
+
+// The size of the trail map, either a fraction of the window or a fixed size
+// e.g. to record in 4K on a smaller screen
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum SimulationResolution {
+    Scaled(u32),
+    Fixed(u32, u32),
+}
+
+impl SimulationResolution {
+    pub fn from_name(name: Option<&str>) -> SimulationResolution {
+        match name {
+            None | Some("full") => SimulationResolution::Scaled(1),
+            Some("half") => SimulationResolution::Scaled(2),
+            Some("quarter") => SimulationResolution::Scaled(4),
+            // We allow fixed sizes like "3840x2160"
+            Some(size) => {
+                let (width, height) = size
+                    .split_once('x')
+                    .unwrap_or_else(|| panic!("Unknown simulation resolution: {size}"));
+                SimulationResolution::Fixed(width.parse().unwrap(), height.parse().unwrap())
+            }
+        }
+    }
+
+    pub fn size(self, width: u32, height: u32) -> (u32, u32) {
+        match self {
+            SimulationResolution::Scaled(divisor) => {
+                ((width / divisor).max(1), (height / divisor).max(1))
+            }
+            SimulationResolution::Fixed(width, height) => (width, height),
+        }
+    }
+}
+
+// The glium backend needs the OpenGL context, the others don't use it
+pub fn create_backend(
+    kind: BackendKind,
+    display: Option<&dyn Facade>,
+    width: u32,
+    height: u32,
+    preset: Preset,
+) -> Box<dyn SimulationBackend> {
+    println!("Using {kind:?} simulation backend");
+    match kind {
+        BackendKind::Glium => Box::new(ShaderPipeline::new(
+            display.expect("The glium backend needs an OpenGL context"),
+            width,
+            height,
+            preset,
+        )),
+        BackendKind::Wgpu => Box::new(WgpuPipeline::new(width, height, preset)),
+        BackendKind::Cpu => Box::new(CpuPipeline::new(width, height, preset)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::preset::{ColorBlend, PresetName};
+
+    #[test]
+    fn resolutions_scale_the_window_or_are_fixed() {
+        let size = |name| SimulationResolution::from_name(name).size(1920, 1080);
+        assert_eq!(size(None), (1920, 1080));
+        assert_eq!(size(Some("half")), (960, 540));
+        assert_eq!(size(Some("quarter")), (480, 270));
+        assert_eq!(size(Some("3840x2160")), (3840, 2160));
+    }
+
+    #[test]
+    #[should_panic(expected = "Unknown simulation resolution: huge")]
+    fn unknown_resolutions_are_rejected() {
+        SimulationResolution::from_name(Some("huge"));
+    }
+
+    #[test]
+    fn point_sizes_follow_the_trail_map_height() {
+        let mut preset = Preset::new(PresetName::GreenSlime);
+        preset.point_size = 2.0;
+        assert_eq!(point_size(&preset, 1200), 2.0);
+        assert_eq!(point_size(&preset, 600), 1.0);
+        assert_eq!(point_size(&preset, 2400), 4.0);
+    }
+
+    #[test]
+    fn palette_colors_fade_with_the_color_blend() {
+        let mut preset = Preset::new(PresetName::GreenSlime);
+        preset.color_strategy = ColorStrategy::Hue;
+        let mapping = DisplayMapping::for_preset(&preset);
+        assert_eq!(
+            (mapping.trail_palette, mapping.attribute_palette),
+            (0.0, 0.0)
+        );
+
+        preset.color_blend = ColorBlend {
+            target: ColorStrategy::PaletteSpeed,
+            amount: 0.25,
+        };
+        let mapping = DisplayMapping::for_preset(&preset);
+        assert_eq!(mapping.palette, preset.palette);
+        assert_eq!(
+            (mapping.trail_palette, mapping.attribute_palette),
+            (0.0, 0.25)
+        );
+    }
+
+    #[test]
+    fn species_fade_their_own_channels() {
+        let mut first = Preset::new(PresetName::GreenSlime);
+        first.fade_speed = 0.1;
+        let mut second = first;
+        second.fade_speed = 0.2;
+        second.blurring = 0.5;
+
+        let (fade_speed, blurring) = channel_diffusion(&[Species::single(first)]);
+        assert_eq!(fade_speed, [0.1; 4]);
+        assert_eq!(blurring, [first.blurring; 4]);
+
+        let species = [Species::single(first), Species::single(second)];
+        let (fade_speed, blurring) = channel_diffusion(&species);
+        assert_eq!(fade_speed, [0.1, 0.2, 0.1, 0.1]);
+        assert_eq!(blurring[1], 0.5);
+    }
+
+    fn state(width: u32, height: u32, texels: &[[f32; 4]]) -> PipelineState {
+        let data: Vec<f32> = texels.iter().flatten().copied().collect();
+        PipelineState {
+            width,
+            height,
+            particles: Vec::new(),
+            texture0: data.clone(),
+            texture1: data,
+        }
+    }
+
+    #[test]
+    fn resizing_interpolates_the_textures() {
+        let resized = state(2, 1, &[[0.0; 4], [1.0; 4]]).resized(4, 1);
+        assert_eq!((resized.width, resized.height), (4, 1));
+        let red: Vec<f32> = resized.texture1.chunks(4).map(|texel| texel[0]).collect();
+        assert_eq!(red, [0.0, 0.25, 0.75, 1.0]);
+        assert_eq!(resized.texture0, resized.texture1);
+    }
+
+    #[test]
+    fn resizing_keeps_flat_textures_flat() {
+        let resized = state(3, 2, &[[0.5; 4]; 6]).resized(7, 5);
+        assert_eq!(resized.texture1.len(), 7 * 5 * 4);
+        assert!(resized.texture1.iter().all(|&value| value == 0.5));
+    }
+
+    #[test]
+    fn clearing_empties_the_textures_at_the_new_size() {
+        let cleared = state(2, 2, &[[1.0; 4]; 4]).cleared(3, 3);
+        assert_eq!((cleared.width, cleared.height), (3, 3));
+        assert_eq!(cleared.texture0, vec![0.0; 36]);
+        assert_eq!(cleared.texture1, vec![0.0; 36]);
+    }
+}
@@ -1,57 +1,107 @@
+use crate::modulation::Modulation;
 use crate::preset::Preset;
-use crate::shader_pipeline::ShaderPipeline;
-use lerp::Lerp;
+use crate::shader_files::ShaderSources;
+use crate::simulation::{DisplayMapping, PipelineState, SimulationBackend, Species};
+use crate::species::Colony;
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::path::Path;
 
 pub struct SlimeMould {
-    shader_pipeline: ShaderPipeline,
+    backend: Box<dyn SimulationBackend>,
+    colony: Option<Colony>,
     old_preset: Preset,
     preset: Preset,
     secondary_preset: Preset,
+    // What the last step ran with, after transitions and blending
+    live_preset: Preset,
     lerp_time: f32,
     lerp_length: f32,
+    modulation: Modulation,
+}
+
+// How far u_time advances every frame
+pub const TIME_STEP: f32 = 0.02;
+
+// The rate the live event loop runs at, which presets are tuned for
+pub const LIVE_FRAME_RATE: f32 = 30.0;
+
+// The presets and transition progress, without any of the GPU state
+#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
+pub struct SlimeMouldState {
+    pub old_preset: Preset,
+    pub preset: Preset,
+    pub secondary_preset: Preset,
+    pub lerp_time: f32,
+    pub lerp_length: f32,
 }
 
 impl SlimeMould {
-    pub fn new(display: &glium::Display, width: u32, height: u32, preset: Preset) -> Self {
+    pub fn new(
+        backend: Box<dyn SimulationBackend>,
+        preset: Preset,
+        colony: Option<Colony>,
+    ) -> Self {
         Self {
-            shader_pipeline: ShaderPipeline::new(display, width, height, preset),
+            backend,
+            colony,
             old_preset: preset,
             secondary_preset: preset,
+            live_preset: preset,
             preset,
             lerp_time: 0.0f32,
             lerp_length: 0.0f32,
+            modulation: Modulation::default(),
         }
     }
 
-    pub fn save_preset(&self) {
-        println!(
-            "preset: {:?}\nsecondary_preset: {:?}",
-            self.preset, self.secondary_preset
-        );
+    pub fn save_preset(&self, path: impl AsRef<Path>) -> Result<(), Box<dyn Error>> {
+        self.preset.save_to_file(path)
+    }
+
+    pub fn backend(&self) -> &dyn SimulationBackend {
+        &*self.backend
+    }
+
+    pub fn reload_shaders(&mut self, sources: &ShaderSources) -> Result<(), String> {
+        self.backend.reload_shaders(sources)
     }
 
-    pub fn draw(
-        &mut self,
-        frame: &mut impl glium::Surface,
-        display: &glium::Display,
-        u_time: f32,
-        blend: f32,
-    ) {
+    // With a colony the trail channels belong to species, so the palette
+    // strategies only apply to a single species
+    pub fn display_mapping(&self) -> DisplayMapping {
+        match &self.colony {
+            Some(colony) => DisplayMapping::from_matrix(colony.display_matrix()),
+            None => DisplayMapping::for_preset(&self.live_preset),
+        }
+    }
+
+    fn species(&self, preset: Preset) -> Vec<Species> {
+        match &self.colony {
+            Some(colony) => colony.species(preset),
+            None => vec![Species::single(preset)],
+        }
+    }
+
+    pub fn step(&mut self, u_time: f32, blend: f32) {
         let lerp_now = (u_time - self.lerp_time).abs();
         //println!("u_time: {u_time} lerp_start: {lerp_start} lerp_now: {lerp_now}");
         let lerp_preset = lerp_now < self.lerp_length;
-        let preset = if lerp_preset {
+        let mut preset = if lerp_preset {
             self.old_preset
-                .lerp(self.preset, lerp_now / self.lerp_length)
+                .blend(self.preset, lerp_now / self.lerp_length)
         } else {
-            self.preset.lerp(self.secondary_preset, blend)
+            self.preset.blend(self.secondary_preset, blend)
         };
+        self.modulation.apply(&mut preset);
 
-        self.shader_pipeline.draw(frame, display, preset, u_time);
+        let species = self.species(preset);
+        self.backend.step(&species, u_time);
+        self.live_preset = preset;
     }
 
     pub fn clear(&mut self) {
-        self.shader_pipeline.clear();
+        self.backend.clear();
     }
     pub fn transition_preset(&mut self, preset_to: Preset, u_time: f32, transition_length: f32) {
         self.old_preset = self.preset;
@@ -65,17 +115,74 @@ impl SlimeMould {
         self.lerp_length = 0.0f32;
     }
 
+    // Changes the live preset without interrupting a transition to it
+    pub fn modify_preset(&mut self, modify: impl FnOnce(&mut Preset)) {
+        modify(&mut self.preset);
+    }
+
+    pub fn set_modulation(&mut self, modulation: Modulation) {
+        self.modulation = modulation;
+    }
+
+    pub fn modulation_mut(&mut self) -> &mut Modulation {
+        &mut self.modulation
+    }
+
     pub fn set_secondary_preset(&mut self, preset: Preset) {
         self.secondary_preset = preset;
     }
 
+    pub fn modify_secondary_preset(&mut self, modify: impl FnOnce(&mut Preset)) {
+        modify(&mut self.secondary_preset);
+    }
+
     pub fn get_preset(&self) -> Preset {
         self.preset
     }
 
+    pub fn get_secondary_preset(&self) -> Preset {
+        self.secondary_preset
+    }
+
+    pub fn live_preset(&self) -> Preset {
+        self.live_preset
+    }
+
     pub fn reset_points(&mut self) {
-        self.shader_pipeline
-            .reset_points(self.preset.initial_parameters);
+        for (index, species) in self.species(self.preset).iter().enumerate() {
+            self.backend
+                .reset_points(index, species.preset.initial_parameters);
+        }
+    }
+
+    pub fn get_state(&self) -> SlimeMouldState {
+        SlimeMouldState {
+            old_preset: self.old_preset,
+            preset: self.preset,
+            secondary_preset: self.secondary_preset,
+            lerp_time: self.lerp_time,
+            lerp_length: self.lerp_length,
+        }
+    }
+
+    pub fn set_state(&mut self, state: SlimeMouldState) {
+        self.old_preset = state.old_preset;
+        self.preset = state.preset;
+        self.secondary_preset = state.secondary_preset;
+        self.lerp_time = state.lerp_time;
+        self.lerp_length = state.lerp_length;
+    }
+
+    pub fn resize(&mut self, width: u32, height: u32, resample: bool) {
+        self.backend.resize(width, height, resample);
+    }
+
+    pub fn read_pipeline_state(&self) -> PipelineState {
+        self.backend.read_state()
+    }
+
+    pub fn write_pipeline_state(&mut self, state: PipelineState) {
+        self.backend.write_state(state);
     }
 
     pub fn update(&mut self, u_time: f32) {
@@ -0,0 +1,164 @@
+use crate::preset::Preset;
+use crate::preset_library::PresetLibrary;
+use crate::simulation::Species;
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+
+// Every species deposits into its own color channel of the trail map
+pub const MAX_SPECIES: usize = 3;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct ColonyFile {
+    species: Vec<SpeciesEntry>,
+    // Row i is how species i reacts to the trails of each species, positive
+    // values attract and negative values repel
+    interaction: Vec<Vec<f32>>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct SpeciesEntry {
+    // A preset name from the library or a path to a preset file
+    preset: String,
+    // What the species' trails look like on screen
+    color: [f32; 3],
+}
+
+// Several species of agents sharing the trail map. The first species follows
+// the live preset, so transitions, beats and automation apply to it, while the
+// others keep the presets they were loaded with.
+#[derive(Debug, Clone)]
+pub struct Colony {
+    presets: Vec<Preset>,
+    colors: Vec<[f32; 3]>,
+    interaction: Vec<[f32; 3]>,
+}
+
+impl Colony {
+    pub fn load_from_file(
+        path: impl AsRef<Path>,
+        preset_library: &PresetLibrary,
+    ) -> Result<Colony, Box<dyn Error>> {
+        let file: ColonyFile = toml::from_str(&fs::read_to_string(path)?)?;
+
+        let count = file.species.len();
+        if !(2..=MAX_SPECIES).contains(&count) {
+            return Err(format!("A colony needs 2 to {MAX_SPECIES} species, not {count}").into());
+        }
+        if file.interaction.len() != count || file.interaction.iter().any(|row| row.len() != count)
+        {
+            return Err(format!("The interaction matrix has to be {count}x{count}").into());
+        }
+
+        let presets = file
+            .species
+            .iter()
+            .map(|species| preset_library.get_by_name_or_path(&species.preset))
+            .collect::<Result<Vec<_>, _>>()?;
+        let interaction = file
+            .interaction
+            .iter()
+            .map(|row| {
+                let mut weights = [0.0; MAX_SPECIES];
+                weights[..count].copy_from_slice(row);
+                weights
+            })
+            .collect();
+
+        Ok(Colony {
+            presets,
+            colors: file.species.iter().map(|species| species.color).collect(),
+            interaction,
+        })
+    }
+
+    pub fn first_preset(&self) -> Preset {
+        self.presets[0]
+    }
+
+    pub fn species(&self, live_preset: Preset) -> Vec<Species> {
+        self.presets
+            .iter()
+            .zip(&self.interaction)
+            .enumerate()
+            .map(|(index, (preset, weights))| Species {
+                preset: if index == 0 { live_preset } else { *preset },
+                sense_weights: *weights,
+            })
+            .collect()
+    }
+
+    // Maps the trail channels to screen colors, column i is species i's color
+    pub fn display_matrix(&self) -> [[f32; 3]; 3] {
+        let mut matrix = [[0.0; 3]; 3];
+        for (column, color) in matrix.iter_mut().zip(&self.colors) {
+            *column = *color;
+        }
+        matrix
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::preset::PresetName;
+
+    fn load(contents: &str) -> Result<Colony, Box<dyn Error>> {
+        let path = std::env::temp_dir().join(format!("colony-{}.toml", std::process::id()));
+        fs::write(&path, contents).unwrap();
+        let colony = Colony::load_from_file(&path, &PresetLibrary::load(None));
+        fs::remove_file(&path).unwrap();
+        colony
+    }
+
+    #[test]
+    fn the_first_species_follows_the_live_preset() {
+        let colony =
+            Colony::load_from_file("colonies/competing.toml", &PresetLibrary::load(None)).unwrap();
+        assert_eq!(colony.first_preset(), Preset::new(PresetName::GreenSlime));
+
+        let live_preset = Preset::new(PresetName::Waves);
+        let species = colony.species(live_preset);
+        assert_eq!(species.len(), 3);
+        assert_eq!(species[0].preset, live_preset);
+        assert_eq!(species[1].preset, Preset::new(PresetName::ShiftingWeb));
+        assert_eq!(species[1].sense_weights, [-0.5, 1.0, -0.5]);
+        assert_eq!(colony.display_matrix()[2], [0.2, 0.4, 1.0]);
+    }
+
+    #[test]
+    fn unused_channels_are_ignored() {
+        let colony = load(
+            "interaction = [[1.0, -1.0], [0.5, 1.0]]\n\
+             [[species]]\npreset = \"GreenSlime\"\ncolor = [1.0, 0.0, 0.0]\n\
+             [[species]]\npreset = \"Waves\"\ncolor = [0.0, 1.0, 0.0]\n",
+        )
+        .unwrap();
+        let species = colony.species(colony.first_preset());
+        assert_eq!(species[0].sense_weights, [1.0, -1.0, 0.0]);
+        assert_eq!(species[1].sense_weights, [0.5, 1.0, 0.0]);
+        assert_eq!(colony.display_matrix()[2], [0.0; 3]);
+    }
+
+    #[test]
+    fn colonies_need_several_species_and_a_square_matrix() {
+        let one = load(
+            "interaction = [[1.0]]\n[[species]]\npreset = \"Waves\"\ncolor = [1.0, 1.0, 1.0]\n",
+        );
+        assert_eq!(
+            one.unwrap_err().to_string(),
+            "A colony needs 2 to 3 species, not 1"
+        );
+
+        let uneven = load(
+            "interaction = [[1.0, 0.0], [1.0]]\n\
+             [[species]]\npreset = \"GreenSlime\"\ncolor = [1.0, 0.0, 0.0]\n\
+             [[species]]\npreset = \"Waves\"\ncolor = [0.0, 1.0, 0.0]\n",
+        );
+        assert_eq!(
+            uneven.unwrap_err().to_string(),
+            "The interaction matrix has to be 2x2"
+        );
+    }
+}
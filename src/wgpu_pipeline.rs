@@ -0,0 +1,790 @@
+use crate::preset::{InitialParameters, Preset};
+use crate::shader_pipeline::{self, Position};
+use crate::simulation::{self, PipelineState, SimulationBackend, Species, BLUR_RADIUS};
+use wgpu::util::DeviceExt;
+
+// Workgroup sizes, these have to match the @workgroup_size attributes in SHADER
+const AGENT_WORKGROUP_SIZE: u32 = 256;
+const TEXEL_WORKGROUP_SIZE: u32 = 8;
+
+const TEXEL_SIZE: u64 = std::mem::size_of::<[f32; 4]>() as u64;
+
+// Laid out to match the Params struct in SHADER
+#[repr(C)]
+#[derive(Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+struct Params {
+    width: u32,
+    height: u32,
+    number_of_points: u32,
+    wall_strategy: u32,
+    color_strategy: u32,
+    // The color strategy being blended towards and how much of it is shown
+    blend_color_strategy: u32,
+    color_blend: f32,
+    u_time: f32,
+    speed_multiplier: f32,
+    random_steer_factor: f32,
+    constant_steer_factor: f32,
+    search_radius: f32,
+    trail_strength: f32,
+    point_size: f32,
+    blur_radius: f32,
+    // The trail channel this species deposits into, or -1 for the colors
+    // from its color strategy
+    species_channel: i32,
+    search_angle: f32,
+    sensor_count: u32,
+    turn_strategy: u32,
+    _padding: u32,
+    sense_weights: [f32; 4],
+    fade_speed: [f32; 4],
+    blurring: [f32; 4],
+}
+
+// The agents of one species and the uniforms they're stepped with
+struct SpeciesBuffers {
+    number_of_points: u32,
+    params: wgpu::Buffer,
+    particles: wgpu::Buffer,
+    bind_group: wgpu::BindGroup,
+}
+
+// ShaderPipeline written as wgpu compute passes, so it can run on Vulkan,
+// Metal and DX12 or on a software adapter like lavapipe when there's no
+// OpenGL. The textures are kept in storage buffers with the same layout as
+// PipelineState, the trail map is ping-ponged by copying the diffuse output
+// back over the previous frame.
+pub struct WgpuPipeline {
+    device: wgpu::Device,
+    queue: wgpu::Queue,
+    width: u32,
+    height: u32,
+    // The uniforms of the clear and diffuse passes
+    params: wgpu::Buffer,
+    species: Vec<SpeciesBuffers>,
+    // The output of the agent step (u_texture0 in ShaderPipeline)
+    deposit: wgpu::Buffer,
+    // The output of the diffuse step (u_texture1 in ShaderPipeline)
+    trails: wgpu::Buffer,
+    trails_out: wgpu::Buffer,
+    clear_pipeline: wgpu::ComputePipeline,
+    agent_pipeline: wgpu::ComputePipeline,
+    diffuse_pipeline: wgpu::ComputePipeline,
+    clear_bind_group: wgpu::BindGroup,
+    diffuse_bind_group: wgpu::BindGroup,
+}
+
+impl WgpuPipeline {
+    pub fn new(width: u32, height: u32, preset: Preset) -> Self {
+        let (device, queue) = pollster::block_on(Self::request_device());
+
+        let module = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some("slime_mould"),
+            source: wgpu::ShaderSource::Wgsl(SHADER.into()),
+        });
+        let create_pipeline = |entry_point: &str| {
+            device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+                label: Some(entry_point),
+                layout: None,
+                module: &module,
+                entry_point,
+            })
+        };
+        let clear_pipeline = create_pipeline("clear_deposit");
+        let agent_pipeline = create_pipeline("step_agents");
+        let diffuse_pipeline = create_pipeline("diffuse");
+
+        let params = Self::create_params_buffer(&device);
+        let deposit = Self::create_texture_buffer(&device, "deposit", width, height);
+        let trails = Self::create_texture_buffer(&device, "trails", width, height);
+        let trails_out = Self::create_texture_buffer(&device, "trails_out", width, height);
+
+        let clear_bind_group =
+            Self::create_bind_group(&device, &clear_pipeline, &[(0, &params), (3, &deposit)]);
+        let diffuse_bind_group = Self::create_bind_group(
+            &device,
+            &diffuse_pipeline,
+            &[(0, &params), (2, &trails), (3, &deposit), (4, &trails_out)],
+        );
+
+        let mut pipeline = Self {
+            width,
+            height,
+            params,
+            species: Vec::new(),
+            deposit,
+            trails,
+            trails_out,
+            clear_pipeline,
+            agent_pipeline,
+            diffuse_pipeline,
+            clear_bind_group,
+            diffuse_bind_group,
+            device,
+            queue,
+        };
+        let species = pipeline.create_species(&shader_pipeline::initial_positions(
+            preset.initial_parameters,
+        ));
+        pipeline.species.push(species);
+        pipeline
+    }
+
+    // Prefers a real GPU but falls back to a software adapter
+    async fn request_adapter() -> Option<wgpu::Adapter> {
+        let instance = wgpu::Instance::new(wgpu::InstanceDescriptor::default());
+        let mut adapter = instance
+            .request_adapter(&wgpu::RequestAdapterOptions {
+                power_preference: wgpu::PowerPreference::HighPerformance,
+                force_fallback_adapter: false,
+                compatible_surface: None,
+            })
+            .await;
+        if adapter.is_none() {
+            adapter = instance
+                .request_adapter(&wgpu::RequestAdapterOptions {
+                    power_preference: wgpu::PowerPreference::LowPower,
+                    force_fallback_adapter: true,
+                    compatible_surface: None,
+                })
+                .await;
+        }
+        adapter
+    }
+
+    // Lets tests that need an adapter skip on machines without one
+    #[cfg(test)]
+    pub fn is_available() -> bool {
+        pollster::block_on(Self::request_adapter()).is_some()
+    }
+
+    async fn request_device() -> (wgpu::Device, wgpu::Queue) {
+        let adapter = Self::request_adapter()
+            .await
+            .expect("No wgpu adapter available");
+        println!("wgpu adapter: {:?}", adapter.get_info());
+
+        adapter
+            .request_device(
+                &wgpu::DeviceDescriptor {
+                    label: Some("slime_mould"),
+                    features: wgpu::Features::empty(),
+                    limits: wgpu::Limits {
+                        max_storage_buffer_binding_size: adapter
+                            .limits()
+                            .max_storage_buffer_binding_size,
+                        max_buffer_size: adapter.limits().max_buffer_size,
+                        ..wgpu::Limits::default()
+                    },
+                },
+                None,
+            )
+            .await
+            .unwrap()
+    }
+
+    fn create_params_buffer(device: &wgpu::Device) -> wgpu::Buffer {
+        device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("params"),
+            size: std::mem::size_of::<Params>() as u64,
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        })
+    }
+
+    fn create_texture_buffer(
+        device: &wgpu::Device,
+        label: &str,
+        width: u32,
+        height: u32,
+    ) -> wgpu::Buffer {
+        device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some(label),
+            size: (width * height) as u64 * TEXEL_SIZE,
+            usage: wgpu::BufferUsages::STORAGE
+                | wgpu::BufferUsages::COPY_SRC
+                | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        })
+    }
+
+    // The layouts are derived from the shader, so only the bindings an entry
+    // point actually uses can be given
+    fn create_bind_group(
+        device: &wgpu::Device,
+        pipeline: &wgpu::ComputePipeline,
+        buffers: &[(u32, &wgpu::Buffer)],
+    ) -> wgpu::BindGroup {
+        let entries: Vec<wgpu::BindGroupEntry> = buffers
+            .iter()
+            .map(|(binding, buffer)| wgpu::BindGroupEntry {
+                binding: *binding,
+                resource: buffer.as_entire_binding(),
+            })
+            .collect();
+        device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: None,
+            layout: &pipeline.get_bind_group_layout(0),
+            entries: &entries,
+        })
+    }
+
+    fn create_species_bind_group(
+        &self,
+        params: &wgpu::Buffer,
+        particles: &wgpu::Buffer,
+    ) -> wgpu::BindGroup {
+        Self::create_bind_group(
+            &self.device,
+            &self.agent_pipeline,
+            &[
+                (0, params),
+                (1, particles),
+                (2, &self.trails),
+                (3, &self.deposit),
+            ],
+        )
+    }
+
+    fn create_species(&self, particles: &[Position]) -> SpeciesBuffers {
+        let data: Vec<f32> = particles
+            .iter()
+            .flat_map(|particle| particle.a_position)
+            .collect();
+        let particles_buffer = self
+            .device
+            .create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                label: Some("particles"),
+                contents: bytemuck::cast_slice(&data),
+                usage: wgpu::BufferUsages::STORAGE
+                    | wgpu::BufferUsages::COPY_SRC
+                    | wgpu::BufferUsages::COPY_DST,
+            });
+        let params = Self::create_params_buffer(&self.device);
+
+        SpeciesBuffers {
+            number_of_points: particles.len() as u32,
+            bind_group: self.create_species_bind_group(&params, &particles_buffer),
+            params,
+            particles: particles_buffer,
+        }
+    }
+
+    fn set_size(&mut self, width: u32, height: u32) {
+        self.width = width;
+        self.height = height;
+        self.deposit = Self::create_texture_buffer(&self.device, "deposit", width, height);
+        self.trails = Self::create_texture_buffer(&self.device, "trails", width, height);
+        self.trails_out = Self::create_texture_buffer(&self.device, "trails_out", width, height);
+
+        self.clear_bind_group = Self::create_bind_group(
+            &self.device,
+            &self.clear_pipeline,
+            &[(0, &self.params), (3, &self.deposit)],
+        );
+        self.diffuse_bind_group = Self::create_bind_group(
+            &self.device,
+            &self.diffuse_pipeline,
+            &[
+                (0, &self.params),
+                (2, &self.trails),
+                (3, &self.deposit),
+                (4, &self.trails_out),
+            ],
+        );
+        let bind_groups: Vec<wgpu::BindGroup> = self
+            .species
+            .iter()
+            .map(|species| self.create_species_bind_group(&species.params, &species.particles))
+            .collect();
+        for (species, bind_group) in self.species.iter_mut().zip(bind_groups) {
+            species.bind_group = bind_group;
+        }
+    }
+
+    fn read_buffer(&self, buffer: &wgpu::Buffer) -> Vec<f32> {
+        let staging = self.device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("readback"),
+            size: buffer.size(),
+            usage: wgpu::BufferUsages::MAP_READ | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        let mut encoder = self
+            .device
+            .create_command_encoder(&wgpu::CommandEncoderDescriptor { label: None });
+        encoder.copy_buffer_to_buffer(buffer, 0, &staging, 0, buffer.size());
+        self.queue.submit(Some(encoder.finish()));
+
+        let slice = staging.slice(..);
+        slice.map_async(wgpu::MapMode::Read, |result| result.unwrap());
+        self.device.poll(wgpu::Maintain::Wait);
+
+        let data = bytemuck::cast_slice::<u8, f32>(&slice.get_mapped_range()).to_vec();
+        staging.unmap();
+        data
+    }
+}
+
+impl SimulationBackend for WgpuPipeline {
+    fn step(&mut self, species: &[Species], u_time: f32) {
+        self.species.truncate(species.len());
+        for added in &species[self.species.len()..] {
+            let buffers = self.create_species(&shader_pipeline::initial_positions(
+                added.preset.initial_parameters,
+            ));
+            self.species.push(buffers);
+        }
+
+        let (fade_speed, blurring) = simulation::channel_diffusion(species);
+        let multi_species = simulation::is_multi_species(species);
+        for (index, (current, buffers)) in species.iter().zip(&self.species).enumerate() {
+            let preset = current.preset;
+            let [r, g, b] = current.sense_weights;
+            let params = Params {
+                width: self.width,
+                height: self.height,
+                number_of_points: buffers.number_of_points,
+                wall_strategy: preset.wall_strategy as u32,
+                color_strategy: preset.color_strategy as u32,
+                blend_color_strategy: preset.color_blend.target as u32,
+                color_blend: preset.color_blend.amount,
+                u_time,
+                speed_multiplier: preset.speed_multiplier,
+                random_steer_factor: preset.random_steer_factor,
+                constant_steer_factor: preset.constant_steer_factor,
+                search_radius: preset.search_radius,
+                trail_strength: preset.trail_strength,
+                point_size: simulation::point_size(&preset, self.height),
+                blur_radius: BLUR_RADIUS,
+                species_channel: if multi_species { index as i32 } else { -1 },
+                search_angle: preset.search_angle,
+                sensor_count: preset.sensor_count.get(),
+                turn_strategy: preset.turn_strategy as u32,
+                _padding: 0,
+                sense_weights: [r, g, b, 0.0],
+                fade_speed,
+                blurring,
+            };
+            self.queue
+                .write_buffer(&buffers.params, 0, bytemuck::bytes_of(&params));
+            // The clear and diffuse passes only use the size and diffusion,
+            // which are the same for every species
+            if index == 0 {
+                self.queue
+                    .write_buffer(&self.params, 0, bytemuck::bytes_of(&params));
+            }
+        }
+
+        let texel_workgroups = (
+            self.width.div_ceil(TEXEL_WORKGROUP_SIZE),
+            self.height.div_ceil(TEXEL_WORKGROUP_SIZE),
+        );
+        let mut encoder = self
+            .device
+            .create_command_encoder(&wgpu::CommandEncoderDescriptor { label: None });
+        {
+            let mut pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor::default());
+
+            pass.set_pipeline(&self.clear_pipeline);
+            pass.set_bind_group(0, &self.clear_bind_group, &[]);
+            pass.dispatch_workgroups(texel_workgroups.0, texel_workgroups.1, 1);
+
+            pass.set_pipeline(&self.agent_pipeline);
+            for buffers in &self.species {
+                pass.set_bind_group(0, &buffers.bind_group, &[]);
+                pass.dispatch_workgroups(
+                    buffers.number_of_points.div_ceil(AGENT_WORKGROUP_SIZE),
+                    1,
+                    1,
+                );
+            }
+
+            pass.set_pipeline(&self.diffuse_pipeline);
+            pass.set_bind_group(0, &self.diffuse_bind_group, &[]);
+            pass.dispatch_workgroups(texel_workgroups.0, texel_workgroups.1, 1);
+        }
+        encoder.copy_buffer_to_buffer(&self.trails_out, 0, &self.trails, 0, self.trails.size());
+        self.queue.submit(Some(encoder.finish()));
+    }
+
+    fn clear(&mut self) {
+        let mut encoder = self
+            .device
+            .create_command_encoder(&wgpu::CommandEncoderDescriptor { label: None });
+        encoder.clear_buffer(&self.deposit, 0, None);
+        encoder.clear_buffer(&self.trails, 0, None);
+        self.queue.submit(Some(encoder.finish()));
+    }
+
+    fn reset_points(&mut self, species: usize, initial_parameters: InitialParameters) {
+        if species < self.species.len() {
+            self.species[species] =
+                self.create_species(&shader_pipeline::initial_positions(initial_parameters));
+        }
+    }
+
+    fn read_state(&self) -> PipelineState {
+        PipelineState {
+            width: self.width,
+            height: self.height,
+            particles: self
+                .species
+                .iter()
+                .map(|species| {
+                    self.read_buffer(&species.particles)
+                        .chunks_exact(4)
+                        .map(|chunk| Position {
+                            a_position: [chunk[0], chunk[1], chunk[2], chunk[3]],
+                        })
+                        .collect()
+                })
+                .collect(),
+            texture0: self.read_buffer(&self.deposit),
+            texture1: self.read_buffer(&self.trails),
+        }
+    }
+
+    fn write_state(&mut self, state: PipelineState) {
+        if state.width != self.width || state.height != self.height {
+            self.set_size(state.width, state.height);
+        }
+        self.species = state
+            .particles
+            .iter()
+            .map(|particles| self.create_species(particles))
+            .collect();
+        self.queue
+            .write_buffer(&self.deposit, 0, bytemuck::cast_slice(&state.texture0));
+        self.queue
+            .write_buffer(&self.trails, 0, bytemuck::cast_slice(&state.texture1));
+    }
+
+    fn read_trails(&self) -> Vec<f32> {
+        self.read_buffer(&self.trails)
+    }
+
+    fn size(&self) -> (u32, u32) {
+        (self.width, self.height)
+    }
+}
+
+// shader_1 and shader_2 from ShaderPipeline as compute shaders. Points are
+// written straight into the deposit buffer instead of being rasterized, and
+// the samplers are replaced by manual bilinear filtering with repeat wrapping.
+const SHADER: &str = r#"
+struct Params {
+    width: u32,
+    height: u32,
+    number_of_points: u32,
+    wall_strategy: u32,
+    color_strategy: u32,
+    blend_color_strategy: u32,
+    color_blend: f32,
+    u_time: f32,
+    speed_multiplier: f32,
+    random_steer_factor: f32,
+    constant_steer_factor: f32,
+    search_radius: f32,
+    trail_strength: f32,
+    point_size: f32,
+    blur_radius: f32,
+    species_channel: i32,
+    search_angle: f32,
+    sensor_count: u32,
+    turn_strategy: u32,
+    padding: u32,
+    sense_weights: vec4<f32>,
+    fade_speed: vec4<f32>,
+    blurring: vec4<f32>,
+}
+
+@group(0) @binding(0) var<uniform> params: Params;
+@group(0) @binding(1) var<storage, read_write> particles: array<vec4<f32>>;
+@group(0) @binding(2) var<storage, read> trails: array<vec4<f32>>;
+@group(0) @binding(3) var<storage, read_write> deposit: array<vec4<f32>>;
+@group(0) @binding(4) var<storage, read_write> trails_out: array<vec4<f32>>;
+
+const PI: f32 = 3.14159;
+const BLUR_DIRECTIONS: f32 = 8.0;
+const BLUR_QUALITY: f32 = 1.0;
+
+fn rand(co: vec2<f32>) -> f32 {
+    return fract(sin(dot(co, vec2<f32>(12.9898, 78.233))) * 43758.5453);
+}
+
+fn hsv2rgb(c: vec3<f32>) -> vec3<f32> {
+    let k = vec4<f32>(1.0, 2.0 / 3.0, 1.0 / 3.0, 3.0);
+    let p = abs(fract(c.xxx + k.xyz) * 6.0 - k.www);
+    return c.z * mix(k.xxx, clamp(p - k.xxx, vec3<f32>(0.0), vec3<f32>(1.0)), c.y);
+}
+
+fn trail_texel(x: i32, y: i32) -> vec4<f32> {
+    let width = i32(params.width);
+    let height = i32(params.height);
+    let wrapped_x = ((x % width) + width) % width;
+    let wrapped_y = ((y % height) + height) % height;
+    return trails[wrapped_y * width + wrapped_x];
+}
+
+fn sample_trails(texcoord: vec2<f32>) -> vec4<f32> {
+    let position = texcoord * vec2<f32>(f32(params.width), f32(params.height)) - 0.5;
+    let base = floor(position);
+    let f = position - base;
+    let x = i32(base.x);
+    let y = i32(base.y);
+    return mix(
+        mix(trail_texel(x, y), trail_texel(x + 1, y), f.x),
+        mix(trail_texel(x, y + 1), trail_texel(x + 1, y + 1), f.x),
+        f.y
+    );
+}
+
+fn sense(texcoord: vec2<f32>, direction: f32) -> f32 {
+    let sample = sample_trails(texcoord + vec2<f32>(cos(direction), sin(direction)) * params.search_radius);
+    return dot(sample.rgb, params.sense_weights.rgb);
+}
+
+// The position is also gl_Position in shader_1, so clip and divide like the
+// rasterizer would before filling the point's square
+fn draw_point(position: vec4<f32>, color: vec4<f32>) {
+    let w = position.w;
+    if (w <= 0.0 || abs(position.x) > w || abs(position.y) > w || abs(position.z) > w) {
+        return;
+    }
+
+    let size = vec2<f32>(f32(params.width), f32(params.height));
+    let center = (position.xy / w + 1.0) / 2.0 * size;
+    let half_size = max(params.point_size, 1.0) / 2.0;
+    let low = vec2<u32>(max(ceil(center - half_size - 0.5), vec2<f32>(0.0)));
+    let high = min(
+        vec2<u32>(max(ceil(center + half_size - 0.5), vec2<f32>(0.0))),
+        vec2<u32>(params.width, params.height)
+    );
+
+    for (var y = low.y; y < high.y; y += 1u) {
+        for (var x = low.x; x < high.x; x += 1u) {
+            if (params.species_channel >= 0) {
+                deposit[y * params.width + x][params.species_channel] = params.trail_strength;
+            } else {
+                deposit[y * params.width + x] = color;
+            }
+        }
+    }
+}
+
+// The red and green deposited by each color strategy, blue is always the
+// trail strength
+fn strategy_color(strategy: u32, direction: f32, speed_var: f32, x_new: f32, y_new: f32) -> vec2<f32> {
+    let distance_from_center = sqrt(x_new * x_new + y_new * y_new);
+    let normalized_distance = distance_from_center / 1.3;
+    var color = vec2<f32>(sin(direction), cos(direction));
+    switch strategy {
+        case 1u: {
+            color = vec2<f32>(speed_var * 50.0, speed_var * 50.0);
+        }
+        case 2u: {
+            color = vec2<f32>(abs(y_new) / 2.0 + 0.5, abs(x_new) / 2.0 + 0.5);
+        }
+        case 3u: {
+            color = vec2<f32>(params.trail_strength, params.trail_strength);
+        }
+        case 4u: {
+            let hue = atan2(y_new, x_new) / (2.0 * PI) + 0.5;
+            color = hsv2rgb(vec3<f32>(hue, 1.0 - normalized_distance, 1.0)).rg;
+        }
+        case 5u: {
+            color = vec2<f32>(mix(0.2, 1.0, normalized_distance), mix(0.5, 1.0, normalized_distance));
+        }
+        case 6u: {
+            let time_factor = sin(params.u_time * 0.5);
+            color = vec2<f32>(
+                0.5 + 0.5 * sin(2.0 * PI * (x_new + y_new) + time_factor),
+                0.5 + 0.5 * sin(2.0 * PI * (x_new - y_new) + time_factor)
+            );
+        }
+        // The palette index times the trail strength, see shader_1
+        case 7u: {
+            color = vec2<f32>(0.0, 0.0);
+        }
+        case 8u: {
+            color = vec2<f32>(fract(direction / (2.0 * PI)) * params.trail_strength, 0.0);
+        }
+        case 9u: {
+            color = vec2<f32>(clamp(speed_var * 50.0, 0.0, 1.0) * params.trail_strength, 0.0);
+        }
+        default: {}
+    }
+    return color;
+}
+
+@compute @workgroup_size(8, 8)
+fn clear_deposit(@builtin(global_invocation_id) id: vec3<u32>) {
+    if (id.x >= params.width || id.y >= params.height) {
+        return;
+    }
+    deposit[id.y * params.width + id.x] = vec4<f32>(0.0, 0.0, 0.0, 1.0);
+}
+
+@compute @workgroup_size(256)
+fn step_agents(@builtin(global_invocation_id) id: vec3<u32>) {
+    if (id.x >= params.number_of_points) {
+        return;
+    }
+    let a_position = particles[id.x];
+
+    // Coord in texture space
+    let texcoord = (a_position.xy + 1.0) / 2.0;
+    let tex_val = sample_trails(texcoord);
+    let noise = rand(texcoord + tex_val.xy);
+
+    // Get speed and direction
+    var direction = (a_position.w - 1.0) * 1000.0;
+    let speed_var = a_position.z * 1000.0;
+
+    direction += params.random_steer_factor * 3.0 * (noise - 0.5);
+
+    var speed = params.speed_multiplier * speed_var;
+
+    // Read the underlying texture with sensors spread evenly from
+    // search_angle on the right to search_angle on the left
+    let centre = f32(params.sensor_count - 1u) / 2.0;
+    var sense_forward = -1.0e38;
+    var sense_left = -1.0e38;
+    var sense_right = -1.0e38;
+    var strongest = -1.0e38;
+    var strongest_angle = 0.0;
+    var total = 0.0;
+    var weighted_angle = 0.0;
+    for (var sensor = 0u; sensor < params.sensor_count; sensor += 1u) {
+        let offset = f32(sensor) - centre;
+        let angle = params.search_angle * offset / centre;
+        let sensed = sense(texcoord, direction + angle);
+
+        // The one or two sensors in the middle look forward
+        if (abs(offset) < 1.0) {
+            sense_forward = max(sense_forward, sensed);
+        } else if (offset > 0.0) {
+            sense_left = max(sense_left, sensed);
+        } else {
+            sense_right = max(sense_right, sensed);
+        }
+
+        if (sensed > strongest) {
+            strongest = sensed;
+            strongest_angle = angle;
+        }
+        total += max(sensed, 0.0);
+        weighted_angle += max(sensed, 0.0) * angle;
+    }
+
+    let steer_amount = params.constant_steer_factor + params.random_steer_factor * noise;
+
+    switch params.turn_strategy {
+        case 1u: {
+            if (strongest > sense_forward) {
+                direction += strongest_angle;
+            }
+        }
+        case 2u: {
+            if (total > 0.0 && params.search_angle > 0.0) {
+                direction += steer_amount * weighted_angle / (total * params.search_angle);
+            }
+        }
+        default: {
+            if (sense_forward > sense_left && sense_forward > sense_right) {
+                // Straight ahead
+            } else if (sense_forward < sense_left && sense_forward < sense_right) {
+                direction += params.random_steer_factor * (noise - 0.5);
+            } else if (sense_right > sense_left) {
+                direction -= steer_amount;
+            } else if (sense_right < sense_left) {
+                direction += steer_amount;
+            }
+        }
+    }
+
+    var y_new = a_position.y;
+    var x_new = a_position.x;
+
+    switch params.wall_strategy {
+        case 1u: {
+            if (y_new > 0.99) { y_new = -0.99; }
+            if (y_new < -0.99) { y_new = 0.99; }
+            if (x_new > 0.99) { x_new = -0.99; }
+            if (x_new < -0.99) { x_new = 0.99; }
+        }
+        case 2u, 3u: {
+            var random_angle = 0.0;
+            if (params.wall_strategy == 3u) {
+                random_angle = noise * params.random_steer_factor;
+            }
+            if (y_new + speed * sin(direction) > 0.90) {
+                let d = atan2(sin(direction), cos(direction));
+                direction -= 2.0 * d + random_angle;
+            }
+            if (y_new + speed * sin(direction) < -0.90) {
+                let d = atan2(sin(direction), cos(direction));
+                direction -= 2.0 * d + random_angle;
+            }
+            if (x_new + speed * cos(direction) > 0.90) {
+                let d = atan2(cos(direction), sin(direction));
+                direction += 2.0 * d + random_angle;
+            }
+            if (x_new + speed * cos(direction) < -0.90) {
+                let d = atan2(cos(direction), sin(direction));
+                direction += 2.0 * d + random_angle;
+            }
+        }
+        case 4u: {
+            let boundary = 0.75;
+            let slowdown_factor = 0.75;
+            if (abs(y_new + speed * sin(direction)) > boundary) {
+                speed *= slowdown_factor;
+                direction = PI - direction;
+            }
+            if (abs(x_new + speed * cos(direction)) > boundary) {
+                speed *= slowdown_factor;
+                direction = -direction;
+            }
+        }
+        default: {}
+    }
+
+    // shader_1 applies the speed multiplier twice
+    y_new += speed * params.speed_multiplier * sin(direction);
+    x_new += speed * params.speed_multiplier * cos(direction);
+
+    // Mix in the color strategy being blended towards
+    let color = mix(
+        strategy_color(params.color_strategy, direction, speed_var, x_new, y_new),
+        strategy_color(params.blend_color_strategy, direction, speed_var, x_new, y_new),
+        params.color_blend
+    );
+
+    let position = vec4<f32>(x_new, y_new, speed_var / 1000.0, 1.0 + direction / 1000.0);
+    particles[id.x] = position;
+    draw_point(position, vec4<f32>(color, params.trail_strength, 1.0));
+}
+
+@compute @workgroup_size(8, 8)
+fn diffuse(@builtin(global_invocation_id) id: vec3<u32>) {
+    if (id.x >= params.width || id.y >= params.height) {
+        return;
+    }
+    let index = id.y * params.width + id.x;
+    let texcoord = (vec2<f32>(id.xy) + 0.5) / vec2<f32>(f32(params.width), f32(params.height));
+
+    let prev_frame = trails[index];
+    var blurred = prev_frame;
+    var pixel_count = 1.0;
+    for (var d = 0.0; d < 6.3; d += 6.3 / BLUR_DIRECTIONS) {
+        for (var i = 1.0 / BLUR_QUALITY; i <= 1.0; i += 1.0 / BLUR_QUALITY) {
+            blurred += sample_trails(texcoord + vec2<f32>(cos(d), sin(d)) * params.blur_radius * i);
+            pixel_count += 1.0;
+        }
+    }
+
+    blurred = prev_frame * (1.0 - params.blurring) + blurred / pixel_count * params.blurring;
+    trails_out[index] = deposit[index] + blurred * (1.0 - params.fade_speed) - 0.0001;
+}
+"#;